@@ -1,4 +1,17 @@
 #!/usr/bin/env python3
 # -*- coding: utf-8 -*-
 
-print("Hi, I'm Python CGI.")
\ No newline at end of file
+import os
+import sys
+
+print("Content-Type: text/plain; charset=utf-8")
+print()
+print("Hi, I'm Python CGI.")
+print("Method: %s" % os.environ.get("REQUEST_METHOD", ""))
+print("Query: %s" % os.environ.get("QUERY_STRING", ""))
+print("Path info: %s" % os.environ.get("PATH_INFO", ""))
+print("Client: %s" % os.environ.get("REMOTE_ADDR", ""))
+
+length = int(os.environ.get("CONTENT_LENGTH") or 0)
+if length > 0:
+    print("Body: %s" % sys.stdin.read(length))
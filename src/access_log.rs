@@ -0,0 +1,82 @@
+//! 访问日志，每个请求一行，格式与 Apache/nginx 的 combined 格式相同
+
+use crate::{config, http::HttpRequest};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+// 按路径打开的日志文件，多个虚拟主机可以共用一个文件。
+// 写入在 tokio 的阻塞线程池中进行，锁是异步锁，等待写日志时不占用运行时的工作线程
+static FILES: LazyLock<Mutex<HashMap<String, File>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 记录一个已经发出的响应，`length` 为响应体长度，未知时为 None
+pub async fn record(req: &HttpRequest, code: &str, length: Option<&str>) {
+    let Ok(config) = config::for_request(req).await else {
+        return;
+    };
+    let Some(path) = &config.access_log else {
+        return;
+    };
+    let mut target = req.path.clone();
+    if !req.query.is_empty() {
+        target.push('?');
+        target.push_str(&req.query);
+    }
+    let line = format!(
+        "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"\n",
+        req.remote_addr
+            .map_or("-".to_string(), |addr| addr.ip().to_string()),
+        req.remote_user.as_deref().unwrap_or("-"),
+        log_time(SystemTime::now()),
+        req.method,
+        target,
+        req.version,
+        code,
+        length.unwrap_or("-"),
+        req.header("Referer").unwrap_or("-"),
+        req.header("User-Agent").unwrap_or("-"),
+    );
+
+    let mut files = FILES.lock().await;
+    if !files.contains_key(path) {
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+        {
+            Ok(file) => {
+                files.insert(path.clone(), file);
+            }
+            Err(e) => {
+                warn!("Cannot open access log {}: {}", path, e);
+                return;
+            }
+        }
+    }
+    if let Some(file) = files.get_mut(path) {
+        // flush 等待后台写入完成，保证出错时能记录下来
+        let written = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(e) = written.await {
+            warn!("Failed to write access log {}: {}", path, e);
+        }
+    }
+}
+
+/// 日志中的时间，例如 "18/Oct/2026:18:08:12 +0000"
+fn log_time(time: SystemTime) -> String {
+    // HTTP 日期格式为 "Sun, 18 Oct 2026 18:08:12 GMT"
+    let date = httpdate::fmt_http_date(time);
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    match parts.as_slice() {
+        [_, day, month, year, clock, _] => format!("{}/{}/{}:{} +0000", day, month, year, clock),
+        _ => date,
+    }
+}
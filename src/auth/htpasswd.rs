@@ -0,0 +1,219 @@
+//! Apache 格式的 htpasswd 文件：每行 "用户名:哈希"，支持 bcrypt、{SHA} 和 argon2
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+use tokio::fs;
+use tracing::{info, warn};
+
+/// 新用户密码的 bcrypt 代价
+const BCRYPT_COST: u32 = 10;
+
+/// 读取过的文件和读取时的修改时间，修改时间变化后重新读取
+struct PasswordFile {
+    modified: Option<SystemTime>,
+    users: Arc<HashMap<String, String>>,
+}
+
+static FILES: LazyLock<Mutex<HashMap<String, PasswordFile>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 检查用户名和密码，文件无法读取或哈希格式不支持时认证失败
+pub async fn verify(file: &str, user: &str, password: &str) -> bool {
+    let users = match load(file).await {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Cannot read htpasswd file {}: {}", file, e);
+            return false;
+        }
+    };
+    let Some(hash) = users.get(user).cloned() else {
+        return false;
+    };
+    // bcrypt 和 argon2 故意很慢，不能占用异步线程
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || verify_hash(&hash, &password))
+        .await
+        .unwrap_or(false)
+}
+
+async fn load(file: &str) -> io::Result<Arc<HashMap<String, String>>> {
+    let modified = fs::metadata(file).await?.modified().ok();
+    if let Some(cached) = FILES.lock().unwrap().get(file) {
+        if modified.is_some() && cached.modified == modified {
+            return Ok(cached.users.clone());
+        }
+    }
+    let users = Arc::new(parse(&fs::read_to_string(file).await?));
+    info!("Loaded {} users from {}", users.len(), file);
+    FILES.lock().unwrap().insert(
+        file.to_string(),
+        PasswordFile {
+            modified,
+            users: users.clone(),
+        },
+    );
+    Ok(users)
+}
+
+/// 跳过空行和 "#" 开头的注释
+fn parse(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(user, hash)| (user.to_string(), hash.to_string()))
+        .collect()
+}
+
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash).is_ok_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        });
+    }
+    if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let digest = STANDARD.encode(Sha1::digest(password.as_bytes()));
+        return constant_time_eq(digest.as_bytes(), expected.as_bytes());
+    }
+    warn!("Unsupported htpasswd hash format");
+    false
+}
+
+/// 比较时间不随第一个不同字节的位置变化
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 命令行工具：`htpasswd FILE USER`，从标准输入读取密码，
+/// 用 bcrypt 哈希后添加到文件中，用户已存在时替换密码，返回进程退出码
+pub async fn add_user(args: &[String]) -> i32 {
+    let [file, user] = args else {
+        eprintln!("usage: htpasswd FILE USER  (password is read from standard input)");
+        return 2;
+    };
+    if user.is_empty() || user.contains(':') {
+        eprintln!("User name must not be empty or contain ':'");
+        return 2;
+    }
+    let mut password = String::new();
+    if let Err(e) = io::stdin().read_line(&mut password) {
+        eprintln!("Cannot read password: {}", e);
+        return 1;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("Password must not be empty");
+        return 2;
+    }
+    let hash = match bcrypt::hash(password, BCRYPT_COST) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("Cannot hash password: {}", e);
+            return 1;
+        }
+    };
+
+    let content = match fs::read_to_string(file).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", file, e);
+            return 1;
+        }
+    };
+    let entry = format!("{}:{}", user, hash);
+    let mut replaced = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| match line.split_once(':') {
+            Some((name, _)) if name == user => {
+                replaced = true;
+                entry.clone()
+            }
+            _ => line.to_string(),
+        })
+        .collect();
+    if !replaced {
+        lines.push(entry);
+    }
+    if let Err(e) = fs::write(file, lines.join("\n") + "\n").await {
+        eprintln!("Cannot write {}: {}", file, e);
+        return 1;
+    }
+    println!(
+        "{} user {}",
+        if replaced { "Updated" } else { "Added" },
+        user
+    );
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    #[test]
+    fn parses_users_and_skips_comments() {
+        let users = parse("# admins\n\nalice:{SHA}abc\n  bob:$2y$10$xyz  \nbroken\n");
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["alice"], "{SHA}abc");
+        assert_eq!(users["bob"], "$2y$10$xyz");
+    }
+
+    #[test]
+    fn verifies_sha1() {
+        let hash = "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=";
+        assert!(verify_hash(hash, "password"));
+        assert!(!verify_hash(hash, "Password"));
+    }
+
+    #[test]
+    fn verifies_bcrypt() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_hash(&hash, "secret"));
+        assert!(!verify_hash(&hash, "secret "));
+        // Apache htpasswd 生成的是 $2y$ 前缀
+        assert!(verify_hash(&hash.replacen("$2b$", "$2y$", 1), "secret"));
+    }
+
+    #[test]
+    fn verifies_argon2() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_hash(&hash, "secret"));
+        assert!(!verify_hash(&hash, "other"));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(!verify_hash("plaintext", "plaintext"));
+        assert!(!verify_hash("$1$salt$hash", "secret"));
+    }
+
+    #[tokio::test]
+    async fn verify_reads_the_file() {
+        let file = std::env::temp_dir().join(format!("htpasswd-test-{}", std::process::id()));
+        std::fs::write(&file, "alice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        let path = file.to_str().unwrap();
+        assert!(verify(path, "alice", "password").await);
+        assert!(!verify(path, "alice", "wrong").await);
+        assert!(!verify(path, "bob", "password").await);
+        std::fs::remove_file(&file).unwrap();
+        assert!(!verify(path, "alice", "password").await);
+    }
+}
@@ -0,0 +1,128 @@
+mod htpasswd;
+mod jwt;
+mod subrequest;
+
+use crate::{
+    config::{self, prefix_matches, AuthConfig},
+    http::{HttpRequest, HttpResponse},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+pub use htpasswd::{add_user, constant_time_eq};
+use tracing::warn;
+
+/// Authorization 头中的认证信息
+enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+/// 请求路径所在的受保护位置，最长前缀优先
+async fn location_for(req: &HttpRequest) -> Option<AuthConfig> {
+    let config = config::for_request(req).await.ok()?;
+    config
+        .auth
+        .iter()
+        .filter(|auth| prefix_matches(&auth.path, &req.path))
+        .max_by_key(|auth| auth.path.len())
+        .cloned()
+}
+
+/// 检查受保护位置的认证信息，通过时把用户名写入 remote_user，供日志和 CGI 使用，
+/// JWT 声明和认证服务的响应头按配置写入请求头；没有通过时返回给客户端的响应
+pub async fn authenticate(req: &mut HttpRequest) -> Result<(), HttpResponse> {
+    let Some(location) = location_for(req).await else {
+        return Ok(());
+    };
+    // 客户端不能自己伪造由服务器写入的请求头
+    let jwt_headers = location
+        .jwt
+        .iter()
+        .flat_map(|jwt| jwt.forward_claims.values());
+    let subrequest_headers = location
+        .auth_request
+        .iter()
+        .flat_map(|config| &config.copy_headers);
+    for header in jwt_headers.chain(subrequest_headers) {
+        req.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(header));
+    }
+
+    if location.htpasswd.is_some() || location.jwt.is_some() {
+        verify_credentials(req, &location).await?;
+    }
+    if let Some(config) = &location.auth_request {
+        subrequest::authorize(req, &location.path, config).await?;
+    }
+    Ok(())
+}
+
+/// Basic 认证或 JWT，没有有效的认证信息时返回 401，JWT 有效但不满足声明规则时返回 403
+async fn verify_credentials(
+    req: &mut HttpRequest,
+    location: &AuthConfig,
+) -> Result<(), HttpResponse> {
+    let credentials = credentials(req);
+    match (&credentials, &location.htpasswd, &location.jwt) {
+        (Some(Credentials::Basic { user, password }), Some(file), _) => {
+            if htpasswd::verify(file, user, password).await {
+                req.remote_user = Some(user.clone());
+                return Ok(());
+            }
+            warn!("Authentication failed for user {:?} on {}", user, req.path);
+        }
+        (Some(Credentials::Bearer(token)), _, Some(config)) => {
+            match jwt::validate(config, token).await {
+                Ok(claims) if jwt::authorized(config, &claims) => {
+                    for (claim, header) in &config.forward_claims {
+                        if let Some(value) = jwt::claim_value(&claims, claim) {
+                            req.headers.insert(header.clone(), jwt::claim_text(value));
+                        }
+                    }
+                    req.remote_user = jwt::claim_value(&claims, "sub").map(jwt::claim_text);
+                    return Ok(());
+                }
+                Ok(_) => {
+                    warn!("JWT claims do not satisfy the rules on {}", req.path);
+                    return Err(HttpResponse::forbidden());
+                }
+                Err(e) => warn!("Invalid JWT on {}: {}", req.path, e),
+            }
+        }
+        _ => {}
+    }
+
+    let realm = location.realm.replace(['\\', '"'], "");
+    let mut challenges = Vec::new();
+    if location.htpasswd.is_some() {
+        challenges.push(format!("Basic realm=\"{}\"", realm));
+    }
+    if location.jwt.is_some() {
+        // RFC 6750 第 3.1 节：带了令牌但无效时说明原因
+        match credentials {
+            Some(Credentials::Bearer(_)) => challenges.push(format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\"",
+                realm
+            )),
+            _ => challenges.push(format!("Bearer realm=\"{}\"", realm)),
+        }
+    }
+    Err(HttpResponse::from_status(401, "").header("WWW-Authenticate", &challenges.join(", ")))
+}
+
+/// 解析 "Authorization: Basic base64(用户名:密码)" 或 "Authorization: Bearer 令牌"
+fn credentials(req: &HttpRequest) -> Option<Credentials> {
+    let (scheme, value) = req.header("Authorization")?.trim().split_once(' ')?;
+    let value = value.trim();
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        return Some(Credentials::Bearer(value.to_string()));
+    }
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(value).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some(Credentials::Basic {
+        user: user.to_string(),
+        password: password.to_string(),
+    })
+}
@@ -0,0 +1,226 @@
+//! 认证子请求（与 nginx 的 auth_request 类似）：把原请求的方法、URI 和请求头交给认证服务，
+//! 2xx 允许请求，401/403 返回给客户端，结果可以按令牌短暂缓存
+
+use crate::{
+    cgi::{self, CgiResponse},
+    config::AuthRequestConfig,
+    http::{HttpRequest, HttpResponse},
+    proxy,
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// 缓存超过这个数量时清理过期的结果
+const MAX_CACHED: usize = 1024;
+
+/// 认证服务的回答
+#[derive(Clone)]
+struct Verdict {
+    code: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Verdict {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    }
+}
+
+// 缓存的回答和过期时间
+static CACHE: LazyLock<Mutex<HashMap<String, (Instant, Verdict)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 询问认证服务，允许时把配置的响应头复制到请求中，拒绝时返回给客户端的响应。
+/// `location` 为受保护位置的路径前缀，用于区分缓存
+pub async fn authorize(
+    req: &mut HttpRequest,
+    location: &str,
+    config: &AuthRequestConfig,
+) -> Result<(), HttpResponse> {
+    let key = cache_key(req, location, config);
+    let cached = key.as_ref().and_then(|key| {
+        let cache = CACHE.lock().unwrap();
+        let (expires, verdict) = cache.get(key)?;
+        (*expires > Instant::now()).then(|| verdict.clone())
+    });
+    let verdict = match cached {
+        Some(verdict) => verdict,
+        None => {
+            let verdict = ask(req, config)
+                .await
+                .ok_or_else(HttpResponse::internal_server_error)?;
+            if let Some(key) = key {
+                store(key, verdict.clone(), config.cache_secs);
+            }
+            verdict
+        }
+    };
+
+    match verdict.code {
+        200..=299 => {
+            for name in &config.copy_headers {
+                if let Some(val) = verdict.header(name) {
+                    req.headers.insert(name.clone(), val.to_string());
+                }
+            }
+            if let Some(name) = &config.user_header {
+                req.remote_user = verdict.header(name).map(str::to_string);
+            }
+            Ok(())
+        }
+        code @ (401 | 403) => {
+            info!("Auth service denied {} with {}", req.path, code);
+            let mut response = HttpResponse::from_status(code, "");
+            if let Some(challenge) = verdict.header("WWW-Authenticate") {
+                response = response.header("WWW-Authenticate", challenge);
+            }
+            Err(response)
+        }
+        code => {
+            error!("Unexpected status {} from auth service", code);
+            Err(HttpResponse::internal_server_error())
+        }
+    }
+}
+
+/// 只缓存带有令牌（Authorization 或 Cookie）的请求
+fn cache_key(req: &HttpRequest, location: &str, config: &AuthRequestConfig) -> Option<String> {
+    if config.cache_secs == 0 {
+        return None;
+    }
+    let authorization = req.header("Authorization");
+    let cookie = req.header("Cookie");
+    if authorization.is_none() && cookie.is_none() {
+        return None;
+    }
+    Some(format!(
+        "{}\n{}\n{}",
+        location,
+        authorization.unwrap_or(""),
+        cookie.unwrap_or("")
+    ))
+}
+
+fn store(key: String, verdict: Verdict, cache_secs: u64) {
+    let now = Instant::now();
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED {
+        cache.retain(|_, (expires, _)| *expires > now);
+    }
+    cache.insert(key, (now + Duration::from_secs(cache_secs), verdict));
+}
+
+/// 发送子请求，认证服务无法访问时返回 None
+async fn ask(req: &HttpRequest, config: &AuthRequestConfig) -> Option<Verdict> {
+    if let Some(url) = &config.url {
+        let Some((address, path)) = parse_url(url) else {
+            error!("Invalid auth_request url {}", url);
+            return None;
+        };
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let (code, headers) = proxy::subrequest(&address, &path, req, timeout).await?;
+        return Some(Verdict { code, headers });
+    }
+    if let Some(script) = &config.cgi {
+        return ask_script(req, Path::new(script)).await;
+    }
+    error!("auth_request needs a url or a cgi script");
+    None
+}
+
+/// 用 GET 运行认证脚本，原来的方法和 URI 以 HTTP_X_ORIGINAL_METHOD、HTTP_X_ORIGINAL_URI 传递
+async fn ask_script(req: &HttpRequest, script: &Path) -> Option<Verdict> {
+    let sub = script_request(req);
+    let root = script.parent().unwrap_or(Path::new("/"));
+    match cgi::run_cgi(script, root, &sub, "").await {
+        CgiResponse::Response(response) => Some(Verdict {
+            code: response.code.parse().ok()?,
+            headers: response.headers.into_iter().collect(),
+        }),
+        CgiResponse::LocalRedirect(_) => {
+            error!("Auth script {} answered with a redirect", script.display());
+            None
+        }
+    }
+}
+
+/// 交给认证脚本的请求，客户端发来的 X-Original-* 被服务器的值代替
+fn script_request(req: &HttpRequest) -> HttpRequest {
+    let mut sub = req.clone();
+    sub.method = "GET".to_string();
+    sub.body.clear();
+    let managed = [
+        "Content-Length",
+        "Content-Type",
+        "X-Original-Method",
+        "X-Original-URI",
+    ];
+    sub.headers
+        .retain(|key, _| !managed.iter().any(|h| h.eq_ignore_ascii_case(key)));
+    let mut uri = req.path.clone();
+    if !req.query.is_empty() {
+        uri.push('?');
+        uri.push_str(&req.query);
+    }
+    sub.headers
+        .insert("X-Original-Method".to_string(), req.method.clone());
+    sub.headers.insert("X-Original-URI".to_string(), uri);
+    sub
+}
+
+/// 把 "http://host:port/path" 拆成上游地址和路径，端口默认为 80
+fn parse_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    let address = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+    {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Some((address, path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_request_replaces_client_original_headers() {
+        let mut req = HttpRequest::new();
+        req.method = "DELETE".to_string();
+        req.path = "/private/a".to_string();
+        req.query = "x=1".to_string();
+        req.body = b"data".to_vec();
+        for (key, val) in [
+            ("x-original-uri", "/public"),
+            ("X-ORIGINAL-METHOD", "GET"),
+            ("Content-Length", "4"),
+            ("Cookie", "session=1"),
+        ] {
+            req.headers.insert(key.to_string(), val.to_string());
+        }
+
+        let sub = script_request(&req);
+        assert_eq!(sub.method, "GET");
+        assert!(sub.body.is_empty());
+        assert_eq!(sub.headers.len(), 3);
+        assert_eq!(sub.headers["X-Original-Method"], "DELETE");
+        assert_eq!(sub.headers["X-Original-URI"], "/private/a?x=1");
+        assert_eq!(sub.header("Cookie"), Some("session=1"));
+    }
+}
@@ -0,0 +1,557 @@
+//! FastCGI 客户端，协议见 https://fastcgi-archives.github.io/FastCGI_Specification.html
+//!
+//! 每个后端地址维护一个连接池，连接以 FCGI_KEEP_CONN 方式复用。
+//! 后端支持多路复用时，同一连接上可以同时进行多个请求，读取任务按请求 ID 分发记录。
+
+use super::BackendStream;
+use crate::config::BackendConfig;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::AbortHandle;
+use tracing::{error, warn, Instrument};
+
+const FCGI_VERSION_1: u8 = 1;
+
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_ABORT_REQUEST: u8 = 2;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_KEEP_CONN: u8 = 1;
+
+// FCGI_END_REQUEST 中的 protocolStatus
+const FCGI_CANT_MPX_CONN: u8 = 1;
+const FCGI_OVERLOADED: u8 = 2;
+const FCGI_UNKNOWN_ROLE: u8 = 3;
+
+/// 单条记录的最大内容长度
+const MAX_CONTENT_LENGTH: usize = 0xffff;
+/// 多路复用时每条连接上的最大并发请求数
+const MAX_MULTIPLEXED_REQUESTS: usize = 64;
+/// 每个请求缓冲的记录数，写满后读取任务等待，后端的输出随之暂停
+const RECORD_BUFFER: usize = 16;
+
+struct Record {
+    kind: u8,
+    content: Vec<u8>,
+}
+
+/// 一条到后端的连接
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<BackendStream>>,
+    /// 正在进行的请求，读取任务把记录发给对应的请求
+    requests: Mutex<HashMap<u16, Sender<Record>>>,
+    alive: AtomicBool,
+    reader_task: Mutex<Option<AbortHandle>>,
+}
+
+// 按后端地址保存的连接池
+static POOLS: LazyLock<Mutex<HashMap<String, Vec<Arc<Connection>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+impl Connection {
+    async fn open(address: &str) -> io::Result<Arc<Connection>> {
+        let stream = BackendStream::connect(address).await?;
+        let (reader, writer) = tokio::io::split(stream);
+        let conn = Arc::new(Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            requests: Mutex::new(HashMap::new()),
+            alive: AtomicBool::new(true),
+            reader_task: Mutex::new(None),
+        });
+        let handle = tokio::spawn(read_loop(conn.clone(), reader));
+        *conn.reader_task.lock().unwrap() = Some(handle.abort_handle());
+        Ok(conn)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.requests.lock().unwrap().is_empty()
+    }
+
+    /// 在连接上登记一个新请求，连接已满时返回 None
+    fn register(&self, limit: usize) -> Option<(u16, Receiver<Record>)> {
+        let mut requests = self.requests.lock().unwrap();
+        if !self.is_alive() || requests.len() >= limit {
+            return None;
+        }
+        // 请求 ID 从 1 开始，0 保留给管理记录
+        let id = (1..=u16::MAX).find(|id| !requests.contains_key(id))?;
+        let (tx, rx) = mpsc::channel(RECORD_BUFFER);
+        requests.insert(id, tx);
+        Some((id, rx))
+    }
+
+    /// 关闭连接：停止读取任务，写端随最后一个引用一起释放
+    fn close(&self) {
+        self.alive.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.reader_task.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    async fn write_request(
+        &self,
+        id: u16,
+        env: &[(String, String)],
+        body: &[u8],
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let role = FCGI_RESPONDER.to_be_bytes();
+        push_record(
+            &mut buf,
+            FCGI_BEGIN_REQUEST,
+            id,
+            &[role[0], role[1], FCGI_KEEP_CONN, 0, 0, 0, 0, 0],
+        );
+        push_stream(&mut buf, FCGI_PARAMS, id, &encode_params(env));
+        push_stream(&mut buf, FCGI_STDIN, id, body);
+
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&buf).await?;
+        writer.flush().await
+    }
+
+    async fn write_record(&self, kind: u8, id: u16, content: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        push_record(&mut buf, kind, id, content);
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&buf).await?;
+        writer.flush().await
+    }
+}
+
+/// 发送请求，返回后端 FCGI_STDOUT 的数据流（CGI 格式的响应）
+pub(super) async fn send_request(
+    backend: &BackendConfig,
+    env: Vec<(String, String)>,
+    body: &[u8],
+) -> io::Result<DuplexStream> {
+    // 复用的空闲连接可能已被后端关闭，写入失败时换一条新连接重试一次
+    let (conn, id, mut records) = loop {
+        let (conn, id, records, reused) = acquire(backend).await?;
+        match conn.write_request(id, &env, body).await {
+            Ok(()) => break (conn, id, records),
+            Err(e) => {
+                conn.close();
+                if !reused {
+                    return Err(e);
+                }
+                warn!(
+                    "Pooled FastCGI connection to {} failed: {}",
+                    backend.address, e
+                );
+            }
+        }
+    };
+
+    let (mut output, reader) = tokio::io::duplex(64 * 1024);
+    let backend = backend.clone();
+    tokio::spawn(
+        async move {
+            let mut aborted = false;
+            let mut responded = false;
+            while let Some(record) = records.recv().await {
+                match record.kind {
+                    FCGI_STDOUT if !aborted => {
+                        responded |= !record.content.is_empty();
+                        let written = output.write_all(&record.content).await;
+                        if written.is_err() {
+                            // 客户端已经不再读取，通知后端放弃该请求
+                            aborted = true;
+                            if let Err(e) = conn.write_record(FCGI_ABORT_REQUEST, id, &[]).await {
+                                error!("Failed to abort FastCGI request: {}", e);
+                            }
+                        }
+                    }
+                    FCGI_STDERR => {
+                        for line in String::from_utf8_lossy(&record.content).lines() {
+                            warn!("FastCGI stderr: {}", line);
+                        }
+                    }
+                    FCGI_END_REQUEST => {
+                        // 后端拒绝了请求，没有输出时按 CGI 格式返回对应的状态码
+                        if let Some(status) = rejection_status(&record.content) {
+                            warn!(
+                                "FastCGI backend {} rejected request: {}",
+                                backend.address, status
+                            );
+                            if !responded {
+                                let head = format!("Status: {}\r\n\r\n", status);
+                                let _ = output.write_all(head.as_bytes()).await;
+                            }
+                        }
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            drop(output);
+            release(&backend);
+        }
+        .in_current_span(),
+    );
+    Ok(reader)
+}
+
+/// 从连接池中取一条有空位的连接，没有时新建，最后一个值表示连接是否为复用的
+async fn acquire(
+    backend: &BackendConfig,
+) -> io::Result<(Arc<Connection>, u16, Receiver<Record>, bool)> {
+    let limit = if backend.multiplex {
+        MAX_MULTIPLEXED_REQUESTS
+    } else {
+        1
+    };
+    {
+        let mut pools = POOLS.lock().unwrap();
+        let conns = pools.entry(backend.address.clone()).or_default();
+        conns.retain(|conn| conn.is_alive());
+        for conn in conns.iter() {
+            if let Some((id, records)) = conn.register(limit) {
+                return Ok((conn.clone(), id, records, true));
+            }
+        }
+    }
+
+    let conn = Connection::open(&backend.address).await?;
+    let (id, records) = conn
+        .register(limit)
+        .ok_or_else(|| io::Error::other("FastCGI connection closed"))?;
+    POOLS
+        .lock()
+        .unwrap()
+        .entry(backend.address.clone())
+        .or_default()
+        .push(conn.clone());
+    Ok((conn, id, records, false))
+}
+
+/// 请求结束后清理连接池，空闲连接超过 max_idle 时关闭多余的
+fn release(backend: &BackendConfig) {
+    let mut pools = POOLS.lock().unwrap();
+    let Some(conns) = pools.get_mut(&backend.address) else {
+        return;
+    };
+    let mut idle = 0;
+    conns.retain(|conn| {
+        if !conn.is_alive() {
+            return false;
+        }
+        if conn.is_idle() {
+            idle += 1;
+            if idle > backend.max_idle {
+                conn.close();
+                return false;
+            }
+        }
+        true
+    });
+}
+
+/// 读取后端发来的记录并按请求 ID 分发，连接断开时所有请求都会收到通道关闭
+async fn read_loop(conn: Arc<Connection>, mut reader: ReadHalf<BackendStream>) {
+    loop {
+        let (id, record) = match read_record(&mut reader).await {
+            Ok(r) => r,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    error!("FastCGI connection error: {}", e);
+                }
+                break;
+            }
+        };
+        let end = record.kind == FCGI_END_REQUEST;
+        let tx = {
+            let mut requests = conn.requests.lock().unwrap();
+            if end {
+                requests.remove(&id)
+            } else {
+                requests.get(&id).cloned()
+            }
+        };
+        // 请求的缓冲满时在这里等待，不再继续读取后端的输出
+        if let Some(tx) = tx {
+            let _ = tx.send(record).await;
+        }
+    }
+    conn.alive.store(false, Ordering::Relaxed);
+    conn.requests.lock().unwrap().clear();
+}
+
+async fn read_record<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(u16, Record)> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+    if header[0] != FCGI_VERSION_1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported FastCGI version",
+        ));
+    }
+    let kind = header[1];
+    let id = u16::from_be_bytes([header[2], header[3]]);
+    let content_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding_length = header[6] as usize;
+
+    let mut content = vec![0u8; content_length + padding_length];
+    reader.read_exact(&mut content).await?;
+    content.truncate(content_length);
+    Ok((id, Record { kind, content }))
+}
+
+/// END_REQUEST 的 protocolStatus 表示后端没有处理请求时，返回给客户端的状态
+fn rejection_status(content: &[u8]) -> Option<&'static str> {
+    match content.get(4).copied()? {
+        FCGI_OVERLOADED => Some("503 Service Unavailable"),
+        FCGI_CANT_MPX_CONN | FCGI_UNKNOWN_ROLE => Some("502 Bad Gateway"),
+        _ => None,
+    }
+}
+
+/// 写入一条记录，内容按 8 字节对齐填充
+fn push_record(buf: &mut Vec<u8>, kind: u8, id: u16, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    buf.push(FCGI_VERSION_1);
+    buf.push(kind);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    buf.push(padding as u8);
+    buf.push(0);
+    buf.extend_from_slice(content);
+    buf.extend(std::iter::repeat_n(0, padding));
+}
+
+/// 写入一个数据流（PARAMS/STDIN），按记录长度上限切分，以空记录结尾
+fn push_stream(buf: &mut Vec<u8>, kind: u8, id: u16, data: &[u8]) {
+    for chunk in data.chunks(MAX_CONTENT_LENGTH) {
+        push_record(buf, kind, id, chunk);
+    }
+    push_record(buf, kind, id, &[]);
+}
+
+/// 名值对编码：长度小于 128 用 1 字节，否则用最高位置 1 的 4 字节
+fn encode_params(env: &[(String, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in env {
+        push_length(&mut buf, name.len());
+        push_length(&mut buf, value.len());
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf
+}
+
+fn push_length(buf: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        buf.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackendProtocol;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    /// 响应者收到完整请求后的回答：(PARAMS, STDIN) -> 写回的记录
+    type Reply = fn(u16, &HashMap<String, String>, &[u8]) -> Vec<u8>;
+
+    fn backend(address: String) -> BackendConfig {
+        BackendConfig {
+            pattern: ".php".to_string(),
+            protocol: BackendProtocol::FastCgi,
+            address,
+            timeout_secs: 5,
+            multiplex: false,
+            max_idle: 1,
+        }
+    }
+
+    fn decode_params(mut data: &[u8]) -> HashMap<String, String> {
+        fn length(data: &mut &[u8]) -> usize {
+            if data[0] < 0x80 {
+                let len = data[0] as usize;
+                *data = &data[1..];
+                len
+            } else {
+                let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) & 0x7fff_ffff;
+                *data = &data[4..];
+                len as usize
+            }
+        }
+        let mut params = HashMap::new();
+        while !data.is_empty() {
+            let name_len = length(&mut data);
+            let value_len = length(&mut data);
+            let name = String::from_utf8(data[..name_len].to_vec()).unwrap();
+            let value = String::from_utf8(data[name_len..name_len + value_len].to_vec()).unwrap();
+            params.insert(name, value);
+            data = &data[name_len + value_len..];
+        }
+        params
+    }
+
+    /// 在本地端口上运行的最小 FastCGI 响应者，返回地址和接受的连接数
+    async fn responder(reply: Reply) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut params = HashMap::<u16, Vec<u8>>::new();
+                    let mut stdin = HashMap::<u16, Vec<u8>>::new();
+                    while let Ok((id, record)) = read_record(&mut stream).await {
+                        match record.kind {
+                            FCGI_PARAMS => params.entry(id).or_default().extend(record.content),
+                            FCGI_STDIN if !record.content.is_empty() => {
+                                stdin.entry(id).or_default().extend(record.content)
+                            }
+                            FCGI_STDIN => {
+                                let env = decode_params(&params.remove(&id).unwrap_or_default());
+                                let body = stdin.remove(&id).unwrap_or_default();
+                                let out = reply(id, &env, &body);
+                                if stream.write_all(&out).await.is_err() {
+                                    break;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+        (address, accepted)
+    }
+
+    fn end_request(buf: &mut Vec<u8>, id: u16, protocol_status: u8) {
+        push_record(
+            buf,
+            FCGI_END_REQUEST,
+            id,
+            &[0, 0, 0, 0, protocol_status, 0, 0, 0],
+        );
+    }
+
+    async fn fetch(backend: &BackendConfig, env: Vec<(String, String)>, body: &[u8]) -> Vec<u8> {
+        let mut output = send_request(backend, env, body).await.unwrap();
+        let mut data = Vec::new();
+        output.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn long_names_and_values_use_four_byte_lengths() {
+        let value = "v".repeat(200);
+        let encoded = encode_params(&env(&[("SHORT", &value)]));
+        assert_eq!(&encoded[..5], &[5, 0x80, 0, 0, 200]);
+        assert_eq!(decode_params(&encoded)["SHORT"], value);
+    }
+
+    #[tokio::test]
+    async fn returns_stdout_as_cgi_output() {
+        let (address, _) = responder(|id, env, body| {
+            let text = format!(
+                "Content-Type: text/plain\r\n\r\n{}:{}",
+                env["SCRIPT_NAME"],
+                String::from_utf8_lossy(body)
+            );
+            let mut out = Vec::new();
+            push_stream(&mut out, FCGI_STDOUT, id, text.as_bytes());
+            end_request(&mut out, id, 0);
+            out
+        })
+        .await;
+        let long_value = "x".repeat(1000);
+        let env = env(&[("SCRIPT_NAME", "/index.php"), ("LONG", &long_value)]);
+        let data = fetch(&backend(address), env, b"name=value").await;
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "Content-Type: text/plain\r\n\r\n/index.php:name=value"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_output_larger_than_the_record_buffer() {
+        const HEAD: &[u8] = b"Content-Type: text/plain\r\n\r\n";
+        let (address, _) = responder(|id, _, _| {
+            let mut out = Vec::new();
+            push_record(
+                &mut out,
+                FCGI_STDOUT,
+                id,
+                b"Content-Type: text/plain\r\n\r\n",
+            );
+            push_stream(&mut out, FCGI_STDOUT, id, &vec![b'a'; 4 * 1024 * 1024]);
+            end_request(&mut out, id, 0);
+            out
+        })
+        .await;
+        let data = fetch(&backend(address), Vec::new(), b"").await;
+        assert_eq!(data.len(), HEAD.len() + 4 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn overloaded_backend_gives_503() {
+        let (address, _) = responder(|id, _, _| {
+            let mut out = Vec::new();
+            end_request(&mut out, id, FCGI_OVERLOADED);
+            out
+        })
+        .await;
+        let data = fetch(&backend(address), Vec::new(), b"").await;
+        assert_eq!(data, b"Status: 503 Service Unavailable\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn cant_mpx_conn_gives_502() {
+        let (address, _) = responder(|id, _, _| {
+            let mut out = Vec::new();
+            end_request(&mut out, id, FCGI_CANT_MPX_CONN);
+            out
+        })
+        .await;
+        let data = fetch(&backend(address), Vec::new(), b"").await;
+        assert_eq!(data, b"Status: 502 Bad Gateway\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn reuses_pooled_connections() {
+        let (address, accepted) = responder(|id, _, _| {
+            let mut out = Vec::new();
+            push_stream(&mut out, FCGI_STDOUT, id, b"Status: 204 No Content\r\n\r\n");
+            end_request(&mut out, id, 0);
+            out
+        })
+        .await;
+        let backend = backend(address);
+        for _ in 0..3 {
+            let data = fetch(&backend, Vec::new(), b"").await;
+            assert_eq!(data, b"Status: 204 No Content\r\n\r\n");
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,147 @@
+mod fastcgi;
+mod scgi;
+mod uwsgi;
+
+use crate::{
+    cgi::{self, CgiReadError, CgiResponse},
+    config::{BackendConfig, BackendProtocol, Config},
+    http::{BoxedReader, HttpRequest, HttpResponse},
+};
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::Instant;
+use tracing::{error, info};
+
+/// 找到处理该文件的后端，匹配规则与 CGI 解释器相同
+pub fn find_backend<'a>(
+    script_path: &Path,
+    root: &Path,
+    config: &'a Config,
+) -> Option<&'a BackendConfig> {
+    let relative = script_path.strip_prefix(root).ok()?;
+    if cgi::exec_forbidden(relative, config) {
+        return None;
+    }
+    config
+        .backends
+        .iter()
+        .find(|backend| cgi::pattern_matches(&backend.pattern, relative))
+}
+
+pub async fn handle_backend_request(
+    backend: &BackendConfig,
+    script_path: &Path,
+    req: &HttpRequest,
+    path_info: &str,
+) -> CgiResponse {
+    info!(
+        "Forwarding {} to {:?} backend {}",
+        script_path.display(),
+        backend.protocol,
+        backend.address
+    );
+    let env = cgi::build_env(req, script_path, path_info).await;
+    let deadline = Instant::now() + Duration::from_secs(backend.timeout_secs);
+
+    let send = async {
+        let output: BoxedReader = match backend.protocol {
+            BackendProtocol::FastCgi => {
+                Box::new(fastcgi::send_request(backend, env, &req.body).await?)
+            }
+            BackendProtocol::Scgi => Box::new(scgi::send_request(backend, env, &req.body).await?),
+            BackendProtocol::Uwsgi => Box::new(uwsgi::send_request(backend, env, &req.body).await?),
+        };
+        Ok::<_, io::Error>(output)
+    };
+    let output = tokio::time::timeout_at(deadline, send).await;
+    let output = match output {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            error!("Backend {} request failed: {}", backend.address, e);
+            return CgiResponse::Response(HttpResponse::bad_gateway());
+        }
+        Err(_) => {
+            error!("Backend {} timed out", backend.address);
+            return CgiResponse::Response(HttpResponse::gateway_timeout());
+        }
+    };
+
+    match cgi::read_cgi_response(output, deadline).await {
+        Ok(parsed) => parsed,
+        Err(CgiReadError::Timeout) => {
+            error!("Backend {} timed out", backend.address);
+            CgiResponse::Response(HttpResponse::gateway_timeout())
+        }
+        Err(CgiReadError::Io(e)) => {
+            error!("Failed to read from backend {}: {}", backend.address, e);
+            CgiResponse::Response(HttpResponse::bad_gateway())
+        }
+        Err(CgiReadError::Malformed(e)) => {
+            error!(
+                "Malformed response from backend {}: {:?}",
+                backend.address, e
+            );
+            CgiResponse::Response(HttpResponse::bad_gateway())
+        }
+    }
+}
+
+/// 到后端的连接，地址以 "unix:" 开头时使用 Unix 域套接字
+pub enum BackendStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl BackendStream {
+    pub async fn connect(address: &str) -> io::Result<Self> {
+        match address.strip_prefix("unix:") {
+            Some(path) => UnixStream::connect(path).await.map(BackendStream::Unix),
+            None => TcpStream::connect(address).await.map(BackendStream::Tcp),
+        }
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            BackendStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            BackendStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            BackendStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            BackendStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
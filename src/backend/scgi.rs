@@ -0,0 +1,77 @@
+//! SCGI 客户端，协议见 https://python.ca/scgi/protocol.txt
+//!
+//! 每个请求使用一条新连接，响应与 CGI 脚本的输出格式相同。
+
+use super::BackendStream;
+use crate::config::BackendConfig;
+use std::io;
+use tokio::io::AsyncWriteExt;
+
+/// 发送请求，返回连接本身作为响应数据流
+pub(super) async fn send_request(
+    backend: &BackendConfig,
+    env: Vec<(String, String)>,
+    body: &[u8],
+) -> io::Result<BackendStream> {
+    let request = encode_request(&env, body);
+    let mut stream = BackendStream::connect(&backend.address).await?;
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+fn encode_request(env: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    // CONTENT_LENGTH 必须是第一个头，SCGI 头标识协议版本
+    let mut headers = Vec::new();
+    push_header(&mut headers, "CONTENT_LENGTH", &body.len().to_string());
+    push_header(&mut headers, "SCGI", "1");
+    for (name, value) in env {
+        if name != "CONTENT_LENGTH" {
+            push_header(&mut headers, name, value);
+        }
+    }
+
+    // 头部以 netstring 编码：<长度>:<内容>,
+    let mut request = format!("{}:", headers.len()).into_bytes();
+    request.extend_from_slice(&headers);
+    request.push(b',');
+    request.extend_from_slice(body);
+    request
+}
+
+fn push_header(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn headers_are_a_netstring_starting_with_content_length() {
+        let request = encode_request(
+            &env(&[("REQUEST_METHOD", "POST"), ("CONTENT_LENGTH", "999")]),
+            b"body",
+        );
+        let headers = b"CONTENT_LENGTH\x004\x00SCGI\x001\x00REQUEST_METHOD\x00POST\x00";
+        let mut expected = format!("{}:", headers.len()).into_bytes();
+        expected.extend_from_slice(headers);
+        expected.extend_from_slice(b",body");
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn empty_body_still_sends_content_length() {
+        let request = encode_request(&[], b"");
+        assert_eq!(request, b"24:CONTENT_LENGTH\x000\x00SCGI\x001\x00,");
+    }
+}
@@ -0,0 +1,91 @@
+//! uwsgi 协议客户端，协议见 https://uwsgi-docs.readthedocs.io/en/latest/Protocol.html
+//!
+//! 每个请求使用一条新连接。应用返回的是带状态行的 HTTP 响应，
+//! 由 CGI 响应解析统一处理。
+
+use super::BackendStream;
+use crate::config::BackendConfig;
+use std::io;
+use tokio::io::AsyncWriteExt;
+
+/// modifier1 = 0 表示 WSGI 请求
+const UWSGI_MODIFIER_WSGI: u8 = 0;
+
+/// 发送请求，返回连接本身作为响应数据流
+pub(super) async fn send_request(
+    backend: &BackendConfig,
+    env: Vec<(String, String)>,
+    body: &[u8],
+) -> io::Result<BackendStream> {
+    let request = encode_request(&env, body)?;
+    let mut stream = BackendStream::connect(&backend.address).await?;
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+fn encode_request(env: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut vars = Vec::new();
+    for (name, value) in env {
+        push_string(&mut vars, name)?;
+        push_string(&mut vars, value)?;
+    }
+    let size = u16::try_from(vars.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "uwsgi vars too large"))?;
+
+    // 包头：modifier1、小端序的数据长度、modifier2
+    let mut request = vec![UWSGI_MODIFIER_WSGI];
+    request.extend_from_slice(&size.to_le_bytes());
+    request.push(0);
+    request.extend_from_slice(&vars);
+    request.extend_from_slice(body);
+    Ok(request)
+}
+
+fn push_string(buf: &mut Vec<u8>, value: &str) -> io::Result<()> {
+    let len = u16::try_from(value.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "uwsgi var too long"))?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_header_has_little_endian_sizes() {
+        let env = vec![("REQUEST_METHOD".to_string(), "GET".to_string())];
+        let request = encode_request(&env, b"body").unwrap();
+        // 2 + 14 + 2 + 3 = 21 字节的变量
+        let mut expected = vec![0, 21, 0, 0];
+        expected.extend_from_slice(&[14, 0]);
+        expected.extend_from_slice(b"REQUEST_METHOD");
+        expected.extend_from_slice(&[3, 0]);
+        expected.extend_from_slice(b"GET");
+        expected.extend_from_slice(b"body");
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn sizes_above_255_use_both_bytes() {
+        let value = "v".repeat(300);
+        let env = vec![("X".to_string(), value.clone())];
+        let request = encode_request(&env, b"").unwrap();
+        assert_eq!(&request[..4], &[0, 0x31, 0x01, 0]);
+        assert_eq!(&request[4..7], &[1, 0, b'X']);
+        assert_eq!(&request[7..9], &[0x2c, 0x01]);
+        assert_eq!(&request[9..], value.as_bytes());
+    }
+
+    #[test]
+    fn oversized_vars_are_rejected() {
+        let env = vec![("X".to_string(), "v".repeat(70_000))];
+        assert!(encode_request(&env, b"").is_err());
+        let env: Vec<_> = (0..5)
+            .map(|i| (format!("X{}", i), "v".repeat(20_000)))
+            .collect();
+        assert!(encode_request(&env, b"").is_err());
+    }
+}
@@ -0,0 +1,666 @@
+//! 位于路由和源站（反向代理、CGI、后端）之间的 HTTP 缓存
+//!
+//! 遵循 RFC 9111：按 Cache-Control、Expires 计算新鲜期，按 Vary 区分变体，
+//! 过期后用 ETag/Last-Modified 向源站验证，并支持 stale-while-revalidate
+//! 和 stale-if-error（RFC 5861）。
+
+mod store;
+
+use crate::{
+    config::{self, CacheConfig, Config},
+    http::{BodyStream, BoxedReader, HttpRequest, HttpResponse},
+    ip_filter,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Cursor;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+use store::CachedResponse;
+use tokio::io::AsyncReadExt;
+use tracing::{error, info, warn, Instrument};
+
+/// 可以缓存的状态码（RFC 9110 第 15.1 节中默认可缓存的状态码）
+const CACHEABLE_STATUS: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+/// 不保存到缓存中的响应头，逐跳头和由缓存重新生成的头
+const UNSTORED_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Transfer-Encoding",
+    "Content-Length",
+    "Age",
+    "X-Cache",
+    "Trailer",
+    "Upgrade",
+];
+
+/// 发往源站的条件请求头，填充缓存时去掉，由缓存自己回答
+const CONDITIONAL_HEADERS: [&str; 4] =
+    ["If-None-Match", "If-Modified-Since", "If-Match", "If-Range"];
+
+// 正在填充缓存的 URL，同一 URL 的并发未命中等待第一个请求的结果
+static FILL_LOCKS: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 新鲜度相关的指令
+struct Freshness {
+    fresh_for: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+    must_revalidate: bool,
+}
+
+/// 启动磁盘缓存的定期清理
+pub fn start(config: &Config) {
+    if let Some(cache) = &config.cache {
+        store::start(cache);
+    }
+}
+
+/// 请求是否经过缓存：只有 GET 和 HEAD，且请求没有要求 no-store。
+/// 正向代理的请求不缓存，它们的 Host 头由客户端任意填写，
+/// 否则可以用其他站点的响应污染本站的缓存
+pub fn applies(req: &HttpRequest) -> bool {
+    (req.method == "GET" || req.method == "HEAD")
+        && req.authority.is_none()
+        && !has_directive(&directives(req.header("Cache-Control")), "no-store")
+}
+
+/// 从缓存回答请求，未命中或过期时调用 fetch 向源站获取
+pub async fn handle<F, Fut>(req: &HttpRequest, config: &CacheConfig, fetch: F) -> HttpResponse
+where
+    F: Fn(HttpRequest) -> Fut + Copy + Send + 'static,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    let key = cache_key(req);
+    let request_cc = directives(req.header("Cache-Control"));
+    // 客户端要求先验证
+    let force_revalidate = has_directive(&request_cc, "no-cache")
+        || directive_secs(&request_cc, "max-age") == Some(0)
+        || req
+            .header("Pragma")
+            .is_some_and(|v| v.eq_ignore_ascii_case("no-cache"));
+
+    if let Some(entry) = store::lookup(&key, req, config).await {
+        let age = entry.age();
+        if !force_revalidate && age < entry.fresh_for {
+            return serve(&entry, req, "HIT");
+        }
+        if !force_revalidate
+            && !entry.must_revalidate
+            && age < entry.fresh_for + entry.stale_while_revalidate
+        {
+            tokio::spawn(
+                revalidate_in_background(key, entry.clone(), req.clone(), config.clone(), fetch)
+                    .in_current_span(),
+            );
+            return serve(&entry, req, "STALE");
+        }
+        return revalidate(&key, entry, req, config, fetch).await;
+    }
+
+    // HEAD 没有响应体，未命中时不填充缓存
+    if req.method == "HEAD" {
+        return fetch(req.clone()).await.header("X-Cache", "MISS");
+    }
+
+    // 合并并发的未命中：拿到锁后先检查其他请求是否已经填充了缓存
+    let lock = fill_lock(&key);
+    let timeout = Duration::from_secs(config.lock_timeout_secs);
+    let guard = tokio::time::timeout(timeout, lock.clone().lock_owned())
+        .await
+        .ok();
+    if guard.is_some() && !force_revalidate {
+        if let Some(entry) = store::lookup(&key, req, config).await {
+            if entry.age() < entry.fresh_for {
+                release_fill_lock(&key, lock, guard);
+                return serve(&entry, req, "HIT");
+            }
+        }
+    }
+    let response = fetch(without_conditionals(req)).await;
+    let response = store_response(&key, req, response, config, "MISS").await;
+    release_fill_lock(&key, lock, guard);
+    response
+}
+
+/// 管理操作：PURGE 删除一个 URL 的所有缓存变体，只允许 purge_allow 中的客户端地址
+pub async fn purge(req: &HttpRequest, config: &CacheConfig) -> HttpResponse {
+    let client = match config::read_config().await {
+        Ok(c) => ip_filter::client_ip(req, &c.ip_access),
+        Err(_) => None,
+    };
+    let allowed = client.is_some_and(|ip| config.purge_allow.iter().any(|net| net.contains(&ip)));
+    if !allowed {
+        warn!("PURGE from {:?} rejected", client);
+        return HttpResponse::from_status(403, "");
+    }
+    let key = cache_key(req);
+    if store::remove(&key, config).await {
+        info!("Purged cache for {}", key);
+        HttpResponse::ok()
+    } else {
+        HttpResponse::not_found()
+    }
+}
+
+/// 缓存键：Host 加上路径和查询字符串
+fn cache_key(req: &HttpRequest) -> String {
+    let host = req.header("Host").unwrap_or("").to_ascii_lowercase();
+    if req.query.is_empty() {
+        format!("{}{}", host, req.path)
+    } else {
+        format!("{}{}?{}", host, req.path, req.query)
+    }
+}
+
+fn fill_lock(key: &str) -> Arc<tokio::sync::Mutex<()>> {
+    FILL_LOCKS
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_default()
+        .clone()
+}
+
+/// 没有其他请求在等待时从表中删除锁
+fn release_fill_lock(
+    key: &str,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+) {
+    drop(guard);
+    let mut locks = FILL_LOCKS.lock().unwrap();
+    drop(lock);
+    if locks
+        .get(key)
+        .is_some_and(|lock| Arc::strong_count(lock) == 1)
+    {
+        locks.remove(key);
+    }
+}
+
+fn without_conditionals(req: &HttpRequest) -> HttpRequest {
+    let mut req = req.clone();
+    req.headers.retain(|key, _| {
+        !CONDITIONAL_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(key))
+    });
+    req
+}
+
+/// 向源站发送条件请求验证过期的缓存
+async fn revalidate<F, Fut>(
+    key: &str,
+    entry: Arc<CachedResponse>,
+    req: &HttpRequest,
+    config: &CacheConfig,
+    fetch: F,
+) -> HttpResponse
+where
+    F: Fn(HttpRequest) -> Fut,
+    Fut: Future<Output = HttpResponse>,
+{
+    let mut conditional = without_conditionals(req);
+    conditional.method = "GET".to_string();
+    if let Some(etag) = entry.header("ETag") {
+        conditional
+            .headers
+            .insert("If-None-Match".to_string(), etag.to_string());
+    }
+    if let Some(modified) = entry.header("Last-Modified") {
+        conditional
+            .headers
+            .insert("If-Modified-Since".to_string(), modified.to_string());
+    }
+    let response = fetch(conditional).await;
+    let code: u16 = response.code.parse().unwrap_or(0);
+
+    if code == 304 {
+        if let Some(updated) = refresh(&entry, &response) {
+            store::insert(updated.clone(), config).await;
+            return serve(&updated, req, "REVALIDATED");
+        }
+    }
+    if matches!(code, 500 | 502 | 503 | 504) && entry.age() < entry.fresh_for + entry.stale_if_error
+    {
+        warn!(
+            "Origin returned {} for {}, serving stale response",
+            code, key
+        );
+        return serve(&entry, req, "STALE");
+    }
+    store_response(key, req, response, config, "EXPIRED").await
+}
+
+/// stale-while-revalidate：先返回旧响应，再在后台更新缓存
+async fn revalidate_in_background<F, Fut>(
+    key: String,
+    entry: Arc<CachedResponse>,
+    req: HttpRequest,
+    config: CacheConfig,
+    fetch: F,
+) where
+    F: Fn(HttpRequest) -> Fut,
+    Fut: Future<Output = HttpResponse>,
+{
+    // 已经有请求在更新同一个 URL 时不再重复
+    let lock = fill_lock(&key);
+    let Ok(guard) = lock.clone().try_lock_owned() else {
+        return;
+    };
+    info!("Revalidating {} in the background", key);
+    let _ = revalidate(&key, entry, &req, &config, fetch).await;
+    release_fill_lock(&key, lock, Some(guard));
+}
+
+/// 用 304 响应中的新头更新缓存条目
+fn refresh(entry: &CachedResponse, not_modified: &HttpResponse) -> Option<CachedResponse> {
+    let mut updated = entry.clone();
+    for (key, val) in &not_modified.headers {
+        if is_unstored(key) {
+            continue;
+        }
+        updated
+            .headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        updated.headers.push((key.clone(), val.clone()));
+    }
+    let freshness = freshness(&updated.headers)?;
+    updated.stored_at = store::unix_now();
+    updated.initial_age = response_header(not_modified, "Age")
+        .and_then(|age| age.trim().parse().ok())
+        .unwrap_or(0);
+    updated.fresh_for = freshness.fresh_for;
+    updated.stale_while_revalidate = freshness.stale_while_revalidate;
+    updated.stale_if_error = freshness.stale_if_error;
+    updated.must_revalidate = freshness.must_revalidate;
+    Some(updated)
+}
+
+/// 可以缓存时读出完整的响应体并保存，否则原样返回
+async fn store_response(
+    key: &str,
+    req: &HttpRequest,
+    mut response: HttpResponse,
+    config: &CacheConfig,
+    status: &str,
+) -> HttpResponse {
+    let code: u16 = response.code.parse().unwrap_or(0);
+    let cc = directives(response_header(&response, "Cache-Control"));
+    let vary = response_header(&response, "Vary").unwrap_or("").to_string();
+    // 带 Authorization 的请求只有源站明确允许时才能在共享缓存中保存
+    let authorized = req.header("Authorization").is_none()
+        || ["public", "s-maxage", "must-revalidate"]
+            .iter()
+            .any(|d| has_directive(&cc, d));
+    let storable = CACHEABLE_STATUS.contains(&code)
+        && !has_directive(&cc, "no-store")
+        && !has_directive(&cc, "private")
+        && vary.trim() != "*"
+        && response_header(&response, "Set-Cookie").is_none()
+        && authorized;
+    let headers: Vec<(String, String)> = response
+        .headers
+        .iter()
+        .filter(|(key, _)| !is_unstored(key))
+        .map(|(key, val)| (key.clone(), val.clone()))
+        .collect();
+    let freshness = match freshness(&headers) {
+        Some(freshness) if storable => freshness,
+        _ => return response.header("X-Cache", status),
+    };
+    let too_large = response_header(&response, "Content-Length")
+        .and_then(|len| len.trim().parse::<usize>().ok())
+        .is_some_and(|len| len > config.max_object_bytes);
+    if too_large {
+        return response.header("X-Cache", status);
+    }
+
+    // 流式响应体先读出来，超过大小限制时把已读的部分接回去继续转发
+    let body = match response.stream.take() {
+        None => response.body.clone(),
+        Some(BodyStream::Raw(reader)) => {
+            response.stream = Some(BodyStream::Raw(reader));
+            return response.header("X-Cache", status);
+        }
+        Some(BodyStream::Chunked(reader)) => {
+            match read_limited(reader, config.max_object_bytes).await {
+                Ok(Ok(body)) => body,
+                Ok(Err(rest)) => {
+                    response.stream = Some(BodyStream::Chunked(rest));
+                    return response.header("X-Cache", status);
+                }
+                Err(e) => {
+                    error!("Failed to read response for cache: {}", e);
+                    return HttpResponse::bad_gateway();
+                }
+            }
+        }
+        Some(BodyStream::Sized(reader)) => {
+            match read_limited(reader, config.max_object_bytes).await {
+                Ok(Ok(body)) => body,
+                Ok(Err(rest)) => {
+                    response.stream = Some(BodyStream::Sized(rest));
+                    return response.header("X-Cache", status);
+                }
+                Err(e) => {
+                    error!("Failed to read response for cache: {}", e);
+                    return HttpResponse::bad_gateway();
+                }
+            }
+        }
+    };
+
+    let entry = CachedResponse {
+        key: key.to_string(),
+        code,
+        reason: response.reason.clone(),
+        headers,
+        vary: vary
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| (name.to_string(), req.header(name).map(str::to_string)))
+            .collect(),
+        stored_at: store::unix_now(),
+        initial_age: response_header(&response, "Age")
+            .and_then(|age| age.trim().parse().ok())
+            .unwrap_or(0),
+        fresh_for: freshness.fresh_for,
+        stale_while_revalidate: freshness.stale_while_revalidate,
+        stale_if_error: freshness.stale_if_error,
+        must_revalidate: freshness.must_revalidate,
+        body_len: body.len(),
+        body,
+    };
+    let response = build_response(&entry, status);
+    store::insert(entry, config).await;
+    response
+}
+
+/// 读取最多 limit 字节，超出时返回把已读部分和剩余部分接在一起的读端
+async fn read_limited(
+    mut reader: BoxedReader,
+    limit: usize,
+) -> std::io::Result<Result<Vec<u8>, BoxedReader>> {
+    let mut body = Vec::new();
+    (&mut reader)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() > limit {
+        return Ok(Err(Box::new(Cursor::new(body).chain(reader))));
+    }
+    Ok(Ok(body))
+}
+
+/// 按 Cache-Control 和 Expires 计算新鲜期，没有明确的缓存信息时不缓存
+fn freshness(headers: &[(String, String)]) -> Option<Freshness> {
+    let cc = directives(find_header(headers, "Cache-Control"));
+    let fresh_for = if has_directive(&cc, "no-cache") {
+        0
+    } else if let Some(secs) =
+        directive_secs(&cc, "s-maxage").or_else(|| directive_secs(&cc, "max-age"))
+    {
+        secs
+    } else {
+        let expires = find_header(headers, "Expires")?;
+        // 无法解析的 Expires 表示已经过期
+        let expires = httpdate::parse_http_date(expires).unwrap_or(SystemTime::UNIX_EPOCH);
+        let date = find_header(headers, "Date")
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .unwrap_or_else(SystemTime::now);
+        expires
+            .duration_since(date)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    };
+    Some(Freshness {
+        fresh_for,
+        stale_while_revalidate: directive_secs(&cc, "stale-while-revalidate").unwrap_or(0),
+        stale_if_error: directive_secs(&cc, "stale-if-error").unwrap_or(0),
+        must_revalidate: has_directive(&cc, "must-revalidate")
+            || has_directive(&cc, "proxy-revalidate"),
+    })
+}
+
+/// 用缓存条目回答请求，客户端的条件请求匹配时返回 304
+fn serve(entry: &CachedResponse, req: &HttpRequest, status: &str) -> HttpResponse {
+    if entry.code == 200 && is_not_modified(entry, req) {
+        let mut response = HttpResponse::from_status(304, "");
+        for name in ["ETag", "Last-Modified", "Cache-Control", "Expires", "Vary"] {
+            if let Some(val) = entry.header(name) {
+                response = response.header(name, val);
+            }
+        }
+        return response
+            .header("Age", &entry.age().to_string())
+            .header("X-Cache", status);
+    }
+    build_response(entry, status)
+}
+
+fn build_response(entry: &CachedResponse, status: &str) -> HttpResponse {
+    let mut response = HttpResponse::from_status(entry.code, &entry.reason);
+    for (key, val) in &entry.headers {
+        response = response.header(key, val);
+    }
+    response.body = entry.body.clone();
+    response
+        .header("Content-Length", &entry.body.len().to_string())
+        .header("Age", &entry.age().to_string())
+        .header("X-Cache", status)
+}
+
+fn is_not_modified(entry: &CachedResponse, req: &HttpRequest) -> bool {
+    if let Some(tags) = req.header("If-None-Match") {
+        let Some(etag) = entry.header("ETag") else {
+            return false;
+        };
+        // 弱比较，忽略 W/ 前缀
+        let etag = etag.trim_start_matches("W/");
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    match (
+        req.header("If-Modified-Since"),
+        entry.header("Last-Modified"),
+    ) {
+        (Some(since), Some(modified)) => {
+            match (
+                httpdate::parse_http_date(since),
+                httpdate::parse_http_date(modified),
+            ) {
+                (Ok(since), Ok(modified)) => modified <= since,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+fn is_unstored(name: &str) -> bool {
+    UNSTORED_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+fn response_header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+    response
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, val)| val.as_str())
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, val)| val.as_str())
+}
+
+/// 解析 Cache-Control，指令名转为小写
+fn directives(value: Option<&str>) -> Vec<(String, Option<String>)> {
+    let Some(value) = value else {
+        return Vec::new();
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((name, val)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(val.trim().trim_matches('"').to_string()),
+            ),
+            None => (d.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+fn has_directive(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(n, _)| n == name)
+}
+
+fn directive_secs(directives: &[(String, Option<String>)], name: &str) -> Option<u64> {
+    directives
+        .iter()
+        .find(|(n, _)| n == name)
+        .and_then(|(_, val)| val.as_deref()?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn entry(pairs: &[(&str, &str)]) -> CachedResponse {
+        CachedResponse {
+            key: "example.com/".to_string(),
+            code: 200,
+            reason: "OK".to_string(),
+            headers: headers(pairs),
+            vary: Vec::new(),
+            stored_at: store::unix_now(),
+            initial_age: 0,
+            fresh_for: 60,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            must_revalidate: false,
+            body_len: 0,
+            body: Vec::new(),
+        }
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        for (name, value) in pairs {
+            req.headers.insert(name.to_string(), value.to_string());
+        }
+        req
+    }
+
+    #[test]
+    fn forward_proxy_requests_bypass_the_cache() {
+        // 同样的 Host 和路径，只有源站形式的请求可以读写缓存
+        let mut origin = request(&[("Host", "victim")]);
+        origin.method = "GET".to_string();
+        origin.path = "/a.txt".to_string();
+        let mut proxied = origin.clone();
+        proxied.authority = Some("127.0.0.1:18090".to_string());
+        assert!(applies(&origin));
+        assert!(!applies(&proxied));
+    }
+
+    #[test]
+    fn s_maxage_wins_over_max_age() {
+        let f = freshness(&headers(&[("Cache-Control", "max-age=60, s-maxage=120")])).unwrap();
+        assert_eq!(f.fresh_for, 120);
+    }
+
+    #[test]
+    fn no_cache_is_stale_at_once() {
+        let f = freshness(&headers(&[("Cache-Control", "no-cache, max-age=60")])).unwrap();
+        assert_eq!(f.fresh_for, 0);
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        let f = freshness(&headers(&[
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Expires", "Sun, 06 Nov 1994 08:59:37 GMT"),
+        ]))
+        .unwrap();
+        assert_eq!(f.fresh_for, 600);
+        let f = freshness(&headers(&[("Expires", "0")])).unwrap();
+        assert_eq!(f.fresh_for, 0);
+    }
+
+    #[test]
+    fn no_freshness_information_is_not_cached() {
+        assert!(freshness(&headers(&[("Content-Type", "text/plain")])).is_none());
+    }
+
+    #[test]
+    fn reads_stale_extensions() {
+        let f = freshness(&headers(&[(
+            "Cache-Control",
+            "Max-Age=10, stale-while-revalidate=30, stale-if-error=\"300\", proxy-revalidate",
+        )]))
+        .unwrap();
+        assert_eq!(f.fresh_for, 10);
+        assert_eq!(f.stale_while_revalidate, 30);
+        assert_eq!(f.stale_if_error, 300);
+        assert!(f.must_revalidate);
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        let entry = entry(&[("ETag", "W/\"v1\"")]);
+        assert!(is_not_modified(
+            &entry,
+            &request(&[("If-None-Match", "\"v1\"")])
+        ));
+        assert!(is_not_modified(
+            &entry,
+            &request(&[("If-None-Match", "\"x\", *")])
+        ));
+        assert!(!is_not_modified(
+            &entry,
+            &request(&[("If-None-Match", "\"v2\"")])
+        ));
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        let entry = entry(&[("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        let later = request(&[("If-Modified-Since", "Sun, 06 Nov 1994 09:00:00 GMT")]);
+        let earlier = request(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:00:00 GMT")]);
+        assert!(is_not_modified(&entry, &later));
+        assert!(!is_not_modified(&entry, &earlier));
+    }
+
+    #[test]
+    fn fresh_entry_is_served_with_age() {
+        let mut entry = entry(&[("Content-Type", "text/plain")]);
+        entry.initial_age = 5;
+        entry.body = b"hello".to_vec();
+        let response = serve(&entry, &request(&[]), "HIT");
+        assert_eq!(response.code, "200");
+        assert_eq!(response_header(&response, "Age"), Some("5"));
+        assert_eq!(response_header(&response, "X-Cache"), Some("HIT"));
+        assert_eq!(response_header(&response, "Content-Length"), Some("5"));
+    }
+}
@@ -0,0 +1,468 @@
+//! 缓存存储：内存中的 LRU 层和可选的磁盘层
+//!
+//! 同一 URL 按 Vary 区分的多个变体保存在同一个条目中。
+//! 磁盘上每个 URL 一个文件，每个变体先写一行 JSON 元数据，紧接着是响应体。
+//! 后台定期删除所有变体都不能再使用的文件，总大小超过 disk_max_bytes 时从最早写入的文件开始删除。
+
+use crate::config::CacheConfig;
+use crate::http::HttpRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::{info, warn, Instrument};
+
+/// 定期清理磁盘缓存的间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CachedResponse {
+    pub key: String,
+    pub code: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// Vary 列出的请求头，以及存储时请求中的取值
+    pub vary: Vec<(String, Option<String>)>,
+    /// 存储时间（Unix 秒）
+    pub stored_at: u64,
+    /// 存储时响应已有的年龄（Age 头）
+    pub initial_age: u64,
+    /// 新鲜期（秒）
+    pub fresh_for: u64,
+    pub stale_while_revalidate: u64,
+    pub stale_if_error: u64,
+    /// 过期后必须先向源站验证，不能直接使用旧响应
+    pub must_revalidate: bool,
+    pub body_len: usize,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    /// 响应当前的年龄（秒）
+    pub fn age(&self) -> u64 {
+        unix_now().saturating_sub(self.stored_at) + self.initial_age
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    }
+
+    /// 再也不能使用：新鲜期和过期后仍可使用的时间都已过去，也没有能向源站验证的 ETag 或
+    /// Last-Modified。可以验证的变体一直保留，直到磁盘超出预算
+    fn is_expired(&self) -> bool {
+        self.age() >= self.fresh_for + self.stale_while_revalidate.max(self.stale_if_error)
+            && self.header("ETag").is_none()
+            && self.header("Last-Modified").is_none()
+    }
+
+    /// 请求中 Vary 列出的头与存储时相同才能使用该变体
+    fn matches(&self, req: &HttpRequest) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.header(name) == value.as_deref())
+    }
+}
+
+/// 按最近使用顺序淘汰的内存缓存，recency 中序号最小的是最久未使用的
+struct MemoryStore {
+    entries: HashMap<String, (u64, Vec<Arc<CachedResponse>>)>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+static MEMORY: LazyLock<Mutex<MemoryStore>> = LazyLock::new(|| {
+    Mutex::new(MemoryStore {
+        entries: HashMap::new(),
+        recency: BTreeMap::new(),
+        tick: 0,
+    })
+});
+
+// 磁盘临时文件的序号，避免并发写同一个 URL 时互相覆盖
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+// 磁盘缓存的大致大小：上次清理时的总大小加上之后写入的字节数
+static DISK_USAGE: AtomicU64 = AtomicU64::new(0);
+
+// 正在清理磁盘缓存，避免同时运行多次
+static SWEEPING: AtomicBool = AtomicBool::new(false);
+
+impl MemoryStore {
+    fn get(&mut self, key: &str) -> Option<Vec<Arc<CachedResponse>>> {
+        self.tick += 1;
+        let (tick, variants) = self.entries.get_mut(key)?;
+        self.recency.remove(tick);
+        *tick = self.tick;
+        self.recency.insert(self.tick, key.to_string());
+        Some(variants.clone())
+    }
+
+    fn put(&mut self, key: &str, variants: Vec<Arc<CachedResponse>>, max_entries: usize) {
+        self.remove(key);
+        self.tick += 1;
+        self.entries.insert(key.to_string(), (self.tick, variants));
+        self.recency.insert(self.tick, key.to_string());
+        while self.entries.len() > max_entries.max(1) {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some((tick, _)) => {
+                self.recency.remove(&tick);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 查找与请求匹配的变体，内存中没有时从磁盘加载
+pub async fn lookup(
+    key: &str,
+    req: &HttpRequest,
+    config: &CacheConfig,
+) -> Option<Arc<CachedResponse>> {
+    let cached = MEMORY.lock().unwrap().get(key);
+    let variants = match cached {
+        Some(variants) => variants,
+        None => {
+            let loaded: Vec<Arc<CachedResponse>> = read_disk(config.disk_dir.as_deref()?, key)
+                .await?
+                .into_iter()
+                .map(Arc::new)
+                .collect();
+            MEMORY
+                .lock()
+                .unwrap()
+                .put(key, loaded.clone(), config.max_entries);
+            loaded
+        }
+    };
+    variants.into_iter().find(|entry| entry.matches(req))
+}
+
+/// 保存一个变体，替换 Vary 取值相同的旧变体。内存中已淘汰的 URL 先从磁盘读出其他变体，
+/// 重写文件时不会丢掉它们
+pub async fn insert(entry: CachedResponse, config: &CacheConfig) {
+    let key = entry.key.clone();
+    let entry = Arc::new(entry);
+    let in_memory = MEMORY.lock().unwrap().contains(&key);
+    let on_disk = match &config.disk_dir {
+        Some(dir) if !in_memory => read_disk(dir, &key).await.unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    let variants = {
+        let mut memory = MEMORY.lock().unwrap();
+        let mut variants = memory
+            .get(&key)
+            .unwrap_or_else(|| on_disk.into_iter().map(Arc::new).collect());
+        variants.retain(|variant| variant.vary != entry.vary && !variant.is_expired());
+        variants.push(entry);
+        memory.put(&key, variants.clone(), config.max_entries);
+        variants
+    };
+    let Some(dir) = &config.disk_dir else {
+        return;
+    };
+    match write_disk(Path::new(dir), &key, &variants).await {
+        Ok(written) => {
+            let usage = DISK_USAGE.fetch_add(written, Ordering::Relaxed) + written;
+            if usage > config.disk_max_bytes {
+                tokio::spawn(sweep(dir.clone(), config.disk_max_bytes).in_current_span());
+            }
+        }
+        Err(e) => warn!("Failed to write cache file for {}: {}", key, e),
+    }
+}
+
+/// 设置了磁盘缓存时启动定期清理
+pub fn start(config: &CacheConfig) {
+    let Some(dir) = config.disk_dir.clone() else {
+        return;
+    };
+    let budget = config.disk_max_bytes;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweep(dir.clone(), budget).await;
+        }
+    });
+}
+
+/// 删除所有变体都不能再使用或无法解析的文件，然后按写入时间从旧到新删除，直到总大小不超过预算
+async fn sweep(dir: String, budget: u64) {
+    if SWEEPING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    let mut files = Vec::new();
+    let mut expired = 0;
+    if let Ok(mut entries) = fs::read_dir(&dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            // 跳过正在写入的临时文件
+            if path.extension().is_some() {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let live = match fs::read(&path).await {
+                Ok(data) => parse_disk(&data, None)
+                    .is_some_and(|variants| variants.iter().any(|variant| !variant.is_expired())),
+                Err(_) => continue,
+            };
+            if !live {
+                if fs::remove_file(&path).await.is_ok() {
+                    expired += 1;
+                }
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+            files.push((modified, metadata.len(), path));
+        }
+    }
+
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort_by_key(|(modified, _, _)| *modified);
+    let mut evicted = 0;
+    for (_, len, path) in files {
+        if total <= budget {
+            break;
+        }
+        if fs::remove_file(&path).await.is_ok() {
+            total -= len;
+            evicted += 1;
+        }
+    }
+    if expired > 0 || evicted > 0 {
+        info!(
+            "Cache sweep removed {} expired and {} files over budget, {} bytes left",
+            expired, evicted, total
+        );
+    }
+    DISK_USAGE.store(total, Ordering::Relaxed);
+    SWEEPING.store(false, Ordering::Release);
+}
+
+/// 删除一个 URL 的所有变体，返回是否存在过
+pub async fn remove(key: &str, config: &CacheConfig) -> bool {
+    let in_memory = MEMORY.lock().unwrap().remove(key);
+    let on_disk = match &config.disk_dir {
+        Some(dir) => fs::remove_file(disk_path(Path::new(dir), key))
+            .await
+            .is_ok(),
+        None => false,
+    };
+    in_memory || on_disk
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn disk_path(dir: &Path, key: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    dir.join(format!("{:016x}", hasher.finish()))
+}
+
+async fn read_disk(dir: &str, key: &str) -> Option<Vec<CachedResponse>> {
+    let data = fs::read(disk_path(Path::new(dir), key)).await.ok()?;
+    parse_disk(&data, Some(key))
+}
+
+/// 解析缓存文件，`key` 不为空时检查每个变体都属于该键
+fn parse_disk(data: &[u8], key: Option<&str>) -> Option<Vec<CachedResponse>> {
+    let mut variants = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let newline = rest.iter().position(|&b| b == b'\n')?;
+        let mut entry: CachedResponse = serde_json::from_slice(&rest[..newline]).ok()?;
+        rest = &rest[newline + 1..];
+        // 文件名是键的哈希，键不同说明发生了冲突
+        if key.is_some_and(|key| entry.key != key) || rest.len() < entry.body_len {
+            return None;
+        }
+        entry.body = rest[..entry.body_len].to_vec();
+        rest = &rest[entry.body_len..];
+        variants.push(entry);
+    }
+    Some(variants)
+}
+
+/// 先写临时文件再改名，读取时不会看到写了一半的文件，返回写入的字节数
+async fn write_disk(dir: &Path, key: &str, variants: &[Arc<CachedResponse>]) -> io::Result<u64> {
+    let mut data = Vec::new();
+    for entry in variants {
+        serde_json::to_writer(&mut data, entry.as_ref())?;
+        data.push(b'\n');
+        data.extend_from_slice(&entry.body);
+    }
+    fs::create_dir_all(dir).await?;
+    let path = disk_path(dir, key);
+    let temp = path.with_extension(format!(
+        "tmp{}",
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = data.len() as u64;
+    fs::write(&temp, data).await?;
+    fs::rename(&temp, &path).await?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(vary: &[(&str, Option<&str>)]) -> CachedResponse {
+        CachedResponse {
+            key: "example.com/".to_string(),
+            code: 200,
+            reason: "OK".to_string(),
+            headers: Vec::new(),
+            vary: vary
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+                .collect(),
+            stored_at: unix_now(),
+            initial_age: 0,
+            fresh_for: 60,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            must_revalidate: false,
+            body_len: 0,
+            body: Vec::new(),
+        }
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        for (name, value) in pairs {
+            req.headers.insert(name.to_string(), value.to_string());
+        }
+        req
+    }
+
+    #[test]
+    fn matches_on_vary_headers() {
+        let gzip = variant(&[("Accept-Encoding", Some("gzip"))]);
+        assert!(gzip.matches(&request(&[("accept-encoding", "gzip")])));
+        assert!(!gzip.matches(&request(&[("Accept-Encoding", "br")])));
+        assert!(!gzip.matches(&request(&[])));
+    }
+
+    #[test]
+    fn absent_vary_header_must_stay_absent() {
+        let plain = variant(&[("Accept-Language", None)]);
+        assert!(plain.matches(&request(&[])));
+        assert!(!plain.matches(&request(&[("Accept-Language", "fr")])));
+    }
+
+    fn config(dir: &Path, disk_max_bytes: u64) -> CacheConfig {
+        CacheConfig {
+            disk_dir: Some(dir.to_str().unwrap().to_string()),
+            disk_max_bytes,
+            ..CacheConfig::default()
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn insert_keeps_variants_that_are_only_on_disk() {
+        let dir = temp_dir("variants");
+        let config = config(&dir, u64::MAX);
+        let mut gzip = variant(&[("Accept-Encoding", Some("gzip"))]);
+        gzip.key = "variants.test/".to_string();
+        let mut br = variant(&[("Accept-Encoding", Some("br"))]);
+        br.key = gzip.key.clone();
+
+        insert(gzip, &config).await;
+        // 内存中的条目被淘汰后再保存另一个变体
+        MEMORY.lock().unwrap().remove("variants.test/");
+        insert(br, &config).await;
+
+        let on_disk = read_disk(config.disk_dir.as_deref().unwrap(), "variants.test/")
+            .await
+            .unwrap();
+        assert_eq!(on_disk.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweep_removes_expired_files_and_enforces_the_budget() {
+        let dir = temp_dir("sweep");
+        let config = config(&dir, u64::MAX);
+        let entry = |key: &str, stored_at: u64, headers: &[(&str, &str)]| {
+            let mut entry = variant(&[]);
+            entry.key = key.to_string();
+            entry.stored_at = stored_at;
+            entry.headers = headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            entry.body = vec![b'x'; 1000];
+            entry.body_len = 1000;
+            Arc::new(entry)
+        };
+        let old = unix_now() - 3600;
+        let write = |entry: Arc<CachedResponse>| {
+            let dir = dir.clone();
+            async move {
+                write_disk(&dir, &entry.key.clone(), &[entry])
+                    .await
+                    .unwrap();
+            }
+        };
+        write(entry("expired.test/", old, &[])).await;
+        write(entry("validator.test/", old, &[("ETag", "\"v1\"")])).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        write(entry("fresh.test/", unix_now(), &[])).await;
+
+        let dir_name = config.disk_dir.clone().unwrap();
+        sweep(dir_name.clone(), u64::MAX).await;
+        assert!(read_disk(&dir_name, "expired.test/").await.is_none());
+        assert!(read_disk(&dir_name, "validator.test/").await.is_some());
+        assert!(read_disk(&dir_name, "fresh.test/").await.is_some());
+
+        // 超出预算时先删除最早写入的文件
+        sweep(dir_name.clone(), 1500).await;
+        assert!(read_disk(&dir_name, "validator.test/").await.is_none());
+        assert!(read_disk(&dir_name, "fresh.test/").await.is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn age_includes_the_initial_age() {
+        let mut entry = variant(&[]);
+        entry.stored_at = unix_now() - 10;
+        entry.initial_age = 5;
+        assert!((15..=16).contains(&entry.age()));
+    }
+}
@@ -0,0 +1,791 @@
+use crate::{
+    config::{self, CgiConfig, Config},
+    http::{BodyStream, HttpRequest, HttpResponse},
+};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::{error, info, warn, Instrument};
+
+/// CGI 响应头部的最大长度
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+// 同时运行的脚本名额，第一次运行脚本时按 cgi.max_concurrent 创建
+static SCRIPT_SLOTS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+const SERVER_SOFTWARE: &str = concat!("multithreading_http_server/", env!("CARGO_PKG_VERSION"));
+
+/// 文件作为脚本执行的方式
+pub struct ScriptHandler {
+    /// 解释器命令及参数，为空表示直接执行脚本文件
+    pub interpreter: Vec<String>,
+}
+
+/// 判断文件是否应作为脚本执行；不是脚本时返回 None，按静态文件处理。
+/// `root` 为文件所在挂载点的目录，匹配规则按相对它的路径计算
+pub fn find_handler(script_path: &Path, root: &Path, config: &Config) -> Option<ScriptHandler> {
+    let relative = script_path.strip_prefix(root).ok()?;
+    let cgi = &config.cgi;
+
+    if exec_forbidden(relative, config) {
+        return None;
+    }
+
+    if let Some(rule) = cgi
+        .interpreters
+        .iter()
+        .find(|rule| pattern_matches(&rule.pattern, relative))
+    {
+        return Some(ScriptHandler {
+            interpreter: rule.command.split_whitespace().map(String::from).collect(),
+        });
+    }
+
+    let is_cgi_file = relative.extension().is_some_and(|ext| ext == "cgi");
+    if is_cgi_file || cgi.cgi_dirs.iter().any(|dir| relative.starts_with(dir)) {
+        return Some(ScriptHandler {
+            interpreter: Vec::new(),
+        });
+    }
+    None
+}
+
+/// 禁止执行的目录优先于所有匹配规则（包括外部后端），保证上传的文件永远不会被运行
+pub fn exec_forbidden(relative: &Path, config: &Config) -> bool {
+    config
+        .cgi
+        .no_exec_dirs
+        .iter()
+        .any(|dir| relative.starts_with(dir))
+}
+
+pub fn pattern_matches(pattern: &str, relative: &Path) -> bool {
+    if let Some(ext) = pattern.strip_prefix('.') {
+        return relative.extension().is_some_and(|e| e == ext);
+    }
+    let Ok(glob) = glob::Pattern::new(pattern) else {
+        warn!("Invalid interpreter pattern: {}", pattern);
+        return false;
+    };
+    if pattern.contains('/') {
+        glob.matches_path(relative)
+    } else {
+        relative
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| glob.matches(name))
+    }
+}
+
+/// 按 RFC 3875 第 4.1 节构建 CGI 元变量
+pub async fn build_env(
+    req: &HttpRequest,
+    script_path: &Path,
+    path_info: &str,
+) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    let mut push = |key: &str, val: String| env.push((key.to_string(), val));
+
+    // SCRIPT_NAME 是去掉 PATH_INFO 后剩下的 URL 路径
+    let script_name = req
+        .path
+        .strip_suffix(path_info)
+        .unwrap_or(&req.path)
+        .to_string();
+
+    let (server_name, server_port) = server_name_and_port(req).await;
+
+    push("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    push("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string());
+    push("SERVER_PROTOCOL", req.version.clone());
+    push("SERVER_NAME", server_name);
+    push("SERVER_PORT", server_port);
+    // 与 Apache、nginx 相同，脚本据此判断客户端是否使用 HTTPS
+    if req.tls {
+        push("HTTPS", "on".to_string());
+    }
+    push("REQUEST_METHOD", req.method.clone());
+    push("QUERY_STRING", req.query.clone());
+    push("SCRIPT_NAME", script_name);
+    push("SCRIPT_FILENAME", script_path.display().to_string());
+    push("PATH_INFO", path_info.to_string());
+    if !path_info.is_empty() {
+        if let Some(dir) = script_path.parent() {
+            let translated = dir.join(path_info.trim_start_matches('/'));
+            push("PATH_TRANSLATED", translated.display().to_string());
+        }
+    }
+    if let Some(addr) = req.remote_addr {
+        push("REMOTE_ADDR", addr.ip().to_string());
+        push("REMOTE_PORT", addr.port().to_string());
+    }
+    if let Some(user) = &req.remote_user {
+        // 认证方式取自 Authorization 头，例如 "Basic" 或 "Bearer"，认证服务给出的用户没有
+        let scheme = req
+            .header("Authorization")
+            .and_then(|val| val.split_whitespace().next());
+        if let Some(scheme) = scheme {
+            push("AUTH_TYPE", scheme.to_string());
+        }
+        push("REMOTE_USER", user.clone());
+    }
+    if !req.body.is_empty() || req.header("Content-Length").is_some() {
+        push("CONTENT_LENGTH", req.body.len().to_string());
+    }
+    if let Some(content_type) = req.header("Content-Type") {
+        push("CONTENT_TYPE", content_type.to_string());
+    }
+
+    // 其余请求头以 HTTP_ 前缀传递，Content-Type/Content-Length 已在上面给出，
+    // 通过认证后不再把带密码或令牌的 Authorization 交给脚本。
+    // Proxy 头不传递：HTTP_PROXY 会被脚本中的 HTTP 客户端当作代理设置（httpoxy）
+    for (key, val) in &req.headers {
+        if key.eq_ignore_ascii_case("Content-Type")
+            || key.eq_ignore_ascii_case("Content-Length")
+            || key.eq_ignore_ascii_case("Proxy")
+        {
+            continue;
+        }
+        if req.remote_user.is_some() && key.eq_ignore_ascii_case("Authorization") {
+            continue;
+        }
+        let name = format!("HTTP_{}", key.to_ascii_uppercase().replace('-', "_"));
+        push(&name, val.clone());
+    }
+
+    env
+}
+
+/// SERVER_NAME 优先取 Host 头，端口优先取实际监听的端口
+async fn server_name_and_port(req: &HttpRequest) -> (String, String) {
+    let config = config::read_config().await.ok();
+    let port = req
+        .local_addr
+        .map(|addr| addr.port())
+        .or(config.as_ref().map(|c| c.port))
+        .map(|p| p.to_string())
+        .unwrap_or_default();
+
+    let name = match req.header("Host") {
+        // 去掉 Host 中的端口，注意 IPv6 字面量形如 [::1]:8080
+        Some(host) => match host.rsplit_once(':') {
+            Some((name, port)) if !name.ends_with(':') && port.parse::<u16>().is_ok() => {
+                name.to_string()
+            }
+            _ => host.to_string(),
+        },
+        None => config.map(|c| c.host.clone()).unwrap_or_default(),
+    };
+    (name, port)
+}
+
+/// CGI 脚本输出解析后的结果（RFC 3875 第 6.2 节）
+pub enum CgiResponse {
+    /// 文档响应或客户端重定向，可直接返回给客户端
+    Response(HttpResponse),
+    /// 本地重定向：服务器需要以该 URL 重新处理请求
+    LocalRedirect(String),
+}
+
+#[derive(Debug)]
+pub enum CgiOutputError {
+    MissingHeaderEnd,
+    InvalidHeaderLine,
+    InvalidStatus,
+    MissingContentType,
+}
+
+/// 这些头由服务器自己生成，脚本给出的值会被忽略
+const SERVER_MANAGED_HEADERS: [&str; 5] = [
+    "Status",
+    "Content-Type",
+    "Content-Length",
+    "Connection",
+    "Transfer-Encoding",
+];
+
+pub fn parse_cgi_output(output: &[u8]) -> Result<CgiResponse, CgiOutputError> {
+    let (header_block, body) =
+        split_header_block(output).ok_or(CgiOutputError::MissingHeaderEnd)?;
+    let headers = parse_header_lines(header_block)?;
+
+    let find = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    };
+    let content_type = find("Content-Type");
+    let location = find("Location");
+    let status = find("Status").map(parse_status).transpose()?;
+
+    // 本地重定向只包含一个以 / 开头的 Location
+    if let Some(location) = location {
+        if location.starts_with('/')
+            && content_type.is_none()
+            && status.is_none()
+            && body.is_empty()
+        {
+            return Ok(CgiResponse::LocalRedirect(location.to_string()));
+        }
+    }
+
+    let (code, reason) = match (status, location, content_type) {
+        (Some(status), _, _) => status,
+        // 客户端重定向默认 302
+        (None, Some(_), _) => (302, String::new()),
+        (None, None, Some(_)) => (200, String::new()),
+        (None, None, None) => return Err(CgiOutputError::MissingContentType),
+    };
+
+    let mut response = HttpResponse::from_status(code, &reason);
+    if content_type.is_some() || !body.is_empty() {
+        response = response.body(content_type.unwrap_or("application/octet-stream"), body);
+    }
+    for (key, val) in &headers {
+        if SERVER_MANAGED_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(key))
+        {
+            continue;
+        }
+        response = response.header(key, val);
+    }
+    Ok(CgiResponse::Response(response))
+}
+
+/// 按第一个空行切分头部和正文，脚本可能使用 \n 或 \r\n 换行
+fn split_header_block(output: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut start = 0;
+    while let Some(pos) = output[start..].iter().position(|&b| b == b'\n') {
+        let line_end = start + pos;
+        let line = &output[start..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Some((&output[..start], &output[line_end + 1..]));
+        }
+        start = line_end + 1;
+    }
+    None
+}
+
+fn parse_header_lines(block: &[u8]) -> Result<Vec<(String, String)>, CgiOutputError> {
+    let text = std::str::from_utf8(block).map_err(|_| CgiOutputError::InvalidHeaderLine)?;
+    text.lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            // uwsgi 等后端以 HTTP 状态行开头，等同于 Status 头
+            if idx == 0 && line.starts_with("HTTP/") {
+                let status = line.split_once(' ').map(|(_, s)| s).unwrap_or("");
+                return Ok(("Status".to_string(), status.trim().to_string()));
+            }
+            line.split_once(':')
+                .filter(|(key, _)| !key.is_empty() && !key.contains(' '))
+                .map(|(key, val)| (key.to_string(), val.trim().to_string()))
+                .ok_or(CgiOutputError::InvalidHeaderLine)
+        })
+        .collect()
+}
+
+/// 解析形如 "404 Not Found" 的 Status 头
+fn parse_status(value: &str) -> Result<(u16, String), CgiOutputError> {
+    let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+    match code.parse::<u16>() {
+        Ok(code) if (100..=999).contains(&code) => Ok((code, reason.trim().to_string())),
+        _ => Err(CgiOutputError::InvalidStatus),
+    }
+}
+
+pub async fn run_cgi(
+    script_path: &Path,
+    root: &Path,
+    req: &HttpRequest,
+    path_info: &str,
+) -> CgiResponse {
+    info!("Executing CGI script: {}", script_path.display());
+    let config = match config::read_config().await {
+        Ok(config) => config,
+        Err(_) => return CgiResponse::Response(HttpResponse::internal_server_error()),
+    };
+    let handler = match find_handler(script_path, root, &config) {
+        Some(handler) => handler,
+        None => return CgiResponse::Response(HttpResponse::internal_server_error()),
+    };
+    let limits = config.cgi.clone();
+    let env = build_env(req, script_path, path_info).await;
+
+    let mut command = match build_command(script_path, &handler, env, &limits) {
+        Some(command) => command,
+        None => return CgiResponse::Response(HttpResponse::internal_server_error()),
+    };
+    // 名额一直占用到脚本进程退出
+    let slots = SCRIPT_SLOTS.get_or_init(|| Arc::new(Semaphore::new(limits.max_concurrent.max(1))));
+    let wait = Duration::from_secs(limits.timeout_secs);
+    let permit = match tokio::time::timeout(wait, slots.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => permit,
+        _ => {
+            warn!(
+                "{} CGI scripts already running, rejecting {}",
+                limits.max_concurrent,
+                script_path.display()
+            );
+            return CgiResponse::Response(HttpResponse::from_status(503, ""));
+        }
+    };
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to execute CGI script: {}", e);
+            return CgiResponse::Response(HttpResponse::internal_server_error());
+        }
+    };
+    // 脚本是新进程组的组长，进程组 ID 即其 PID
+    let pgid = child.id().map(|pid| pid as libc::pid_t);
+
+    // 在单独的任务中写入请求体，避免脚本输出过多时双方互相阻塞
+    if let Some(mut stdin) = child.stdin.take() {
+        let body = req.body.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stdin.write_all(&body).await {
+                // 脚本不读取 stdin 就退出时会出现 BrokenPipe，属于正常情况
+                if e.kind() != tokio::io::ErrorKind::BrokenPipe {
+                    error!("Failed to write CGI stdin: {}", e);
+                }
+            }
+        });
+    }
+
+    // stderr 逐行转发到日志，当前 span 携带请求 ID
+    if let Some(stderr) = child.stderr.take() {
+        let request_id = req.id;
+        tokio::spawn(
+            async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!(request_id, "CGI stderr: {}", line);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    let stdout = match child.stdout.take() {
+        Some(stdout) => stdout,
+        None => return CgiResponse::Response(HttpResponse::internal_server_error()),
+    };
+    let deadline = Instant::now() + Duration::from_secs(limits.timeout_secs);
+
+    // nph 脚本自己输出完整的 HTTP 响应，服务器原样转发
+    if is_nph_script(script_path) {
+        supervise(child, pgid, deadline, script_path.to_path_buf(), permit);
+        return CgiResponse::Response(
+            HttpResponse::new().stream(BodyStream::Raw(Box::new(stdout))),
+        );
+    }
+
+    match read_cgi_response(stdout, deadline).await {
+        Ok(parsed) => {
+            supervise(child, pgid, deadline, script_path.to_path_buf(), permit);
+            parsed
+        }
+        Err(e) => {
+            kill_process_group(pgid);
+            match e {
+                CgiReadError::Timeout => {
+                    error!(
+                        "CGI script {} timed out after {}s, killing process group",
+                        script_path.display(),
+                        limits.timeout_secs
+                    );
+                    CgiResponse::Response(HttpResponse::gateway_timeout())
+                }
+                CgiReadError::Io(e) => {
+                    error!("Failed to read CGI output: {}", e);
+                    CgiResponse::Response(HttpResponse::internal_server_error())
+                }
+                CgiReadError::Malformed(e) => {
+                    error!(
+                        "Malformed output from CGI script {}: {:?}",
+                        script_path.display(),
+                        e
+                    );
+                    CgiResponse::Response(HttpResponse::bad_gateway())
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CgiReadError {
+    Timeout,
+    Io(tokio::io::Error),
+    Malformed(CgiOutputError),
+}
+
+/// 从脚本或后端的输出中读取 CGI 响应：先解析头部，之后的正文以 chunked 编码继续转发
+pub async fn read_cgi_response<R>(
+    mut output: R,
+    deadline: Instant,
+) -> Result<CgiResponse, CgiReadError>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let (head, eof) = tokio::time::timeout_at(deadline, read_header_block(&mut output))
+        .await
+        .map_err(|_| CgiReadError::Timeout)?
+        .map_err(CgiReadError::Io)?;
+    let parsed = parse_cgi_output(&head).map_err(CgiReadError::Malformed)?;
+
+    match parsed {
+        // 头部之后已读到的数据和剩余的输出一起转发
+        CgiResponse::Response(mut response) if !eof => {
+            let prefix = std::io::Cursor::new(std::mem::take(&mut response.body));
+            Ok(CgiResponse::Response(response.stream(BodyStream::Chunked(
+                Box::new(prefix.chain(output)),
+            ))))
+        }
+        // 输出已经读完，或者是本地重定向（不需要正文），直接返回
+        parsed => Ok(parsed),
+    }
+}
+
+/// 读取到头部结束（第一个空行）或输出关闭为止，返回已读数据以及是否已到结尾
+async fn read_header_block<R>(output: &mut R) -> tokio::io::Result<(Vec<u8>, bool)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = output.read(&mut buf).await?;
+        if n == 0 {
+            return Ok((head, true));
+        }
+        head.extend_from_slice(&buf[..n]);
+        if split_header_block(&head).is_some() {
+            return Ok((head, false));
+        }
+        if head.len() > MAX_HEADER_SIZE {
+            // 交给 parse_cgi_output 报告格式错误
+            return Ok((head, true));
+        }
+    }
+}
+
+fn is_nph_script(script_path: &Path) -> bool {
+    script_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("nph-"))
+}
+
+/// 在后台等待脚本退出，超时则杀掉整个进程组，之后释放运行名额
+fn supervise(
+    mut child: Child,
+    pgid: Option<libc::pid_t>,
+    deadline: Instant,
+    script: PathBuf,
+    permit: OwnedSemaphorePermit,
+) {
+    tokio::spawn(
+        async move {
+            match tokio::time::timeout_at(deadline, child.wait()).await {
+                Ok(Ok(status)) => info!("CGI script exited with {}", status),
+                Ok(Err(e)) => error!("Failed to wait for CGI script: {}", e),
+                Err(_) => error!(
+                    "CGI script {} timed out, killing process group",
+                    script.display()
+                ),
+            }
+            // 清理脚本可能遗留在后台的子进程
+            kill_process_group(pgid);
+            drop(permit);
+        }
+        .in_current_span(),
+    );
+}
+
+/// 构建脚本进程：清空环境变量、设置工作目录、独立进程组和资源限制
+fn build_command(
+    script_path: &Path,
+    handler: &ScriptHandler,
+    env: Vec<(String, String)>,
+    limits: &CgiConfig,
+) -> Option<Command> {
+    let working_dir = match &limits.working_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => script_path.parent()?.to_path_buf(),
+    };
+
+    let mut command = match handler.interpreter.split_first() {
+        Some((program, args)) => {
+            let mut command = Command::new(program);
+            command.args(args).arg(script_path);
+            command
+        }
+        None => Command::new(script_path),
+    };
+    command
+        .env_clear()
+        .envs(
+            limits
+                .env_allowlist
+                .iter()
+                .filter_map(|key| std::env::var(key).ok().map(|val| (key.clone(), val))),
+        )
+        .envs(env)
+        .current_dir(working_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .process_group(0);
+
+    let rlimits = [
+        (libc::RLIMIT_CPU, limits.max_cpu_secs),
+        (
+            libc::RLIMIT_AS,
+            limits.max_memory_mb.saturating_mul(1024 * 1024),
+        ),
+        (libc::RLIMIT_NOFILE, limits.max_open_files),
+        (libc::RLIMIT_NPROC, limits.max_processes),
+    ];
+    // SAFETY: pre_exec 闭包在 fork 之后、exec 之前运行，只调用了 async-signal-safe 的 setrlimit
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, value) in rlimits {
+                let limit = libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value as libc::rlim_t,
+                };
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Some(command)
+}
+
+fn kill_process_group(pgid: Option<libc::pid_t>) {
+    if let Some(pgid) = pgid {
+        // SAFETY: 向进程组发送信号，进程组不存在时只会返回 ESRCH
+        unsafe {
+            libc::kill(-pgid, libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.method = "GET".to_string();
+        req.path = "/test.cgi".to_string();
+        req.version = "HTTP/1.1".to_string();
+        for (name, value) in headers {
+            req.headers.insert(name.to_string(), value.to_string());
+        }
+        req
+    }
+
+    fn var<'a>(env: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        env.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.as_str())
+    }
+
+    #[tokio::test]
+    async fn proxy_header_is_not_passed() {
+        let req = request(&[("Proxy", "http://evil:8080"), ("X-Custom", "1")]);
+        let env = build_env(&req, Path::new("/srv/test.cgi"), "").await;
+        assert_eq!(var(&env, "HTTP_PROXY"), None);
+        assert_eq!(var(&env, "HTTP_X_CUSTOM"), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn authorization_is_hidden_after_authentication() {
+        let mut req = request(&[("Authorization", "Basic YWxpY2U6c2VjcmV0")]);
+        let env = build_env(&req, Path::new("/srv/test.cgi"), "").await;
+        assert!(var(&env, "HTTP_AUTHORIZATION").is_some());
+
+        req.remote_user = Some("alice".to_string());
+        let env = build_env(&req, Path::new("/srv/test.cgi"), "").await;
+        assert_eq!(var(&env, "HTTP_AUTHORIZATION"), None);
+        assert_eq!(var(&env, "REMOTE_USER"), Some("alice"));
+        assert_eq!(var(&env, "AUTH_TYPE"), Some("Basic"));
+    }
+
+    fn parsed(output: &[u8]) -> HttpResponse {
+        match parse_cgi_output(output) {
+            Ok(CgiResponse::Response(response)) => response,
+            Ok(CgiResponse::LocalRedirect(location)) => panic!("local redirect to {}", location),
+            Err(e) => panic!("parse error {:?}", e),
+        }
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response.headers.get(name).map(String::as_str)
+    }
+
+    #[test]
+    fn document_response_defaults_to_200() {
+        let response = parsed(b"Content-Type: text/plain\r\nX-Script: 1\r\n\r\nhello");
+        assert_eq!(response.code, "200");
+        assert_eq!(response.reason, "OK");
+        assert_eq!(header(&response, "Content-Type"), Some("text/plain"));
+        assert_eq!(header(&response, "Content-Length"), Some("5"));
+        assert_eq!(header(&response, "X-Script"), Some("1"));
+        assert_eq!(response.body, b"hello");
+    }
+
+    #[test]
+    fn status_header_sets_code_and_reason() {
+        let response = parsed(b"Status: 404 Gone Fishing\r\nContent-Type: text/plain\r\n\r\n");
+        assert_eq!(response.code, "404");
+        assert_eq!(response.reason, "Gone Fishing");
+        assert!(header(&response, "Status").is_none());
+
+        let response = parsed(b"Status: 503\r\nContent-Type: text/plain\r\n\r\n");
+        assert_eq!(response.reason, "Service Unavailable");
+
+        for status in ["abc", "42", "1000 Too Big"] {
+            let output = format!("Status: {}\r\nContent-Type: text/plain\r\n\r\n", status);
+            assert!(matches!(
+                parse_cgi_output(output.as_bytes()),
+                Err(CgiOutputError::InvalidStatus)
+            ));
+        }
+    }
+
+    #[test]
+    fn location_redirects() {
+        // 只有以 / 开头的 Location 是本地重定向
+        match parse_cgi_output(b"Location: /other?x=1\r\n\r\n") {
+            Ok(CgiResponse::LocalRedirect(location)) => assert_eq!(location, "/other?x=1"),
+            _ => panic!("expected a local redirect"),
+        }
+
+        let response = parsed(b"Location: http://example.com/\r\n\r\n");
+        assert_eq!(response.code, "302");
+        assert_eq!(header(&response, "Location"), Some("http://example.com/"));
+
+        // 带 Status 或正文时按客户端重定向处理
+        let response = parsed(b"Status: 301\r\nLocation: /moved\r\n\r\n");
+        assert_eq!(response.code, "301");
+        assert_eq!(header(&response, "Location"), Some("/moved"));
+    }
+
+    #[test]
+    fn missing_headers_are_rejected() {
+        assert!(matches!(
+            parse_cgi_output(b"Content-Type: text/plain\r\nhello"),
+            Err(CgiOutputError::MissingHeaderEnd)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b"X-Script: 1\r\n\r\nhello"),
+            Err(CgiOutputError::MissingContentType)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b"not a header\r\n\r\n"),
+            Err(CgiOutputError::InvalidHeaderLine)
+        ));
+    }
+
+    #[test]
+    fn bare_lf_separates_headers() {
+        let response =
+            parsed(b"Content-Type: text/html\nStatus: 201 Created\n\nline1\r\n\r\nline2");
+        assert_eq!(response.code, "201");
+        assert_eq!(header(&response, "Content-Type"), Some("text/html"));
+        assert_eq!(response.body, b"line1\r\n\r\nline2");
+    }
+
+    #[test]
+    fn server_managed_headers_are_ignored() {
+        let response = parsed(
+            b"Content-Type: text/plain\r\nContent-Length: 99\r\nConnection: keep-alive\r\n\r\nhi",
+        );
+        assert_eq!(header(&response, "Content-Length"), Some("2"));
+        assert_eq!(header(&response, "Connection"), Some("close"));
+    }
+
+    fn config(cgi: serde_json::Value) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "static_dir": "/srv/www",
+            "concurrent_thread": 1,
+            "cgi": cgi
+        }))
+        .unwrap()
+    }
+
+    fn interpreter(path: &str, config: &Config) -> Option<Vec<String>> {
+        let root = Path::new("/srv/www");
+        find_handler(&root.join(path), root, config).map(|handler| handler.interpreter)
+    }
+
+    #[test]
+    fn patterns_match_extensions_names_and_paths() {
+        let relative = Path::new("app/tools/run.py");
+        assert!(pattern_matches(".py", relative));
+        assert!(!pattern_matches(".p", relative));
+        assert!(pattern_matches("run.*", relative));
+        assert!(!pattern_matches("tools/*.py", relative));
+        assert!(pattern_matches("app/*/*.py", relative));
+        assert!(pattern_matches("app/**/*.py", relative));
+    }
+
+    #[test]
+    fn handlers_come_from_interpreters_cgi_files_and_cgi_dirs() {
+        let config = config(serde_json::json!({
+            "interpreters": [
+                { "pattern": ".py", "command": "python3 -u" },
+                { "pattern": "tools/*.sh", "command": "/bin/sh" }
+            ],
+            "cgi_dirs": ["cgi-bin"]
+        }));
+        assert_eq!(
+            interpreter("a/b.py", &config),
+            Some(vec!["python3".to_string(), "-u".to_string()])
+        );
+        assert_eq!(
+            interpreter("tools/x.sh", &config),
+            Some(vec!["/bin/sh".to_string()])
+        );
+        assert_eq!(interpreter("other/x.sh", &config), None);
+        assert_eq!(interpreter("x.cgi", &config), Some(Vec::new()));
+        assert_eq!(interpreter("cgi-bin/anything", &config), Some(Vec::new()));
+        assert_eq!(interpreter("cgi-binary/anything", &config), None);
+        assert_eq!(interpreter("index.html", &config), None);
+        // 不在挂载目录下的文件不作为脚本
+        assert!(find_handler(Path::new("/etc/x.py"), Path::new("/srv/www"), &config).is_none());
+    }
+
+    #[test]
+    fn no_exec_dirs_win_over_every_rule() {
+        let config = config(serde_json::json!({
+            "interpreters": [{ "pattern": ".py", "command": "python3" }],
+            "cgi_dirs": ["uploads"],
+            "no_exec_dirs": ["uploads"]
+        }));
+        assert!(exec_forbidden(Path::new("uploads/x.py"), &config));
+        assert_eq!(interpreter("uploads/x.py", &config), None);
+        assert_eq!(interpreter("uploads/x.cgi", &config), None);
+        assert_eq!(interpreter("uploads/deep/x", &config), None);
+        assert_eq!(
+            interpreter("x.py", &config),
+            Some(vec!["python3".to_string()])
+        );
+    }
+}
@@ -1,12 +1,864 @@
-use serde::Deserialize;
-use tokio::{fs, sync::OnceCell};
+use crate::http::HttpRequest;
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
 // #[derive(Debug, Deserialize, Clone)]
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub host: String,
     pub port: u16,
+    /// 文档根目录，挂载在 "/"。相对路径相对于配置文件所在的目录
     pub static_dir: String,
+    /// 其他挂载到 URL 前缀的目录
+    #[serde(default)]
+    pub mounts: Vec<MountConfig>,
+    /// 请求目录时依次查找的索引文件，找到时代替目录列表
+    #[serde(default = "default_index_files")]
+    pub index_files: Vec<String>,
+    /// 没有索引文件时是否列出目录内容，关闭时返回 403
+    #[serde(default = "default_true")]
+    pub autoindex: bool,
+    /// 按 URL 路径前缀覆盖目录设置，最长前缀优先，对子目录同样有效
+    #[serde(default)]
+    pub directories: Vec<DirectoryConfig>,
+    #[serde(default)]
+    pub cgi: CgiConfig,
+    #[serde(default)]
+    pub backends: Vec<BackendConfig>,
+    #[serde(default)]
+    pub proxy: Vec<ProxyRoute>,
+    /// 代理和 CGI 响应的 HTTP 缓存，未设置时不缓存
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// 正向代理，未设置时不接受绝对形式的请求和 CONNECT 隧道
+    #[serde(default)]
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    /// 以 JSON 返回上游池状态的路径，例如 "/_status/upstreams"，未设置时不开放
+    #[serde(default)]
+    pub upstream_status_path: Option<String>,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    #[serde(default)]
+    pub sse: SseConfig,
+    /// 访问日志文件，每个请求一行（Combined Log Format），未设置时不记录
+    #[serde(default)]
+    pub access_log: Option<String>,
+    /// HTTPS 监听端口，未设置时只监听 HTTP
+    #[serde(default)]
+    pub tls_port: Option<u16>,
+    /// 没有虚拟主机匹配 SNI 时使用的证书
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// 按 Host 头选择的虚拟主机，覆盖文档根目录、挂载点、访问日志和证书
+    #[serde(default)]
+    pub virtual_hosts: Vec<VirtualHostConfig>,
+    /// 按顺序执行的重写和重定向规则，在映射到文件之前生效
+    #[serde(default)]
+    pub rewrites: Vec<RewriteRule>,
+    /// 错误页：状态码（"404"）或状态类别（"5xx"）到文件的映射，未配置时使用内置的页面
+    #[serde(default)]
+    pub error_pages: HashMap<String, String>,
+    /// 需要认证的位置，最长前缀优先
+    #[serde(default)]
+    pub auth: Vec<AuthConfig>,
+    /// 按客户端 IP 的访问控制
+    #[serde(default)]
+    pub ip_access: IpAccessConfig,
+}
+
+/// IP 访问控制。条目是 IPv4/IPv6 地址或 CIDR，例如 "10.0.0.0/8"、"2001:db8::/32"。
+/// deny 优先，allow 不为空时只允许其中的地址
+#[derive(Deserialize, Clone, Default)]
+pub struct IpAccessConfig {
+    /// 接受连接时检查，被拒绝的连接直接关闭
+    #[serde(default, deserialize_with = "ip_nets")]
+    pub allow: Vec<IpNet>,
+    #[serde(default, deserialize_with = "ip_nets")]
+    pub deny: Vec<IpNet>,
+    /// 按 URL 路径前缀的规则，路由时检查，被拒绝时返回 403，最长前缀优先
+    #[serde(default)]
+    pub locations: Vec<IpLocationConfig>,
+    /// 可信的反向代理，来自它们的请求按 X-Forwarded-For 或 X-Real-IP 中的客户端地址检查位置规则
+    #[serde(default, deserialize_with = "ip_nets")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IpLocationConfig {
+    /// URL 路径前缀，例如 "/admin"
+    pub path: String,
+    #[serde(default, deserialize_with = "ip_nets")]
+    pub allow: Vec<IpNet>,
+    #[serde(default, deserialize_with = "ip_nets")]
+    pub deny: Vec<IpNet>,
+}
+
+/// 加载配置时解析 IP 条目，单个地址视为只含它自己的网段，无法解析的条目使配置无效
+fn ip_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| de::Error::custom(format!("invalid IP address or CIDR {:?}", entry)))
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct VirtualHostConfig {
+    /// 主机名，可以使用 "*.example.com" 这样的通配符
+    pub names: Vec<String>,
+    /// 没有主机名匹配时使用该虚拟主机，否则使用顶层的设置
+    #[serde(default)]
+    pub default: bool,
+    pub static_dir: String,
+    #[serde(default)]
+    pub mounts: Vec<MountConfig>,
+    #[serde(default)]
+    pub access_log: Option<String>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// 不为空时代替顶层的重写规则
+    #[serde(default)]
+    pub rewrites: Vec<RewriteRule>,
+    /// 覆盖顶层同一状态码的错误页
+    #[serde(default)]
+    pub error_pages: HashMap<String, String>,
+    /// 不为空时代替顶层的认证设置
+    #[serde(default)]
+    pub auth: Vec<AuthConfig>,
+}
+
+/// 受保护的位置：路径前缀下的请求必须通过 Basic 认证（htpasswd）或带有有效的 JWT，
+/// 两者都设置时任意一种都可以
+#[derive(Deserialize, Clone)]
+pub struct AuthConfig {
+    /// URL 路径前缀，例如 "/private"
+    pub path: String,
+    /// WWW-Authenticate 中的 realm
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Apache 格式的 htpasswd 文件，修改后自动重新读取
+    #[serde(default)]
+    pub htpasswd: Option<String>,
+    /// 要求 "Authorization: Bearer" 中的 JWT
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    /// 处理请求前询问外部的认证服务，与上面的认证方式同时设置时两者都要通过
+    #[serde(default)]
+    pub auth_request: Option<AuthRequestConfig>,
+}
+
+/// 认证子请求：认证服务返回 2xx 时允许请求，401/403 原样返回给客户端
+#[derive(Deserialize, Clone)]
+pub struct AuthRequestConfig {
+    /// 认证服务的 URL，例如 "http://127.0.0.1:9000/auth"
+    #[serde(default)]
+    pub url: Option<String>,
+    /// 代替 url 执行的 CGI 脚本，必须能被识别为脚本（.cgi 或配置了解释器）
+    #[serde(default)]
+    pub cgi: Option<String>,
+    /// 允许时从认证服务的响应复制到请求中的头，例如 "X-Auth-User"
+    #[serde(default)]
+    pub copy_headers: Vec<String>,
+    /// 认证服务响应中的用户名头，用作 REMOTE_USER 和访问日志中的用户
+    #[serde(default)]
+    pub user_header: Option<String>,
+    /// 按 Authorization 和 Cookie 缓存结果的秒数，0 表示不缓存
+    #[serde(default)]
+    pub cache_secs: u64,
+    /// 等待认证服务的最长时间（秒），超时返回 500
+    #[serde(default = "default_auth_request_timeout")]
+    pub timeout_secs: u64,
+}
+
+/// JWT 的验证密钥和规则，密钥文件修改后自动重新读取
+#[derive(Deserialize, Clone)]
+pub struct JwtConfig {
+    /// HS256 的共享密钥文件
+    #[serde(default)]
+    pub secret_file: Option<String>,
+    /// RS256 或 ES256 的 PEM 公钥文件
+    #[serde(default)]
+    pub public_key_file: Option<String>,
+    /// 本地的 JWKS 文件，按令牌头中的 kid 选择密钥
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    /// 设置时 aud 必须包含该值
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// 设置时 iss 必须等于该值
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// 检查 exp 和 nbf 时允许的时钟误差（秒）
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway_secs: u64,
+    /// 全部满足才允许访问的声明规则，例如 "role == admin"、"scope contains read"
+    #[serde(default)]
+    pub require: Vec<String>,
+    /// 声明名到请求头的映射，这些请求头转发给 CGI 脚本和上游，例如 {"sub": "X-Auth-User"}
+    #[serde(default)]
+    pub forward_claims: HashMap<String, String>,
+}
+
+/// 一条重写规则：所有条件都满足时执行动作。
+/// 动作三选一：`rewrite` 在内部改写路径，`redirect` 返回重定向，只有 `status` 时直接返回该状态码
+#[derive(Deserialize, Clone)]
+pub struct RewriteRule {
+    /// 匹配解码后路径的正则，捕获组可以在 rewrite 和 redirect 中用 $1、${name} 引用
+    #[serde(default)]
+    pub path: Option<String>,
+    /// 请求方法，为空时匹配所有方法
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// 匹配主机名（不含端口，小写）的正则
+    #[serde(default)]
+    pub host: Option<String>,
+    /// 请求头名称到正则的映射，请求头必须存在且匹配
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// 匹配原始查询字符串的正则
+    #[serde(default)]
+    pub query: Option<String>,
+    /// 新的路径，可以带 "?" 和新的查询参数，原来的查询参数接在后面
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    /// 重定向的目标，可以是路径或完整的 URL
+    #[serde(default)]
+    pub redirect: Option<String>,
+    /// 重定向时为 301、302（默认）、307 或 308，否则为直接返回的状态码
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// rewrite 之后：last 用新路径重新从第一条规则开始，break 停止执行规则，不设置时继续下一条
+    #[serde(default)]
+    pub flag: Option<RewriteFlag>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteFlag {
+    Last,
+    Break,
+}
+
+/// PEM 格式的证书链和私钥
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+/// 反向代理：把路径前缀挂载到上游 HTTP/1.1 服务
+#[derive(Deserialize, Clone)]
+pub struct ProxyRoute {
+    /// 路径前缀，例如 "/api"
+    pub prefix: String,
+    /// 单个上游地址 "host:port" 或 "unix:/path/to.sock"
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// 多个上游组成的池，与 upstream 同时设置时合并
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamServer>,
+    /// 负载均衡策略
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    /// 一致性哈希使用的请求头，未设置时按客户端 IP 哈希
+    #[serde(default)]
+    pub hash_header: Option<String>,
+    /// 主动健康检查，未设置时只做被动摘除
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    /// 连续失败多少次后摘除上游
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+    /// 被动摘除的冷却时间（秒），之后重新参与负载均衡
+    #[serde(default = "default_fail_timeout")]
+    pub fail_timeout_secs: u64,
+    /// 幂等请求的重试
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// 每个上游的熔断器，未设置时不熔断
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// 把部分请求复制一份发给影子上游，响应直接丢弃
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// 转发时去掉路径前缀，"/api/users" 变为 "/users"
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// 保留客户端的 Host 头，否则改为上游地址
+    #[serde(default)]
+    pub preserve_host: bool,
+    /// 连接上游的最长时间（秒），连接失败返回 502，超时返回 504
+    #[serde(default = "default_proxy_connect_timeout")]
+    pub connect_timeout_secs: u64,
+    /// 等待上游响应头的最长时间（秒）
+    #[serde(default = "default_backend_timeout")]
+    pub timeout_secs: u64,
+    /// 连接池中最多保留的空闲连接数
+    #[serde(default = "default_backend_max_idle")]
+    pub max_idle: usize,
+    /// WebSocket 隧道两个方向都没有数据的最长时间（秒），超过后关闭
+    #[serde(default = "default_websocket_idle_timeout")]
+    pub websocket_idle_timeout_secs: u64,
+}
+
+impl Config {
+    /// 按最长前缀找到 URL 所在的挂载点，没有匹配时为挂载在 "/" 的 static_dir
+    pub fn mount_for(&self, url_path: &str) -> MountConfig {
+        self.mounts
+            .iter()
+            .filter(|mount| prefix_matches(&mount.url, url_path))
+            .max_by_key(|mount| mount.url.len())
+            .cloned()
+            .unwrap_or_else(|| MountConfig {
+                url: "/".to_string(),
+                root: self.static_dir.clone(),
+                autoindex: None,
+                cgi: true,
+                cache_control: None,
+                error_pages: HashMap::new(),
+            })
+    }
+
+    /// 按 Host 头（或 TLS 的 SNI）找到虚拟主机，没有匹配时使用标记为 default 的虚拟主机
+    pub fn virtual_host(&self, host: &str) -> Option<&VirtualHostConfig> {
+        self.virtual_host_index(host)
+            .map(|index| &self.virtual_hosts[index])
+    }
+
+    fn virtual_host_index(&self, host: &str) -> Option<usize> {
+        let name = host_name(host);
+        let hosts = &self.virtual_hosts;
+        hosts
+            .iter()
+            .position(|vhost| vhost.names.iter().any(|n| n.eq_ignore_ascii_case(&name)))
+            .or_else(|| hosts.iter().position(|vhost| vhost.matches_wildcard(&name)))
+            .or_else(|| hosts.iter().position(|vhost| vhost.default))
+    }
+
+    /// 用虚拟主机的设置覆盖顶层设置，得到该虚拟主机的请求实际使用的配置
+    fn merge_virtual_host(&self, vhost: &VirtualHostConfig) -> Config {
+        let mut config = self.clone();
+        let vhost = vhost.clone();
+        config.static_dir = vhost.static_dir;
+        config.mounts = vhost.mounts;
+        config.access_log = vhost.access_log;
+        config.tls = vhost.tls;
+        if !vhost.rewrites.is_empty() {
+            config.rewrites = vhost.rewrites;
+        }
+        config.error_pages.extend(vhost.error_pages);
+        if !vhost.auth.is_empty() {
+            config.auth = vhost.auth;
+        }
+        config
+    }
+
+    /// 把目录解析为绝对路径，之后的请求处理不再依赖进程的当前目录
+    fn resolve_paths(&mut self, base: &Path) {
+        self.static_dir = resolve_path(base, &self.static_dir);
+        for mount in &mut self.mounts {
+            mount.resolve_paths(base);
+        }
+        if let Some(tls) = &mut self.tls {
+            tls.resolve_paths(base);
+        }
+        resolve_error_pages(base, &mut self.error_pages);
+        for auth in &mut self.auth {
+            auth.resolve_paths(base);
+        }
+        let disk_dir = self
+            .cache
+            .as_mut()
+            .and_then(|cache| cache.disk_dir.as_mut());
+        let files = [
+            self.access_log.as_mut(),
+            self.cgi.working_dir.as_mut(),
+            disk_dir,
+        ];
+        for file in files.into_iter().flatten() {
+            *file = resolve_path(base, file);
+        }
+        for vhost in &mut self.virtual_hosts {
+            vhost.static_dir = resolve_path(base, &vhost.static_dir);
+            for mount in &mut vhost.mounts {
+                mount.resolve_paths(base);
+            }
+            if let Some(tls) = &mut vhost.tls {
+                tls.resolve_paths(base);
+            }
+            resolve_error_pages(base, &mut vhost.error_pages);
+            for auth in &mut vhost.auth {
+                auth.resolve_paths(base);
+            }
+            if let Some(file) = &mut vhost.access_log {
+                *file = resolve_path(base, file);
+            }
+        }
+    }
+}
+
+impl VirtualHostConfig {
+    fn matches_wildcard(&self, name: &str) -> bool {
+        self.names.iter().any(|pattern| {
+            pattern.contains('*')
+                && glob::Pattern::new(&pattern.to_ascii_lowercase())
+                    .is_ok_and(|glob| glob.matches(name))
+        })
+    }
+}
+
+impl MountConfig {
+    fn resolve_paths(&mut self, base: &Path) {
+        self.root = resolve_path(base, &self.root);
+        resolve_error_pages(base, &mut self.error_pages);
+    }
+}
+
+impl AuthConfig {
+    fn resolve_paths(&mut self, base: &Path) {
+        if let Some(file) = &mut self.htpasswd {
+            *file = resolve_path(base, file);
+        }
+        if let Some(script) = self.auth_request.as_mut().and_then(|r| r.cgi.as_mut()) {
+            *script = resolve_path(base, script);
+        }
+        if let Some(jwt) = &mut self.jwt {
+            let files = [
+                &mut jwt.secret_file,
+                &mut jwt.public_key_file,
+                &mut jwt.jwks_file,
+            ];
+            for file in files.into_iter().flatten() {
+                *file = resolve_path(base, file);
+            }
+        }
+    }
+}
+
+fn resolve_error_pages(base: &Path, pages: &mut HashMap<String, String>) {
+    for file in pages.values_mut() {
+        *file = resolve_path(base, file);
+    }
+}
+
+impl TlsConfig {
+    fn resolve_paths(&mut self, base: &Path) {
+        self.cert = resolve_path(base, &self.cert);
+        self.key = resolve_path(base, &self.key);
+    }
+}
+
+/// 去掉 Host 头中的端口并转为小写，IPv6 地址保留方括号
+pub fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    name.to_ascii_lowercase()
+}
+
+fn resolve_path(base: &Path, path: &str) -> String {
+    let path = base.join(path);
+    path.canonicalize().unwrap_or(path).display().to_string()
+}
+
+/// URL 路径是否位于前缀之下，"/api" 匹配 "/api" 和 "/api/x"，不匹配 "/apix"
+pub fn prefix_matches(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl ProxyRoute {
+    /// 路由的全部上游，单个 upstream 视为权重为 1 的池成员
+    pub fn servers(&self) -> Vec<UpstreamServer> {
+        let mut servers: Vec<UpstreamServer> = self
+            .upstream
+            .iter()
+            .map(|address| UpstreamServer {
+                address: address.clone(),
+                weight: 1,
+            })
+            .collect();
+        servers.extend(self.upstreams.iter().cloned());
+        servers
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UpstreamServer {
+    pub address: String,
+    /// 权重，只对 weighted 和 hash 策略有效
+    #[serde(default = "default_upstream_weight")]
+    pub weight: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Weighted,
+    /// 按客户端 IP 或 hash_header 做一致性哈希
+    Hash,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    /// 探测路径，返回 2xx/3xx 视为健康
+    pub path: String,
+    #[serde(default = "default_health_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ForwardProxyConfig {
+    /// 允许访问的目标 "host:port"，host 可以使用 glob（"*.example.com"），
+    /// port 可以是 "*"，省略端口表示任意端口
+    pub allow: Vec<String>,
+    /// 需要代理认证的用户，为空时不认证
+    #[serde(default)]
+    pub users: Vec<ProxyUser>,
+    #[serde(default = "default_proxy_connect_timeout")]
+    pub connect_timeout_secs: u64,
+    /// 等待目标服务器响应头的最长时间（秒）
+    #[serde(default = "default_backend_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ProxyUser {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// 内存中最多缓存的 URL 数，超出后淘汰最久未使用的
+    pub max_entries: usize,
+    /// 单个响应体的最大字节数，更大的响应不缓存
+    pub max_object_bytes: usize,
+    /// 磁盘缓存目录，未设置时只缓存在内存中
+    pub disk_dir: Option<String>,
+    /// 磁盘缓存的总大小上限（字节）
+    pub disk_max_bytes: u64,
+    /// 同一 URL 并发未命中时，等待第一个请求填充缓存的最长时间（秒）
+    pub lock_timeout_secs: u64,
+    /// 允许发送 PURGE 请求的客户端地址或 CIDR，来自可信代理的请求按 ip_access 的规则取客户端地址
+    #[serde(deserialize_with = "ip_nets")]
+    pub purge_allow: Vec<IpNet>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 1024,
+            max_object_bytes: 1024 * 1024,
+            disk_dir: None,
+            disk_max_bytes: 1024 * 1024 * 1024,
+            lock_timeout_secs: 5,
+            purge_allow: vec![
+                IpNet::from(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                IpNet::from(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            ],
+        }
+    }
+}
+
+/// WebSocket 内置路由，路径未设置时不启用
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// 回显路由，例如 "/ws/echo"
+    pub echo_path: Option<String>,
+    /// 广播房间路由，例如 "/ws/room"
+    pub room_path: Option<String>,
+    /// 单条消息（包括分片拼接后）的最大字节数，超出时以 1009 关闭连接
+    pub max_message_bytes: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            echo_path: None,
+            room_path: None,
+            max_message_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Server-Sent Events 设置
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SseConfig {
+    /// 内置的计时事件流路由，例如 "/events/ticker"
+    pub ticker_path: Option<String>,
+    /// 没有事件时发送注释行的间隔（秒）
+    pub keep_alive_secs: u64,
+    /// 流开始时发给客户端的重连等待时间（毫秒），未设置时使用浏览器默认值
+    pub retry_ms: Option<u64>,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig {
+            ticker_path: None,
+            keep_alive_secs: 15,
+            retry_ms: None,
+        }
+    }
+}
+
+/// 只重试幂等方法（GET、HEAD、OPTIONS、PUT、DELETE、TRACE）
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// 最多重试次数，0 表示不重试
+    pub attempts: u32,
+    /// 遇到这些状态码时重试，连接失败总是重试
+    pub retry_on_status: Vec<u16>,
+    /// 第一次重试前的等待时间（毫秒），之后每次翻倍
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// 重试预算：每个请求积累的重试额度，每次重试消耗 1
+    pub budget_ratio: f64,
+    /// 重试额度的上限，也是启动时的初始额度
+    pub budget_burst: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 0,
+            retry_on_status: vec![502, 503, 504],
+            backoff_ms: 50,
+            max_backoff_ms: 1000,
+            budget_ratio: 0.2,
+            budget_burst: 10.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// 连续失败（连接错误或 5xx）多少次后熔断
+    #[serde(default = "default_breaker_threshold")]
+    pub failure_threshold: u32,
+    /// 熔断持续时间（秒），之后进入半开状态
+    #[serde(default = "default_breaker_open")]
+    pub open_secs: u64,
+    /// 半开状态下允许同时通过的试探请求数
+    #[serde(default = "default_breaker_trials")]
+    pub half_open_requests: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MirrorConfig {
+    /// 影子上游地址
+    pub upstream: String,
+    /// 复制的请求比例，0 到 100
+    #[serde(default = "default_mirror_percent")]
+    pub percent: f64,
+}
+
+/// 把 URL 前缀挂载到一个目录，未设置的选项沿用全局设置
+#[derive(Deserialize, Clone)]
+pub struct MountConfig {
+    /// URL 前缀，例如 "/assets"
+    pub url: String,
+    /// 目录，例如 "/srv/assets"，相对路径相对于配置文件所在的目录
+    pub root: String,
+    /// 没有索引文件时是否列出目录，未设置时使用全局的 autoindex
+    #[serde(default)]
+    pub autoindex: Option<bool>,
+    /// 是否执行其中的 CGI 和后端脚本，关闭时全部按静态文件处理
+    #[serde(default = "default_true")]
+    pub cgi: bool,
+    /// 静态文件和目录列表响应的 Cache-Control 头，例如 "public, max-age=3600"
+    #[serde(default)]
+    pub cache_control: Option<String>,
+    /// 覆盖虚拟主机和顶层同一状态码的错误页
+    #[serde(default)]
+    pub error_pages: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DirectoryConfig {
+    /// URL 路径前缀，例如 "/public/private"
+    pub path: String,
+    pub autoindex: bool,
+}
+
+/// CGI 脚本的执行限制
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CgiConfig {
+    /// 脚本运行的最长时间（秒），超时后整个进程组会被杀掉并返回 504
+    pub timeout_secs: u64,
+    /// RLIMIT_CPU，单位秒
+    pub max_cpu_secs: u64,
+    /// RLIMIT_AS，单位 MB
+    pub max_memory_mb: u64,
+    /// RLIMIT_NOFILE
+    pub max_open_files: u64,
+    /// RLIMIT_NPROC：按脚本运行用户的全部进程计算，不只是这个脚本；以 root 运行时不起作用
+    pub max_processes: u64,
+    /// 同时运行的脚本数上限，达到上限时新请求最多等待 timeout_secs，之后返回 503
+    pub max_concurrent: usize,
+    /// 允许从服务器进程传递给脚本的环境变量，其余变量全部清除
+    pub env_allowlist: Vec<String>,
+    /// 脚本的工作目录，未设置时使用脚本所在目录
+    pub working_dir: Option<String>,
+    /// 按扩展名或 glob 模式指定解释器，按顺序匹配
+    pub interpreters: Vec<InterpreterRule>,
+    /// cgi-bin 目录，相对脚本所在挂载点的目录（"/" 的挂载目录就是 static_dir），
+    /// 其中的所有文件都作为脚本执行
+    pub cgi_dirs: Vec<String>,
+    /// 禁止执行脚本的目录，与 cgi_dirs 一样相对挂载目录，例如上传目录，优先级最高
+    pub no_exec_dirs: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct InterpreterRule {
+    /// ".py" 形式匹配扩展名，其余按 glob 匹配文件名（含 / 时匹配相对挂载目录的路径）
+    pub pattern: String,
+    /// 解释器命令，可以带参数，例如 "python3 -u"
+    pub command: String,
+}
+
+impl Default for CgiConfig {
+    fn default() -> Self {
+        CgiConfig {
+            timeout_secs: 30,
+            max_cpu_secs: 10,
+            max_memory_mb: 512,
+            max_open_files: 64,
+            max_processes: 64,
+            max_concurrent: 32,
+            env_allowlist: vec!["PATH".to_string(), "LANG".to_string(), "TZ".to_string()],
+            working_dir: None,
+            interpreters: Vec::new(),
+            cgi_dirs: Vec::new(),
+            no_exec_dirs: Vec::new(),
+        }
+    }
+}
+
+/// 把匹配的脚本交给外部应用服务器执行，例如 PHP-FPM 或 SCGI/uwsgi 的 Python 应用
+#[derive(Deserialize, Clone)]
+pub struct BackendConfig {
+    /// 匹配规则与 cgi.interpreters 的 pattern 相同
+    pub pattern: String,
+    pub protocol: BackendProtocol,
+    /// "host:port" 或 "unix:/path/to.sock"
+    pub address: String,
+    /// 等待后端返回响应头的最长时间（秒），超时返回 504
+    #[serde(default = "default_backend_timeout")]
+    pub timeout_secs: u64,
+    /// 后端是否支持在同一连接上并发处理多个请求（FastCGI 的 FCGI_MPXS_CONNS）
+    #[serde(default)]
+    pub multiplex: bool,
+    /// 连接池中最多保留的空闲连接数
+    #[serde(default = "default_backend_max_idle")]
+    pub max_idle: usize,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendProtocol {
+    FastCgi,
+    Scgi,
+    Uwsgi,
+}
+
+fn default_index_files() -> Vec<String> {
+    vec!["index.html".to_string(), "index.htm".to_string()]
+}
+
+fn default_realm() -> String {
+    "Restricted".to_string()
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+fn default_auth_request_timeout() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_backend_timeout() -> u64 {
+    30
+}
+
+fn default_backend_max_idle() -> usize {
+    8
+}
+
+fn default_proxy_connect_timeout() -> u64 {
+    5
+}
+
+fn default_websocket_idle_timeout() -> u64 {
+    300
+}
+
+fn default_upstream_weight() -> u32 {
+    1
+}
+
+fn default_max_fails() -> u32 {
+    3
+}
+
+fn default_fail_timeout() -> u64 {
+    10
+}
+
+fn default_health_interval() -> u64 {
+    10
+}
+
+fn default_health_timeout() -> u64 {
+    2
+}
+
+fn default_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_breaker_open() -> u64 {
+    30
+}
+
+fn default_breaker_trials() -> u32 {
+    1
+}
+
+fn default_mirror_percent() -> f64 {
+    100.0
 }
 
 #[derive(Debug)]
@@ -15,26 +867,199 @@ pub enum ConfigError {
     ConfigFormatError,
 }
 
-const CONFIG_PATH: &str = "./config.json";
+/// 命令行没有指定 --config 时使用的配置文件
+#[cfg(not(test))]
+const DEFAULT_CONFIG_PATH: &str = "./config.json";
+/// 单元测试使用 testdata 中固定的配置和文件，不受 config.json 修改的影响
+#[cfg(test)]
+const DEFAULT_CONFIG_PATH: &str = "./testdata/config.json";
+
+// 命令行指定的配置文件
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// 全局缓存的配置，以及每个虚拟主机合并后的配置（与 virtual_hosts 一一对应）。
+// 重新加载时整体替换，已经取得配置的请求继续使用旧的那份
+static CONFIG: RwLock<Option<Arc<LoadedConfig>>> = RwLock::new(None);
+
+struct LoadedConfig {
+    base: Arc<Config>,
+    virtual_hosts: Vec<Arc<Config>>,
+}
+
+/// 请求实际使用的配置：匹配到虚拟主机时用它的设置覆盖顶层设置。
+/// 合并结果在加载配置时算好，这里只增加引用计数
+pub async fn for_request(req: &HttpRequest) -> Result<Arc<Config>, ConfigError> {
+    let loaded = load().await?;
+    let host = req.header("Host").unwrap_or("");
+    Ok(match loaded.base.virtual_host_index(host) {
+        Some(index) => loaded.virtual_hosts[index].clone(),
+        None => loaded.base.clone(),
+    })
+}
 
-// 全局 OnceCell 缓存配置
-static CONFIG: OnceCell<Config> = OnceCell::const_new();
+/// 指定配置文件，必须在第一次读取配置之前调用
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
 
-pub async fn read_config() -> Result<Config, ConfigError> {
-    // 如果已经初始化过，返回副本
-    if let Some(cfg) = CONFIG.get() {
-        return Ok(cfg.clone());
+pub fn config_path() -> &'static Path {
+    CONFIG_PATH.get_or_init(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// 配置文件实际所在的目录（解析符号链接之后），找不到配置文件时使用工作目录
+pub fn config_dir() -> PathBuf {
+    std::fs::canonicalize(config_path())
+        .ok()
+        .and_then(|file| file.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+pub async fn read_config() -> Result<Arc<Config>, ConfigError> {
+    Ok(load().await?.base.clone())
+}
+
+async fn load() -> Result<Arc<LoadedConfig>, ConfigError> {
+    // 如果已经初始化过，直接返回缓存
+    if let Some(loaded) = CONFIG.read().unwrap().as_ref() {
+        return Ok(loaded.clone());
     }
 
     // 否则异步读取并存入全局缓存
-    let content = fs::read_to_string(CONFIG_PATH)
+    let loaded = Arc::new(read_file().await?);
+    // 并发时可能已经存入，以先存入的为准
+    Ok(CONFIG.write().unwrap().get_or_insert(loaded).clone())
+}
+
+/// 重新读取配置文件，之后的连接和请求使用新的配置。
+/// 新的配置无法读取或格式错误时保留原来的配置
+pub async fn reload() -> Result<(), ConfigError> {
+    let loaded = Arc::new(read_file().await?);
+    *CONFIG.write().unwrap() = Some(loaded);
+    Ok(())
+}
+
+/// 收到 SIGHUP 时重新加载配置
+pub fn start_reload_listener() {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reload().await {
+                Ok(()) => info!("Reloaded config from {}", config_path().display()),
+                Err(e) => error!("Reloading config fail, keeping the old config: {:?}", e),
+            }
+        }
+    });
+}
+
+async fn read_file() -> Result<LoadedConfig, ConfigError> {
+    let path = config_path();
+    let content = fs::read_to_string(path)
+        .await
+        .map_err(|_| ConfigError::ReadConfigFileFail)?;
+
+    let mut config: Config = serde_json::from_str(&content).map_err(|e| {
+        error!("Invalid config file {}: {}", path.display(), e);
+        ConfigError::ConfigFormatError
+    })?;
+    // 相对路径相对于配置文件实际所在的目录（解析符号链接之后），与工作目录无关
+    let file = fs::canonicalize(path)
         .await
         .map_err(|_| ConfigError::ReadConfigFileFail)?;
+    let base = file.parent().ok_or(ConfigError::ReadConfigFileFail)?;
+    config.resolve_paths(base);
+
+    let virtual_hosts = config
+        .virtual_hosts
+        .iter()
+        .map(|vhost| Arc::new(config.merge_virtual_host(vhost)))
+        .collect();
+    Ok(LoadedConfig {
+        base: Arc::new(config),
+        virtual_hosts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> Config {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn relative_paths_are_resolved_against_the_config_directory() {
+        let mut config = config(
+            r#"{
+                "host": "127.0.0.1", "port": 80, "static_dir": "www", "concurrent_thread": 1,
+                "access_log": "logs/access.log",
+                "cache": { "disk_dir": "cache" },
+                "cgi": { "working_dir": "tmp" },
+                "error_pages": { "404": "errors/404.html" },
+                "mounts": [{ "url": "/files", "root": "files" }],
+                "virtual_hosts": [{
+                    "names": ["example.com"], "static_dir": "example",
+                    "access_log": "logs/example.log"
+                }]
+            }"#,
+        );
+        config.resolve_paths(Path::new("/nonexistent/site"));
+
+        assert_eq!(config.static_dir, "/nonexistent/site/www");
+        assert_eq!(
+            config.access_log.as_deref(),
+            Some("/nonexistent/site/logs/access.log")
+        );
+        let cache = config.cache.as_ref().unwrap();
+        assert_eq!(cache.disk_dir.as_deref(), Some("/nonexistent/site/cache"));
+        assert_eq!(
+            config.cgi.working_dir.as_deref(),
+            Some("/nonexistent/site/tmp")
+        );
+        assert_eq!(
+            config.error_pages["404"],
+            "/nonexistent/site/errors/404.html"
+        );
+        assert_eq!(config.mounts[0].root, "/nonexistent/site/files");
+        let vhost = &config.virtual_hosts[0];
+        assert_eq!(vhost.static_dir, "/nonexistent/site/example");
+        assert_eq!(
+            vhost.access_log.as_deref(),
+            Some("/nonexistent/site/logs/example.log")
+        );
+    }
 
-    let config: Config =
-        serde_json::from_str(&content).map_err(|_| ConfigError::ConfigFormatError)?;
+    #[test]
+    fn absolute_paths_are_kept() {
+        let mut config = config(
+            r#"{
+                "host": "127.0.0.1", "port": 80, "static_dir": "/nonexistent/www",
+                "concurrent_thread": 1, "access_log": "/nonexistent/access.log"
+            }"#,
+        );
+        config.resolve_paths(Path::new("/nonexistent/site"));
 
-    let _ = CONFIG.set(config.clone()); // 并发时可能已经 set，无需报错
+        assert_eq!(config.static_dir, "/nonexistent/www");
+        assert_eq!(
+            config.access_log.as_deref(),
+            Some("/nonexistent/access.log")
+        );
+        assert_eq!(config.cgi.working_dir, None);
+    }
 
-    Ok(config)
+    #[tokio::test]
+    async fn reload_replaces_the_loaded_config() {
+        let before = read_config().await.unwrap();
+        reload().await.unwrap();
+        let after = read_config().await.unwrap();
+        assert!(!Arc::ptr_eq(&before, &after));
+        assert_eq!(after.static_dir, before.static_dir);
+        assert_eq!(after.ip_access.locations.len(), 1);
+    }
 }
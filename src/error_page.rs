@@ -0,0 +1,225 @@
+//! 错误响应的正文：按挂载点、虚拟主机、顶层的顺序查找配置的错误页，
+//! 没有配置时使用内置的页面，客户端要求 JSON 时返回 JSON
+
+use crate::{
+    config::{self, Config},
+    http::{HttpRequest, HttpResponse},
+};
+use serde_json::json;
+use std::collections::HashMap;
+use tokio::fs;
+use tracing::warn;
+
+/// 内置的错误页，占位符与配置的错误页相同
+const DEFAULT_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="UTF-8">
+<title>{status} {reason}</title>
+<style>
+body { margin: 0; font-family: -apple-system, "Segoe UI", Helvetica, Arial, sans-serif; background: #f4f5f7; color: #333; }
+main { max-width: 36rem; margin: 15vh auto; padding: 2rem 2.5rem; background: #fff; border-radius: 8px; box-shadow: 0 2px 12px rgba(0, 0, 0, 0.08); }
+h1 { margin: 0 0 0.5rem; font-size: 3rem; color: #c0392b; }
+h2 { margin: 0 0 1.5rem; font-weight: normal; }
+p { margin: 0.25rem 0; color: #777; font-size: 0.9rem; word-break: break-all; }
+</style>
+</head>
+<body>
+<main>
+<h1>{status}</h1>
+<h2>{reason}</h2>
+<p>Path: {path}</p>
+<p>Request ID: {request_id}</p>
+</main>
+</body>
+</html>
+"#;
+
+/// 给没有正文的错误响应（4xx、5xx）加上错误页，其他响应原样返回
+pub async fn decorate(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    let Ok(status) = response.code.parse::<u16>() else {
+        return response;
+    };
+    if status < 400 || !response.body.is_empty() || response.stream.is_some() {
+        return response;
+    }
+
+    if wants_json(req) {
+        let body = json!({
+            "status": status,
+            "error": response.reason,
+            "path": req.path,
+            "request_id": req.id,
+        });
+        return response.body("application/json", body.to_string());
+    }
+
+    let template = match config::for_request(req).await {
+        Ok(config) => match find_page(&config, &req.path, status) {
+            Some(file) => match fs::read_to_string(&file).await {
+                Ok(template) => Some(template),
+                Err(e) => {
+                    warn!("Cannot read error page {}: {}", file, e);
+                    None
+                }
+            },
+            None => None,
+        },
+        Err(_) => None,
+    };
+    let page = render(
+        template.as_deref().unwrap_or(DEFAULT_PAGE),
+        status,
+        &response.reason,
+        req,
+    );
+    response.body("text/html; charset=utf-8", page)
+}
+
+/// Accept 中列出 JSON 且没有同时接受 HTML 时返回 JSON
+fn wants_json(req: &HttpRequest) -> bool {
+    let Some(accept) = req.header("Accept") else {
+        return false;
+    };
+    let types: Vec<&str> = accept
+        .split(',')
+        .map(|item| item.split(';').next().unwrap_or("").trim())
+        .collect();
+    types
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case("application/json") || t.ends_with("+json"))
+        && !types.iter().any(|t| t.eq_ignore_ascii_case("text/html"))
+}
+
+/// 挂载点的设置优先，其次是虚拟主机和顶层（二者已经在 for_request 中合并）
+fn find_page(config: &Config, url_path: &str, status: u16) -> Option<String> {
+    let mount = config.mount_for(url_path);
+    lookup(&mount.error_pages, status).or_else(|| lookup(&config.error_pages, status))
+}
+
+/// 精确的状态码优先于 "4xx" 这样的类别
+fn lookup(pages: &HashMap<String, String>, status: u16) -> Option<String> {
+    pages
+        .get(&status.to_string())
+        .or_else(|| pages.get(&format!("{}xx", status / 100)))
+        .cloned()
+}
+
+/// 替换 {status}、{reason}、{path} 和 {request_id}，路径经过 HTML 转义
+fn render(template: &str, status: u16, reason: &str, req: &HttpRequest) -> String {
+    template
+        .replace("{status}", &status.to_string())
+        .replace("{reason}", &escape_html(reason))
+        .replace("{path}", &escape_html(&req.path))
+        .replace("{request_id}", &req.id.to_string())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, accept: Option<&str>) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.method = "GET".to_string();
+        req.path = path.to_string();
+        if let Some(accept) = accept {
+            req.headers.insert("Accept".to_string(), accept.to_string());
+        }
+        req
+    }
+
+    fn wants(accept: Option<&str>) -> bool {
+        wants_json(&request("/", accept))
+    }
+
+    fn config(json: &str) -> Config {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn json_is_chosen_only_when_html_is_not_accepted() {
+        assert!(!wants(None));
+        assert!(wants(Some("application/json")));
+        assert!(wants(Some("Application/JSON; charset=utf-8")));
+        assert!(wants(Some("application/problem+json, */*;q=0.1")));
+        assert!(!wants(Some("text/html, application/json;q=0.9")));
+        assert!(!wants(Some("text/html,application/xhtml+xml,*/*;q=0.8")));
+        assert!(!wants(Some("*/*")));
+    }
+
+    #[test]
+    fn exact_status_wins_over_status_class() {
+        let config = config(
+            r#"{
+                "host": "127.0.0.1", "port": 80, "static_dir": "/srv/www", "concurrent_thread": 1,
+                "error_pages": { "404": "/pages/404.html", "4xx": "/pages/4xx.html", "5xx": "/pages/5xx.html" }
+            }"#,
+        );
+        assert_eq!(
+            find_page(&config, "/x", 404).as_deref(),
+            Some("/pages/404.html")
+        );
+        assert_eq!(
+            find_page(&config, "/x", 403).as_deref(),
+            Some("/pages/4xx.html")
+        );
+        assert_eq!(
+            find_page(&config, "/x", 502).as_deref(),
+            Some("/pages/5xx.html")
+        );
+    }
+
+    #[test]
+    fn mount_pages_win_over_top_level_pages() {
+        let config = config(
+            r#"{
+                "host": "127.0.0.1", "port": 80, "static_dir": "/srv/www", "concurrent_thread": 1,
+                "error_pages": { "404": "/pages/404.html", "500": "/pages/500.html" },
+                "mounts": [{
+                    "url": "/docs", "root": "/srv/docs",
+                    "error_pages": { "4xx": "/docs/4xx.html" }
+                }]
+            }"#,
+        );
+        // 挂载点的类别页面也优先于顶层的精确状态码
+        assert_eq!(
+            find_page(&config, "/docs/a", 404).as_deref(),
+            Some("/docs/4xx.html")
+        );
+        assert_eq!(
+            find_page(&config, "/docs/a", 500).as_deref(),
+            Some("/pages/500.html")
+        );
+        assert_eq!(
+            find_page(&config, "/a", 404).as_deref(),
+            Some("/pages/404.html")
+        );
+        assert_eq!(find_page(&config, "/a", 503), None);
+    }
+
+    #[tokio::test]
+    async fn error_responses_get_a_body_in_the_requested_format() {
+        let response = decorate(
+            &request("/<missing>", Some("application/json")),
+            HttpResponse::not_found(),
+        )
+        .await;
+        assert_eq!(response.headers["Content-Type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["path"], "/<missing>");
+
+        let response = decorate(&request("/<missing>", None), HttpResponse::not_found()).await;
+        let page = String::from_utf8(response.body).unwrap();
+        assert!(page.contains("<h1>404</h1>"));
+        assert!(page.contains("Path: /&lt;missing&gt;"));
+    }
+}
@@ -1,5 +1,5 @@
 mod request;
 mod response;
 
-pub use request::HttpRequest;
-pub use response::HttpResponse;
+pub use request::{normalize_path, HttpRequest};
+pub use response::{BodyStream, BoxedReader, HttpResponse};
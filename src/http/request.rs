@@ -1,28 +1,52 @@
 use percent_encoding::percent_decode_str;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HttpRequest {
+    pub id: u64, // 请求 ID，用于在日志中关联同一请求
     pub method: String,
-    pub path: String, // 这里存储的是已经解码的路径
+    pub path: String,  // 这里存储的是已经解码的路径
+    pub query: String, // 原始（未解码）的查询字符串，不含 '?'
     pub version: String,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    pub body: Vec<u8>,
+    pub authority: Option<String>, // 绝对形式请求行或 CONNECT 中的目标 "host:port"
+    pub remote_addr: Option<SocketAddr>, // 客户端地址
+    pub local_addr: Option<SocketAddr>, // 本端（服务器）地址
+    pub remote_user: Option<String>, // 通过认证的用户名
+    pub tls: bool,                 // 是否通过 TLS（HTTPS 端口）收到
 }
 
 impl HttpRequest {
     pub fn new() -> Self {
         HttpRequest {
+            id: 0,
             method: String::new(),
             path: String::new(),
+            query: String::new(),
             version: String::new(),
             headers: HashMap::new(),
-            body: String::new(),
+            body: Vec::new(),
+            authority: None,
+            remote_addr: None,
+            local_addr: None,
+            remote_user: None,
+            tls: false,
         }
     }
 
-    pub async fn try_from_reader<T>(mut reader: BufReader<T>) -> Result<Self, HttpRequestError>
+    /// 按名称查找请求头，忽略大小写
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    }
+
+    /// 读取一个请求，读端留给调用者，CONNECT 隧道可以继续使用其中已缓冲的数据
+    pub async fn try_from_reader<T>(reader: &mut BufReader<T>) -> Result<Self, HttpRequestError>
     where
         T: AsyncRead + Unpin,
     {
@@ -40,14 +64,35 @@ impl HttpRequest {
             return Err(HttpRequestError::InvalidRequestLine);
         }
 
-        // 立即解码路径
-        let (method, raw_path, version) = (words[0], words[1], words[2]);
+        // 分离查询字符串，然后立即解码路径
+        let (method, target, version) = (words[0], words[1], words[2]);
+        // 正向代理的请求目标：CONNECT 使用 "host:port"，其余使用 "http://host/path"
+        let target = if method == "CONNECT" {
+            request.authority = Some(target.to_string());
+            String::new()
+        } else if target
+            .get(..7)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        {
+            let rest = &target[7..];
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            request.authority = Some(rest[..end].to_string());
+            match &rest[end..] {
+                path if path.starts_with('/') => path.to_string(),
+                path => format!("/{}", path),
+            }
+        } else {
+            target.to_string()
+        };
+        let (raw_path, query) = target.split_once('?').unwrap_or((&target, ""));
         let decoded_path = percent_decode_str(raw_path)
             .decode_utf8()
             .map_err(|_| HttpRequestError::InvalidPathEncoding)?;
 
         request.method = method.to_string();
-        request.path = decoded_path.into_owned(); // 存储解码后的路径
+        // 存储解码并规范化后的路径，之后的位置匹配都基于它，绝对形式的请求也不例外
+        request.path = normalize_path(&decoded_path);
+        request.query = query.to_string();
         request.version = version.to_string();
 
         // 读取头部
@@ -73,23 +118,103 @@ impl HttpRequest {
         }
 
         // 读取正文
-        if let Some(len) = request.headers.get("Content-Length") {
+        if let Some(len) = request.header("Content-Length") {
             let len = len
                 .parse::<usize>()
                 .map_err(|_| HttpRequestError::InvalidHeader)?;
             let mut body = vec![0u8; len];
-            reader.read_exact(&mut body).await.ok();
-            request.body = String::from_utf8(body).map_err(|_| HttpRequestError::InvalidBody)?;
+            reader
+                .read_exact(&mut body)
+                .await
+                .map_err(|_| HttpRequestError::InvalidBody)?;
+            request.body = body;
         }
 
         Ok(request)
     }
 }
 
+/// 合并重复的 '/'，去掉 "." 段并按 ".." 回退一级（RFC 3986 第 5.2.4 节），
+/// 超出根目录的 ".." 被忽略；保留结尾的 '/'。不以 '/' 开头的路径（如 "*"）原样返回
+pub fn normalize_path(path: &str) -> String {
+    if !path.starts_with('/') {
+        return path.to_string();
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum HttpRequestError {
     InvalidRequestLine,
     InvalidPathEncoding, // 新增的错误类型
     InvalidHeader,
     InvalidBody,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(raw: &str) -> HttpRequest {
+        let mut reader = BufReader::new(raw.as_bytes());
+        HttpRequest::try_from_reader(&mut reader).await.unwrap()
+    }
+
+    #[test]
+    fn collapses_slashes_and_dot_segments() {
+        assert_eq!(normalize_path("//private/x"), "/private/x");
+        assert_eq!(normalize_path("/./private/x"), "/private/x");
+        assert_eq!(normalize_path("/pub/../private/x"), "/private/x");
+        assert_eq!(normalize_path("/a/b/./c//d"), "/a/b/c/d");
+    }
+
+    #[test]
+    fn clamps_at_root() {
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/.."), "/");
+        assert_eq!(normalize_path("/"), "/");
+    }
+
+    #[test]
+    fn keeps_trailing_slash() {
+        assert_eq!(normalize_path("/docs/"), "/docs/");
+        assert_eq!(normalize_path("/docs/sub/.."), "/docs/");
+        assert_eq!(normalize_path("/docs/."), "/docs/");
+    }
+
+    #[test]
+    fn leaves_asterisk_form() {
+        assert_eq!(normalize_path("*"), "*");
+    }
+
+    #[tokio::test]
+    async fn absolute_form_paths_are_normalized() {
+        let req = parse("GET http://x//admin/../private/p.txt HTTP/1.1\r\nHost: y\r\n\r\n").await;
+        assert_eq!(req.authority.as_deref(), Some("x"));
+        assert_eq!(req.path, "/private/p.txt");
+
+        let req = parse("GET http://x HTTP/1.1\r\n\r\n").await;
+        assert_eq!(req.path, "/");
+    }
+}
@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug)]
 pub struct HttpResponse {
@@ -7,6 +9,29 @@ pub struct HttpResponse {
     pub reason: String,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    pub stream: Option<BodyStream>, // 流式响应体，存在时 body 不会被发送
+}
+
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// 长时间产生数据的响应体，由 write_to 边读边写给客户端
+pub enum BodyStream {
+    /// 写完响应头后以 chunked 编码转发
+    Chunked(BoxedReader),
+    /// 长度已知（响应头中已有 Content-Length），写完响应头后原样转发
+    Sized(BoxedReader),
+    /// 不写响应头，原样转发（例如 nph 脚本自己输出完整的 HTTP 响应）
+    Raw(BoxedReader),
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyStream::Chunked(_) => f.write_str("BodyStream::Chunked"),
+            BodyStream::Sized(_) => f.write_str("BodyStream::Sized"),
+            BodyStream::Raw(_) => f.write_str("BodyStream::Raw"),
+        }
+    }
 }
 
 impl HttpResponse {
@@ -17,6 +42,7 @@ impl HttpResponse {
             reason: String::new(),
             headers: HashMap::new(),
             body: Vec::new(),
+            stream: None,
         }
     }
     #[allow(dead_code)]
@@ -51,6 +77,41 @@ impl HttpResponse {
         response.extend(&self.body);
         response
     }
+
+    /// 把响应写给客户端，流式响应体会一直转发到读端结束
+    pub async fn write_to<W>(mut self, writer: &mut W) -> tokio::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self.stream.take() {
+            None => writer.write_all(&self.gen_resp_bytes()).await,
+            Some(BodyStream::Raw(mut reader)) => {
+                tokio::io::copy(&mut reader, writer).await?;
+                Ok(())
+            }
+            Some(BodyStream::Sized(mut reader)) => {
+                writer.write_all(&self.gen_resp_bytes()).await?;
+                tokio::io::copy(&mut reader, writer).await?;
+                Ok(())
+            }
+            Some(BodyStream::Chunked(mut reader)) => {
+                writer.write_all(&self.gen_resp_bytes()).await?;
+                let mut buf = vec![0u8; 8192];
+                loop {
+                    let n = reader.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    writer.write_all(format!("{:x}\r\n", n).as_bytes()).await?;
+                    writer.write_all(&buf[..n]).await?;
+                    writer.write_all(b"\r\n").await?;
+                    // 每块数据立即发出，客户端可以实时看到进度
+                    writer.flush().await?;
+                }
+                writer.write_all(b"0\r\n\r\n").await
+            }
+        }
+    }
 }
 
 impl HttpResponse {
@@ -64,11 +125,21 @@ impl HttpResponse {
         Self::new_with_status("400", "Bad Request")
     }
 
+    /// 403 Forbidden
+    pub fn forbidden() -> Self {
+        Self::new_with_status("403", "Forbidden")
+    }
+
     /// 404 Not Found
     pub fn not_found() -> Self {
         Self::new_with_status("404", "Not Found")
     }
 
+    /// 405 Method Not Allowed，需配合 allow() 设置 Allow 头
+    pub fn method_not_allowed() -> Self {
+        Self::new_with_status("405", "Method Not Allowed")
+    }
+
     /// 500 Internal Server Error
     pub fn internal_server_error() -> Self {
         Self::new_with_status("500", "Internal Server Error")
@@ -79,6 +150,31 @@ impl HttpResponse {
         Self::new_with_status("501", "Not Implemented")
     }
 
+    /// 502 Bad Gateway
+    pub fn bad_gateway() -> Self {
+        Self::new_with_status("502", "Bad Gateway")
+    }
+
+    /// 503 Service Unavailable
+    pub fn service_unavailable() -> Self {
+        Self::new_with_status("503", "Service Unavailable")
+    }
+
+    /// 504 Gateway Timeout
+    pub fn gateway_timeout() -> Self {
+        Self::new_with_status("504", "Gateway Timeout")
+    }
+
+    /// 任意状态码，原因短语为空时使用标准短语
+    pub fn from_status(code: u16, reason: &str) -> Self {
+        let reason = if reason.is_empty() {
+            reason_phrase(code)
+        } else {
+            reason
+        };
+        Self::new_with_status(&code.to_string(), reason)
+    }
+
     fn new_with_status(code: &str, reason: &str) -> Self {
         let mut resp = Self::new();
         resp.version = "HTTP/1.1".to_string();
@@ -89,6 +185,29 @@ impl HttpResponse {
         resp
     }
 
+    /// 设置任意响应头，同名（忽略大小写）的旧值会被替换
+    pub fn header(mut self, key: &str, val: &str) -> Self {
+        self.headers.retain(|k, _| !k.eq_ignore_ascii_case(key));
+        self.headers.insert(key.to_string(), val.to_string());
+        self
+    }
+
+    /// 设置 Allow 头，列出资源支持的方法
+    pub fn allow(self, methods: &[&str]) -> Self {
+        self.header("Allow", &methods.join(", "))
+    }
+
+    /// 设置流式响应体，chunked 模式下改用 Transfer-Encoding 代替 Content-Length
+    pub fn stream(mut self, stream: BodyStream) -> Self {
+        if let BodyStream::Chunked(_) = stream {
+            self.headers
+                .retain(|k, _| !k.eq_ignore_ascii_case("Content-Length"));
+            self = self.header("Transfer-Encoding", "chunked");
+        }
+        self.stream = Some(stream);
+        self
+    }
+
     /// 设置响应体，自动设置 Content-Type 和 Content-Length
     pub fn body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
         self.body = body.into();
@@ -99,3 +218,39 @@ impl HttpResponse {
         self
     }
 }
+
+/// 常见状态码的标准原因短语
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,162 @@
+//! 按客户端 IP 的访问控制：全局规则在接受连接时检查，位置规则在路由时检查。
+//! 地址和 CIDR 在加载配置时已经解析
+
+use crate::{
+    config::{self, prefix_matches, IpAccessConfig},
+    http::{HttpRequest, HttpResponse},
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use tracing::warn;
+
+/// 全局规则是否允许来自该地址的连接
+pub async fn connection_allowed(ip: IpAddr) -> bool {
+    match config::read_config().await {
+        Ok(config) => allowed(ip, &config.ip_access.allow, &config.ip_access.deny),
+        Err(_) => true,
+    }
+}
+
+/// 请求路径所在位置的规则拒绝客户端时返回 403
+pub async fn check(req: &HttpRequest) -> Option<HttpResponse> {
+    let config = config::read_config().await.ok()?;
+    let access = &config.ip_access;
+    let location = access
+        .locations
+        .iter()
+        .filter(|location| prefix_matches(&location.path, &req.path))
+        .max_by_key(|location| location.path.len())?;
+    let ip = client_ip(req, access)?;
+    if allowed(ip, &location.allow, &location.deny) {
+        return None;
+    }
+    warn!("Client {} is not allowed to access {}", ip, req.path);
+    Some(HttpResponse::forbidden())
+}
+
+/// deny 优先，allow 不为空时只允许其中的地址
+fn allowed(ip: IpAddr, allow: &[IpNet], deny: &[IpNet]) -> bool {
+    let ip = ip.to_canonical();
+    if contains(deny, ip) {
+        return false;
+    }
+    allow.is_empty() || contains(allow, ip)
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+/// 客户端地址。来自可信代理的请求从右向左查找 X-Forwarded-For 中第一个不可信的地址，
+/// 没有 X-Forwarded-For 时使用 X-Real-IP
+pub fn client_ip(req: &HttpRequest, access: &IpAccessConfig) -> Option<IpAddr> {
+    let peer = req.remote_addr?.ip().to_canonical();
+    let trusted = |ip: IpAddr| contains(&access.trusted_proxies, ip);
+    if !trusted(peer) {
+        return Some(peer);
+    }
+    if let Some(forwarded) = req.header("X-Forwarded-For") {
+        let chain: Vec<IpAddr> = forwarded
+            .split(',')
+            .filter_map(|item| item.trim().parse::<IpAddr>().ok())
+            .map(|ip| ip.to_canonical())
+            .collect();
+        // 全部是可信代理时取最左边的地址
+        return chain
+            .iter()
+            .rev()
+            .find(|ip| !trusted(**ip))
+            .or(chain.first())
+            .copied()
+            .or(Some(peer));
+    }
+    req.header("X-Real-IP")
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .or(Some(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn nets(entries: &[&str]) -> Vec<IpNet> {
+        entries.iter().map(|entry| entry.parse().unwrap()).collect()
+    }
+
+    fn access(trusted_proxies: &[&str]) -> IpAccessConfig {
+        serde_json::from_value(json!({ "trusted_proxies": trusted_proxies })).unwrap()
+    }
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.remote_addr = Some(format!("{}:40000", peer).parse().unwrap());
+        for (name, value) in headers {
+            req.headers.insert(name.to_string(), value.to_string());
+        }
+        req
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn config_accepts_addresses_and_cidrs() {
+        let access: IpAccessConfig =
+            serde_json::from_value(json!({ "allow": ["10.0.0.0/8", "2001:db8::1"] })).unwrap();
+        assert!(contains(&access.allow, ip("10.1.2.3")));
+        assert!(contains(&access.allow, ip("2001:db8::1")));
+        assert!(!contains(&access.allow, ip("2001:db8::2")));
+        assert!(
+            serde_json::from_value::<IpAccessConfig>(json!({ "deny": ["10.0.0.0/33"] })).is_err()
+        );
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let allow = nets(&["10.0.0.0/8"]);
+        let deny = nets(&["10.0.0.5/32"]);
+        assert!(allowed(ip("10.0.0.4"), &allow, &deny));
+        assert!(!allowed(ip("10.0.0.5"), &allow, &deny));
+        assert!(!allowed(ip("192.168.0.1"), &allow, &deny));
+        assert!(allowed(ip("192.168.0.1"), &[], &deny));
+    }
+
+    #[test]
+    fn mapped_ipv4_matches_ipv4_rules() {
+        assert!(!allowed(
+            ip("::ffff:10.0.0.5"),
+            &[],
+            &nets(&["10.0.0.0/24"])
+        ));
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let req = request("203.0.113.9", &[("X-Forwarded-For", "1.2.3.4")]);
+        assert_eq!(client_ip(&req, &access(&[])), Some(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn skips_trusted_proxies_from_the_right() {
+        let access = access(&["10.0.0.0/8"]);
+        let req = request(
+            "10.0.0.1",
+            &[("X-Forwarded-For", "6.6.6.6, 198.51.100.7, 10.0.0.2")],
+        );
+        assert_eq!(client_ip(&req, &access), Some(ip("198.51.100.7")));
+        let req = request("10.0.0.1", &[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(client_ip(&req, &access), Some(ip("10.0.0.3")));
+    }
+
+    #[test]
+    fn falls_back_to_x_real_ip() {
+        let access = access(&["10.0.0.1"]);
+        let req = request("10.0.0.1", &[("X-Real-IP", "198.51.100.7")]);
+        assert_eq!(client_ip(&req, &access), Some(ip("198.51.100.7")));
+        let req = request("10.0.0.1", &[]);
+        assert_eq!(client_ip(&req, &access), Some(ip("10.0.0.1")));
+    }
+}
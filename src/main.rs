@@ -1,31 +1,83 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tracing::{error, info, warn};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, info_span, warn, Instrument};
 
 mod http;
-use http::HttpRequest;
+use http::{HttpRequest, HttpResponse};
 
 mod router;
 use router::router_request;
 
 mod config;
 
+mod cgi;
+
+mod backend;
+
+mod proxy;
+
+mod cache;
+
+mod websocket;
+
+mod sse;
+
 mod shutdown;
 use shutdown::ShutdownError;
 
+mod tls;
+
+mod access_log;
+
+mod rewrite;
+
+mod error_page;
+
+mod auth;
+
+mod ip_filter;
+
 mod logger;
 
+// 每个连接分配一个递增的请求 ID
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
 #[tokio::main]
 async fn main() {
-    // init log
-    logger::init_logger("./logs");
+    // `--config PATH` 指定配置文件，默认为工作目录下的 config.json
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "--config") {
+        let Some(path) = args.get(1) else {
+            eprintln!("usage: multithreading_http_server [--config PATH] [rewrite-test ... | htpasswd ...]");
+            std::process::exit(2);
+        };
+        config::set_config_path(PathBuf::from(path));
+        args.drain(..2);
+    }
+
+    // 命令行工具：显示一个 URL 命中的重写规则，或者向 htpasswd 文件添加用户，不启动服务器
+    match args.first().map(String::as_str) {
+        Some("rewrite-test") => std::process::exit(rewrite::dry_run(&args[1..]).await),
+        Some("htpasswd") => std::process::exit(auth::add_user(&args[1..]).await),
+        _ => {}
+    }
+
+    // init log，日志目录与其他相对路径一样位于配置文件所在目录
+    let log_dir = config::config_dir().join("logs");
+    logger::init_logger(&log_dir.display().to_string());
 
     // read config
-    info!("Reading config from ./config.json");
+    info!("Reading config from {}", config::config_path().display());
     let config = match config::read_config().await {
         Ok(c) => c,
         Err(e) => {
@@ -33,6 +85,11 @@ async fn main() {
             panic!("Reading config fail, {:?}", e);
         }
     };
+    config::start_reload_listener();
+    proxy::start(&config);
+    websocket::start(&config);
+    sse::start(&config);
+    cache::start(&config);
     let addr = format!("{}:{}", config.host, config.port);
     // bind address
     let listener = match TcpListener::bind(&addr).await {
@@ -42,6 +99,30 @@ async fn main() {
             panic!("Cannot bind to address {}, {}", addr, e);
         }
     };
+    // HTTPS 监听端口，证书按 SNI 从虚拟主机中选择
+    let tls_listener = match (config.tls_port, tls::build_acceptor(&config)) {
+        (Some(port), Some(acceptor)) => {
+            let tls_addr = format!("{}:{}", config.host, port);
+            match TcpListener::bind(&tls_addr).await {
+                Ok(tcp_listener) => {
+                    info!("Listening on {} (TLS)", tls_addr);
+                    Some((tcp_listener, acceptor))
+                }
+                Err(e) => {
+                    error!("Cannot bind to address {}: {}", tls_addr, e);
+                    panic!("Cannot bind to address {}, {}", tls_addr, e);
+                }
+            }
+        }
+        (Some(port), None) => {
+            error!(
+                "tls_port {} is set but no certificate could be loaded",
+                port
+            );
+            None
+        }
+        (None, _) => None,
+    };
     let notify_shutdown = match shutdown::start_shutdown_listener() {
         Ok(val) => val,
         Err(e) => {
@@ -56,15 +137,23 @@ async fn main() {
         tokio::select! {
             _ = notify_shutdown.notified() => {
                 info!("Shutting down...");
+                // 等待 WebSocket 隧道发送关闭帧后结束
+                proxy::close_websockets(Duration::from_secs(10)).await;
                 break;
             }
             accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((socket, addr)) => spawn_connection(socket, addr, None),
+                    Err(e) => {
+                        error!("Accept fail: {}", e);
+                    }
+                }
+            }
+            accept_result = accept_tls(&tls_listener) => {
                 match accept_result {
                     Ok((socket, addr)) => {
-                        info!("New connection from {}", addr);
-                        tokio::spawn(async move {
-                            handle_connection(socket).await;
-                        });
+                        let acceptor = tls_listener.as_ref().map(|(_, acceptor)| acceptor.clone());
+                        spawn_connection(socket, addr, acceptor);
                     }
                     Err(e) => {
                         error!("Accept fail: {}", e);
@@ -75,21 +164,135 @@ async fn main() {
     }
 }
 
-async fn handle_connection(mut socket: TcpStream) {
-    let (reader, mut writer) = socket.split();
-    let reader = BufReader::new(reader);
+/// 没有 HTTPS 监听端口时一直等待
+async fn accept_tls(
+    listener: &Option<(TcpListener, TlsAcceptor)>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 为新连接分配请求 ID 并在后台处理，`acceptor` 不为空时先完成 TLS 握手
+fn spawn_connection(socket: TcpStream, addr: SocketAddr, acceptor: Option<TlsAcceptor>) {
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    info!("New connection from {} (request id {})", addr, request_id);
+    let span = info_span!("request", id = request_id);
+    tokio::spawn(
+        async move {
+            // 全局规则拒绝的地址直接关闭连接
+            if !ip_filter::connection_allowed(addr.ip()).await {
+                warn!("Connection from {} denied by IP rules", addr);
+                return;
+            }
+            let remote_addr = socket.peer_addr().ok();
+            let local_addr = socket.local_addr().ok();
+            match acceptor {
+                None => handle_connection(socket, remote_addr, local_addr, request_id, false).await,
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(stream) => {
+                        handle_connection(stream, remote_addr, local_addr, request_id, true).await
+                    }
+                    Err(e) => warn!("TLS handshake failed: {}", e),
+                },
+            }
+        }
+        .instrument(span),
+    );
+}
+
+async fn handle_connection<S>(
+    socket: S,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    request_id: u64,
+    tls: bool,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // 使用拥有所有权的读写两端，WebSocket 处理器可以接管整个连接
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
     // request
-    let request = match HttpRequest::try_from_reader(reader).await {
+    let mut request = match HttpRequest::try_from_reader(&mut reader).await {
         Ok(r) => r,
         Err(e) => {
             error!("Failed to parse request: {:#?}", e);
             return;
         }
     };
+    request.id = request_id;
+    request.remote_addr = remote_addr;
+    request.local_addr = local_addr;
+    request.tls = tls;
     info!("Request received: {} {}", request.method, request.path);
-    let response = router_request(&request).await;
+
+    // 访问检查先于一切处理，访问日志记录原始请求，认证得到的用户名也记录在其中
+    let admitted = match router::admit(&request).await {
+        Ok(admitted) => admitted,
+        Err(denied) => return send_response(&mut writer, &request, denied).await,
+    };
+    request.remote_user = admitted.remote_user.clone();
+    // 与普通请求一样使用虚拟主机合并后的配置
+    let config = config::for_request(&admitted).await.ok();
+
+    // WebSocket 握手成功后连接交给对应的处理器
+    if let Some(handler) = websocket::find_handler(&admitted) {
+        let max_message = config
+            .as_ref()
+            .map_or(1024 * 1024, |c| c.websocket.max_message_bytes);
+        websocket::serve(handler, admitted, reader, writer, max_message).await;
+        return;
+    }
+    // 发往代理路由的升级请求转发给上游，之后成为双向隧道
+    let upgrade_route = config
+        .as_ref()
+        .filter(|_| websocket::is_upgrade_request(&admitted))
+        .and_then(|c| proxy::find_route(&admitted.path, c));
+    if let Some(route) = upgrade_route {
+        proxy::handle_websocket(route, &admitted, &mut reader, &mut writer).await;
+        return;
+    }
+
+    // CONNECT 建立隧道后不再按 HTTP 处理，直接双向转发
+    let forward_proxy = config.and_then(|c| c.forward_proxy.clone());
+    if let (true, Some(forward)) = (admitted.method == "CONNECT", &forward_proxy) {
+        match proxy::open_tunnel(forward, &admitted).await {
+            Ok(target) => {
+                let established = writer
+                    .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                    .await;
+                if established.is_ok() {
+                    proxy::splice(&mut reader, &mut writer, target).await;
+                }
+            }
+            Err(response) => {
+                if let Err(e) = response.write_to(&mut writer).await {
+                    error!("Failed to write response: {:#?}", e);
+                }
+            }
+        }
+        return;
+    }
+
+    let response = router_request(&admitted).await;
+    send_response(&mut writer, &request, response).await;
+}
+
+/// 写出响应并关闭连接，然后记录访问日志
+async fn send_response<W>(writer: &mut W, request: &HttpRequest, response: HttpResponse)
+where
+    W: AsyncWrite + Unpin,
+{
     info!("Response status: {}", response.code);
-    if let Err(e) = writer.write_all(&response.gen_resp_bytes()).await {
+    let code = response.code.clone();
+    let length = response
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, val)| val.clone());
+    if let Err(e) = response.write_to(writer).await {
         match e.kind() {
             tokio::io::ErrorKind::NotConnected => {}
             _ => {
@@ -105,4 +308,5 @@ async fn handle_connection(mut socket: TcpStream) {
             }
         }
     }
+    access_log::record(request, &code, length.as_deref()).await;
 }
@@ -0,0 +1,558 @@
+//! 上游池：负载均衡、被动摘除和主动健康检查
+//!
+//! 每条代理路由对应一个池，池中记录每个上游的并发数、连续失败次数和健康状态。
+
+use super::breaker::CircuitBreaker;
+use super::upstream;
+use crate::config::{BalanceStrategy, Config, HealthCheckConfig, ProxyRoute};
+use crate::http::HttpRequest;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::{info, warn};
+
+/// 一致性哈希中每单位权重的虚拟节点数
+const VIRTUAL_NODES: u32 = 100;
+
+// 按路由前缀保存的上游池
+static POOLS: LazyLock<Mutex<HashMap<String, Arc<UpstreamPool>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub struct UpstreamPool {
+    prefix: String,
+    strategy: BalanceStrategy,
+    hash_header: Option<String>,
+    max_fails: u32,
+    fail_timeout: Duration,
+    servers: Vec<Server>,
+    /// 轮询计数
+    next: AtomicUsize,
+    /// 平滑加权轮询中每个上游的当前权重
+    current_weights: Mutex<Vec<i64>>,
+    /// 一致性哈希环，按哈希值排序的（虚拟节点, 上游下标）
+    ring: Vec<(u64, usize)>,
+    /// 剩余的重试额度
+    retry_tokens: Mutex<f64>,
+    retry_ratio: f64,
+    retry_burst: f64,
+    /// 经过该路由的请求数，用于按比例选择镜像请求
+    requests: AtomicU64,
+}
+
+struct Server {
+    address: String,
+    weight: u32,
+    /// 正在进行的请求数，包括打开的 WebSocket 隧道
+    active: AtomicUsize,
+    /// 打开的 WebSocket 隧道数
+    websockets: AtomicUsize,
+    /// 连续失败次数
+    fails: AtomicU32,
+    /// 被动摘除的截止时间
+    ejected_until: Mutex<Option<Instant>>,
+    /// 主动健康检查的结果
+    healthy: AtomicBool,
+    breaker: Option<CircuitBreaker>,
+}
+
+/// 选中的上游，释放时并发数减一
+pub struct Selected {
+    pool: Arc<UpstreamPool>,
+    index: usize,
+}
+
+/// 创建所有代理路由的上游池，并为配置了健康检查的路由启动探测任务
+pub fn start(config: &Config) {
+    for route in &config.proxy {
+        let pool = pool_for(route);
+        if let Some(check) = &route.health_check {
+            tokio::spawn(health_check_loop(pool, check.clone()));
+        }
+    }
+}
+
+/// 取得路由的上游池，第一次使用时创建
+pub fn pool_for(route: &ProxyRoute) -> Arc<UpstreamPool> {
+    POOLS
+        .lock()
+        .unwrap()
+        .entry(route.prefix.clone())
+        .or_insert_with(|| Arc::new(UpstreamPool::new(route)))
+        .clone()
+}
+
+/// 所有上游池的状态
+pub fn status() -> Value {
+    let pools = POOLS.lock().unwrap();
+    let mut pools: Vec<&Arc<UpstreamPool>> = pools.values().collect();
+    pools.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+    Value::Array(pools.iter().map(|pool| pool.status()).collect())
+}
+
+impl UpstreamPool {
+    fn new(route: &ProxyRoute) -> Self {
+        let servers: Vec<Server> = route
+            .servers()
+            .into_iter()
+            .map(|server| Server {
+                address: server.address,
+                weight: server.weight.max(1),
+                active: AtomicUsize::new(0),
+                websockets: AtomicUsize::new(0),
+                fails: AtomicU32::new(0),
+                ejected_until: Mutex::new(None),
+                healthy: AtomicBool::new(true),
+                breaker: route.circuit_breaker.clone().map(CircuitBreaker::new),
+            })
+            .collect();
+
+        let mut ring = Vec::new();
+        for (index, server) in servers.iter().enumerate() {
+            for node in 0..server.weight * VIRTUAL_NODES {
+                ring.push((hash_of(&(&server.address, node)), index));
+            }
+        }
+        ring.sort_unstable();
+
+        UpstreamPool {
+            prefix: route.prefix.clone(),
+            strategy: route.balance,
+            hash_header: route.hash_header.clone(),
+            max_fails: route.max_fails.max(1),
+            fail_timeout: Duration::from_secs(route.fail_timeout_secs),
+            current_weights: Mutex::new(vec![0; servers.len()]),
+            servers,
+            next: AtomicUsize::new(0),
+            ring,
+            retry_tokens: Mutex::new(route.retry.budget_burst),
+            retry_ratio: route.retry.budget_ratio,
+            retry_burst: route.retry.budget_burst,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// 按策略选择一个可用的上游，全部不可用时返回 None。
+    /// `client` 是客户端地址，哈希策略没有配置 hash_header 时按它分配
+    pub fn select(self: &Arc<Self>, req: &HttpRequest, client: Option<IpAddr>) -> Option<Selected> {
+        let candidates: Vec<usize> = (0..self.servers.len())
+            .filter(|&i| self.is_available(i))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self.strategy {
+            BalanceStrategy::RoundRobin => self.round_robin(&candidates),
+            BalanceStrategy::LeastConnections => {
+                // 从轮询位置开始找，并发数相同时依次分配
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..candidates.len())
+                    .map(|i| candidates[(start + i) % candidates.len()])
+                    .min_by_key(|&i| self.servers[i].active.load(Ordering::Relaxed))?
+            }
+            BalanceStrategy::Weighted => self.weighted(&candidates),
+            BalanceStrategy::Hash => match self.hash_key(req, client) {
+                Some(key) => self.consistent_hash(&key)?,
+                None => self.round_robin(&candidates),
+            },
+        };
+
+        let server = &self.servers[index];
+        server.active.fetch_add(1, Ordering::Relaxed);
+        if let Some(breaker) = &server.breaker {
+            breaker.on_selected();
+        }
+        Some(Selected {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// 每个新请求积累一部分重试额度
+    pub fn deposit_retry_budget(&self) {
+        let mut tokens = self.retry_tokens.lock().unwrap();
+        *tokens = (*tokens + self.retry_ratio).min(self.retry_burst);
+    }
+
+    /// 消耗一次重试额度，额度不足时返回 false，避免重试放大故障
+    pub fn withdraw_retry_budget(&self) -> bool {
+        let mut tokens = self.retry_tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    /// 按比例决定是否镜像当前请求，每 100 个请求中均匀地选出 percent 个
+    pub fn should_mirror(&self, percent: f64) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        let percent = percent.clamp(0.0, 100.0);
+        ((n + 1.0) * percent / 100.0).floor() > (n * percent / 100.0).floor()
+    }
+
+    fn round_robin(&self, candidates: &[usize]) -> usize {
+        candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+    }
+
+    /// 平滑加权轮询（与 nginx 相同），避免连续选中同一个高权重上游
+    fn weighted(&self, candidates: &[usize]) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best = candidates[0];
+        for &i in candidates {
+            let weight = self.servers[i].weight as i64;
+            current[i] += weight;
+            total += weight;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        best
+    }
+
+    fn hash_key(&self, req: &HttpRequest, client: Option<IpAddr>) -> Option<String> {
+        match &self.hash_header {
+            Some(name) => req.header(name).map(str::to_string),
+            None => client.map(|ip| ip.to_string()),
+        }
+    }
+
+    /// 在哈希环上顺时针找到第一个可用的上游
+    fn consistent_hash(&self, key: &str) -> Option<usize> {
+        let hash = hash_of(&key);
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        (0..self.ring.len())
+            .map(|i| self.ring[(start + i) % self.ring.len()].1)
+            .find(|&i| self.is_available(i))
+    }
+
+    fn is_available(&self, index: usize) -> bool {
+        let server = &self.servers[index];
+        if !server.healthy.load(Ordering::Relaxed) {
+            return false;
+        }
+        if let Some(breaker) = &server.breaker {
+            if !breaker.allows(&server.address) {
+                return false;
+            }
+        }
+        let mut ejected_until = server.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *ejected_until = None;
+                info!(
+                    "Upstream {} of {} is back in rotation",
+                    server.address, self.prefix
+                );
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn status(&self) -> Value {
+        let now = Instant::now();
+        let servers: Vec<Value> = self
+            .servers
+            .iter()
+            .map(|server| {
+                let ejected_secs = server
+                    .ejected_until
+                    .lock()
+                    .unwrap()
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs());
+                json!({
+                    "address": server.address,
+                    "weight": server.weight,
+                    "healthy": server.healthy.load(Ordering::Relaxed),
+                    "ejected_secs": ejected_secs,
+                    "active": server.active.load(Ordering::Relaxed),
+                    "websockets": server.websockets.load(Ordering::Relaxed),
+                    "consecutive_fails": server.fails.load(Ordering::Relaxed),
+                    "circuit": server.breaker.as_ref().map(|b| b.state_name()),
+                })
+            })
+            .collect();
+        json!({
+            "prefix": self.prefix,
+            "strategy": format!("{:?}", self.strategy),
+            "servers": servers,
+        })
+    }
+}
+
+impl Selected {
+    pub fn address(&self) -> &str {
+        &self.pool.servers[self.index].address
+    }
+
+    /// 记录上游返回的响应，5xx 计入熔断器的失败次数
+    pub fn report_response(&self, code: u16) {
+        let server = &self.pool.servers[self.index];
+        server.fails.store(0, Ordering::Relaxed);
+        if let Some(breaker) = &server.breaker {
+            if code >= 500 {
+                breaker.on_failure(&server.address);
+            } else {
+                breaker.on_success(&server.address);
+            }
+        }
+    }
+
+    /// 在 WebSocket 隧道打开和关闭时调用
+    pub fn websocket_opened(&self) {
+        self.pool.servers[self.index]
+            .websockets
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn websocket_closed(&self) {
+        self.pool.servers[self.index]
+            .websockets
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 记录一次连接或传输失败，连续失败达到 max_fails 时摘除该上游
+    pub fn report_failure(&self) {
+        let server = &self.pool.servers[self.index];
+        if let Some(breaker) = &server.breaker {
+            breaker.on_failure(&server.address);
+        }
+        let fails = server.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails < self.pool.max_fails {
+            return;
+        }
+        server.fails.store(0, Ordering::Relaxed);
+        *server.ejected_until.lock().unwrap() = Some(Instant::now() + self.pool.fail_timeout);
+        warn!(
+            "Upstream {} of {} ejected for {}s after {} consecutive failures",
+            server.address,
+            self.pool.prefix,
+            self.pool.fail_timeout.as_secs(),
+            fails
+        );
+    }
+}
+
+impl Drop for Selected {
+    fn drop(&mut self) {
+        self.pool.servers[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 响应体读完之前一直占用上游的并发计数
+pub struct TrackedReader<R> {
+    inner: R,
+    _upstream: Selected,
+}
+
+impl<R> TrackedReader<R> {
+    pub fn new(inner: R, upstream: Selected) -> Self {
+        TrackedReader {
+            inner,
+            _upstream: upstream,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TrackedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+async fn health_check_loop(pool: Arc<UpstreamPool>, check: HealthCheckConfig) {
+    let timeout = Duration::from_secs(check.timeout_secs);
+    let mut interval = tokio::time::interval(Duration::from_secs(check.interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        for server in &pool.servers {
+            let healthy = upstream::probe(&server.address, &check.path, timeout).await;
+            let was_healthy = server.healthy.swap(healthy, Ordering::Relaxed);
+            if was_healthy && !healthy {
+                warn!(
+                    "Upstream {} of {} failed health check {}",
+                    server.address, pool.prefix, check.path
+                );
+            } else if !was_healthy && healthy {
+                info!(
+                    "Upstream {} of {} passed health check again",
+                    server.address, pool.prefix
+                );
+            }
+        }
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    fn pool(route: Value) -> Arc<UpstreamPool> {
+        let route: ProxyRoute = serde_json::from_value(route).unwrap();
+        Arc::new(UpstreamPool::new(&route))
+    }
+
+    fn pick(pool: &Arc<UpstreamPool>, req: &HttpRequest) -> String {
+        pool.select(req, None).unwrap().address().to_string()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_servers() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "upstreams": [{ "address": "a" }, { "address": "b" }, { "address": "c" }]
+        }));
+        let req = HttpRequest::new();
+        let picked: Vec<String> = (0..6).map(|_| pick(&pool, &req)).collect();
+        assert_eq!(picked, ["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn weighted_is_smooth() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "balance": "weighted",
+            "upstreams": [
+                { "address": "a", "weight": 5 },
+                { "address": "b", "weight": 1 },
+                { "address": "c", "weight": 1 }
+            ]
+        }));
+        let req = HttpRequest::new();
+        let picked: Vec<String> = (0..7).map(|_| pick(&pool, &req)).collect();
+        assert_eq!(picked, ["a", "a", "b", "a", "c", "a", "a"]);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_servers() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "balance": "least_connections",
+            "upstreams": [{ "address": "a" }, { "address": "b" }]
+        }));
+        let req = HttpRequest::new();
+        let busy = pool.select(&req, None).unwrap();
+        let busy_address = busy.address().to_string();
+        for _ in 0..3 {
+            assert_ne!(pick(&pool, &req), busy_address);
+        }
+    }
+
+    #[test]
+    fn hash_keeps_a_key_on_one_server() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "balance": "hash",
+            "hash_header": "X-User",
+            "upstreams": [{ "address": "a" }, { "address": "b" }, { "address": "c" }]
+        }));
+        let mut req = HttpRequest::new();
+        req.headers
+            .insert("X-User".to_string(), "alice".to_string());
+        let first = pick(&pool, &req);
+        for _ in 0..5 {
+            assert_eq!(pick(&pool, &req), first);
+        }
+    }
+
+    #[test]
+    fn hash_without_header_uses_the_client_address() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "balance": "hash",
+            "upstreams": [{ "address": "a" }, { "address": "b" }, { "address": "c" }]
+        }));
+        // 所有请求都来自同一个代理，按 client 而不是 remote_addr 分配
+        let mut req = HttpRequest::new();
+        req.remote_addr = Some("10.0.0.1:40000".parse().unwrap());
+        let pick = |client: &str| {
+            let client = Some(client.parse().unwrap());
+            pool.select(&req, client).unwrap().address().to_string()
+        };
+        let first = pick("198.51.100.7");
+        for _ in 0..5 {
+            assert_eq!(pick("198.51.100.7"), first);
+        }
+        let picked: HashSet<String> = (1..=30)
+            .map(|i| pick(&format!("203.0.113.{}", i)))
+            .collect();
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn failing_server_is_ejected() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "max_fails": 2,
+            "fail_timeout_secs": 60,
+            "upstreams": [{ "address": "a" }, { "address": "b" }]
+        }));
+        let req = HttpRequest::new();
+        for _ in 0..2 {
+            let selected = pool.select(&req, None).unwrap();
+            assert_eq!(selected.address(), "a");
+            selected.report_failure();
+            assert_eq!(pick(&pool, &req), "b");
+        }
+        for _ in 0..4 {
+            assert_eq!(pick(&pool, &req), "b");
+        }
+    }
+
+    #[test]
+    fn no_server_when_all_are_ejected() {
+        let pool = pool(json!({ "prefix": "/", "upstream": "a", "max_fails": 1 }));
+        let req = HttpRequest::new();
+        pool.select(&req, None).unwrap().report_failure();
+        assert!(pool.select(&req, None).is_none());
+    }
+
+    #[test]
+    fn mirrors_the_requested_share() {
+        let pool = pool(json!({ "prefix": "/", "upstream": "a" }));
+        let mirrored = (0..100).filter(|_| pool.should_mirror(25.0)).count();
+        assert_eq!(mirrored, 25);
+    }
+
+    #[test]
+    fn retry_budget_is_limited() {
+        let pool = pool(json!({
+            "prefix": "/",
+            "upstream": "a",
+            "retry": { "budget_ratio": 0.5, "budget_burst": 1.0 }
+        }));
+        assert!(pool.withdraw_retry_budget());
+        assert!(!pool.withdraw_retry_budget());
+        pool.deposit_retry_budget();
+        assert!(!pool.withdraw_retry_budget());
+        pool.deposit_retry_budget();
+        assert!(pool.withdraw_retry_budget());
+    }
+}
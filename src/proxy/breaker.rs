@@ -0,0 +1,149 @@
+//! 单个上游的熔断器
+//!
+//! closed：正常转发，连续失败达到阈值后转为 open；
+//! open：拒绝所有请求，持续 open_secs 后转为 half-open；
+//! half-open：只放行少量试探请求，成功则恢复 closed，失败则重新 open。
+
+use crate::config::CircuitBreakerConfig;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { trials: u32 },
+}
+
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// 是否允许请求通过，open 状态到期后转为 half-open
+    pub fn allows(&self, address: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() < until => false,
+            State::Open { .. } => {
+                info!("Circuit for upstream {} is half-open", address);
+                *state = State::HalfOpen { trials: 0 };
+                true
+            }
+            State::HalfOpen { trials } => trials < self.config.half_open_requests.max(1),
+        }
+    }
+
+    /// 请求被分配到该上游，half-open 时占用一个试探名额
+    pub fn on_selected(&self) {
+        if let State::HalfOpen { trials } = &mut *self.state.lock().unwrap() {
+            *trials += 1;
+        }
+    }
+
+    pub fn on_success(&self, address: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let State::HalfOpen { .. } = *state {
+            info!("Circuit for upstream {} is closed", address);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn on_failure(&self, address: &str) {
+        let mut state = self.state.lock().unwrap();
+        let open = match &mut *state {
+            State::Closed { failures } => {
+                *failures += 1;
+                *failures >= self.config.failure_threshold.max(1)
+            }
+            State::HalfOpen { .. } => true,
+            State::Open { .. } => false,
+        };
+        if open {
+            warn!(
+                "Circuit for upstream {} is open for {}s",
+                address, self.config.open_secs
+            );
+            *state = State::Open {
+                until: Instant::now() + Duration::from_secs(self.config.open_secs),
+            };
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold,
+            open_secs,
+            half_open_requests: 1,
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(3, 60);
+        breaker.on_failure("a");
+        breaker.on_failure("a");
+        assert!(breaker.allows("a"));
+        breaker.on_failure("a");
+        assert_eq!(breaker.state_name(), "open");
+        assert!(!breaker.allows("a"));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(2, 60);
+        breaker.on_failure("a");
+        breaker.on_success("a");
+        breaker.on_failure("a");
+        assert_eq!(breaker.state_name(), "closed");
+    }
+
+    #[test]
+    fn half_open_admits_limited_trials() {
+        let breaker = breaker(1, 0);
+        breaker.on_failure("a");
+        assert!(breaker.allows("a"));
+        assert_eq!(breaker.state_name(), "half_open");
+        breaker.on_selected();
+        assert!(!breaker.allows("a"));
+    }
+
+    #[test]
+    fn half_open_trial_closes_or_reopens() {
+        let closing = breaker(1, 0);
+        closing.on_failure("a");
+        assert!(closing.allows("a"));
+        closing.on_selected();
+        closing.on_success("a");
+        assert_eq!(closing.state_name(), "closed");
+
+        let reopening = breaker(1, 60);
+        reopening.on_failure("a");
+        *reopening.state.lock().unwrap() = State::HalfOpen { trials: 1 };
+        reopening.on_failure("a");
+        assert_eq!(reopening.state_name(), "open");
+        assert!(!reopening.allows("a"));
+    }
+}
@@ -0,0 +1,274 @@
+//! 正向代理：转发绝对形式的请求（GET http://host/path），以及 CONNECT 隧道
+
+use super::upstream::{self, UpstreamOptions};
+use super::{
+    append_via, build_response, connection_tokens, error_response, is_hop_by_hop, PATH_ENCODE_SET,
+};
+use crate::{
+    auth::constant_time_eq,
+    config::{ForwardProxyConfig, ProxyUser},
+    http::{HttpRequest, HttpResponse},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::utf8_percent_encode;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{error, info, warn};
+
+/// 到同一目标最多保留的空闲连接数
+const MAX_IDLE: usize = 4;
+
+pub async fn handle_forward_request(
+    config: &ForwardProxyConfig,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let authority = req.authority.as_deref().unwrap_or("");
+    let address = match check_target(config, req, authority, Some(80)) {
+        Ok(address) => address,
+        Err(response) => return *response,
+    };
+    info!(
+        "Forward proxying {} http://{}{}",
+        req.method, authority, req.path
+    );
+
+    let request = build_request(req, authority);
+    let options = UpstreamOptions {
+        connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+        max_idle: MAX_IDLE,
+    };
+    let send = upstream::send(&address, &request, &req.method, &options);
+    match tokio::time::timeout(Duration::from_secs(config.timeout_secs), send).await {
+        Ok(Ok(response)) => build_response(response, None),
+        Ok(Err(e)) => error_response(&address, e),
+        Err(_) => error_response(&address, upstream::UpstreamError::Timeout),
+    }
+}
+
+/// 处理 CONNECT：检查目标并建立连接，失败时返回要发给客户端的响应
+pub async fn open_tunnel(
+    config: &ForwardProxyConfig,
+    req: &HttpRequest,
+) -> Result<TcpStream, HttpResponse> {
+    let authority = req.authority.as_deref().unwrap_or("");
+    let address = check_target(config, req, authority, None).map_err(|response| *response)?;
+    let connect = TcpStream::connect(&address);
+    match tokio::time::timeout(Duration::from_secs(config.connect_timeout_secs), connect).await {
+        Ok(Ok(stream)) => {
+            info!("Tunnel opened to {}", address);
+            Ok(stream)
+        }
+        Ok(Err(e)) => {
+            error!("Cannot open tunnel to {}: {}", address, e);
+            Err(HttpResponse::bad_gateway())
+        }
+        Err(_) => {
+            error!("Connecting tunnel to {} timed out", address);
+            Err(HttpResponse::gateway_timeout())
+        }
+    }
+}
+
+/// 在客户端和目标之间双向转发字节，任一方向结束时关闭对端的写方向
+pub async fn splice<R, W>(client_reader: &mut R, client_writer: &mut W, target: TcpStream)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut target_reader, mut target_writer) = target.into_split();
+    let upload = async {
+        let copied = tokio::io::copy(client_reader, &mut target_writer).await;
+        let _ = target_writer.shutdown().await;
+        copied
+    };
+    let download = async {
+        let copied = tokio::io::copy(&mut target_reader, client_writer).await;
+        let _ = client_writer.shutdown().await;
+        copied
+    };
+    match tokio::join!(upload, download) {
+        (Ok(sent), Ok(received)) => info!(
+            "Tunnel closed, {} bytes sent, {} bytes received",
+            sent, received
+        ),
+        (Err(e), _) | (_, Err(e)) => warn!("Tunnel closed with error: {}", e),
+    }
+}
+
+/// 检查代理认证和目标白名单，返回要连接的地址
+fn check_target(
+    config: &ForwardProxyConfig,
+    req: &HttpRequest,
+    authority: &str,
+    default_port: Option<u16>,
+) -> Result<String, Box<HttpResponse>> {
+    if !config.users.is_empty() && !is_authorized(&config.users, req) {
+        warn!("Proxy authentication failed for {:?}", req.remote_addr);
+        return Err(Box::new(
+            HttpResponse::from_status(407, "Proxy Authentication Required")
+                .header("Proxy-Authenticate", "Basic realm=\"proxy\""),
+        ));
+    }
+    let Some((host, port)) = split_authority(authority, default_port) else {
+        warn!("Invalid proxy target: {}", authority);
+        return Err(Box::new(HttpResponse::bad_request()));
+    };
+    if !is_allowed(&config.allow, host, port) {
+        warn!("Proxy target {}:{} is not allowed", host, port);
+        return Err(Box::new(HttpResponse::from_status(403, "")));
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+fn is_authorized(users: &[ProxyUser], req: &HttpRequest) -> bool {
+    let Some(credentials) = req
+        .header("Proxy-Authorization")
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v.trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok())
+    else {
+        return false;
+    };
+    let Some((username, password)) = credentials.split_once(':') else {
+        return false;
+    };
+    // 用不短路的 & 同时比较用户名和密码，响应时间不泄露哪一部分错误
+    users.iter().any(|user| {
+        constant_time_eq(user.username.as_bytes(), username.as_bytes())
+            & constant_time_eq(user.password.as_bytes(), password.as_bytes())
+    })
+}
+
+/// 拆分 "host:port"，IPv6 地址带方括号，没有端口时使用默认端口
+fn split_authority(authority: &str, default_port: Option<u16>) -> Option<(&str, u16)> {
+    let port_start = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => Some(i),
+        _ => None,
+    };
+    let (host, port) = match port_start {
+        Some(i) => (&authority[..i], authority[i + 1..].parse().ok()?),
+        None => (authority, default_port?),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port))
+}
+
+fn is_allowed(allow: &[String], host: &str, port: u16) -> bool {
+    allow.iter().any(|rule| {
+        let (host_rule, port_rule) = match split_authority(rule, None) {
+            Some((host_rule, port_rule)) => (host_rule, Some(port_rule.to_string())),
+            // 端口写成 "*" 或省略时不限制端口
+            None => match rule.rsplit_once(':') {
+                Some((host_rule, "*")) => (host_rule, None),
+                _ => (rule.as_str(), None),
+            },
+        };
+        let port_matches = port_rule.is_none_or(|p| p == port.to_string());
+        // 主机名不区分大小写，规则和目标都转为小写再比较
+        let host_matches = glob::Pattern::new(&host_rule.to_ascii_lowercase())
+            .is_ok_and(|pattern| pattern.matches(&host.to_ascii_lowercase()));
+        port_matches && host_matches
+    })
+}
+
+/// 转发给目标服务器的请求，请求行改为 origin 形式
+fn build_request(req: &HttpRequest, authority: &str) -> Vec<u8> {
+    let mut target = utf8_percent_encode(&req.path, PATH_ENCODE_SET).to_string();
+    if !req.query.is_empty() {
+        target.push('?');
+        target.push_str(&req.query);
+    }
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, target);
+    let tokens = connection_tokens(req.header("Connection"));
+    for (key, val) in &req.headers {
+        let managed = ["Host", "Content-Length", "Via", "Proxy-Connection"]
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(key));
+        if managed || is_hop_by_hop(key, &tokens) {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", key, val));
+    }
+    head.push_str(&format!("Host: {}\r\n", authority));
+    head.push_str(&format!("Via: {}\r\n", append_via(req.header("Via"))));
+    if !req.body.is_empty() || req.header("Content-Length").is_some() {
+        head.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+    }
+    head.push_str("Connection: keep-alive\r\n\r\n");
+
+    let mut request = head.into_bytes();
+    request.extend_from_slice(&req.body);
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|rule| rule.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_authorities() {
+        assert_eq!(
+            split_authority("example.com:8080", None),
+            Some(("example.com", 8080))
+        );
+        assert_eq!(
+            split_authority("example.com", Some(80)),
+            Some(("example.com", 80))
+        );
+        assert_eq!(split_authority("example.com", None), None);
+        assert_eq!(split_authority("[::1]:443", None), Some(("[::1]", 443)));
+        assert_eq!(split_authority("[::1]", Some(80)), Some(("[::1]", 80)));
+        assert_eq!(split_authority(":80", None), None);
+        assert_eq!(split_authority("example.com:http", None), None);
+    }
+
+    #[test]
+    fn allows_listed_hosts_and_ports() {
+        let allow = rules(&["example.com:443", "*.example.org", "api.test:*"]);
+        assert!(is_allowed(&allow, "example.com", 443));
+        assert!(!is_allowed(&allow, "example.com", 80));
+        assert!(is_allowed(&allow, "www.example.org", 8080));
+        assert!(!is_allowed(&allow, "example.org.evil", 80));
+        assert!(is_allowed(&allow, "api.test", 9000));
+        assert!(!is_allowed(&allow, "other.test", 80));
+    }
+
+    #[test]
+    fn host_match_ignores_case() {
+        let allow = rules(&["*.example.org", "API.Example.COM:443"]);
+        assert!(is_allowed(&allow, "WWW.Example.ORG", 80));
+        assert!(is_allowed(&allow, "api.example.com", 443));
+    }
+
+    #[test]
+    fn empty_allow_list_allows_nothing() {
+        assert!(!is_allowed(&[], "example.com", 80));
+    }
+
+    #[test]
+    fn checks_proxy_credentials() {
+        let users = vec![ProxyUser {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        }];
+        let mut req = HttpRequest::new();
+        assert!(!is_authorized(&users, &req));
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        req.headers
+            .insert("Proxy-Authorization".to_string(), basic("alice:secret"));
+        assert!(is_authorized(&users, &req));
+        req.headers
+            .insert("Proxy-Authorization".to_string(), basic("alice:wrong"));
+        assert!(!is_authorized(&users, &req));
+        req.headers
+            .insert("Proxy-Authorization".to_string(), basic("alice"));
+        assert!(!is_authorized(&users, &req));
+    }
+}
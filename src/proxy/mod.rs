@@ -0,0 +1,498 @@
+mod balancer;
+mod breaker;
+mod forward;
+mod upstream;
+mod websocket;
+
+use crate::{
+    config::{self, prefix_matches, Config, ProxyRoute},
+    http::{BodyStream, BoxedReader, HttpRequest, HttpResponse},
+    ip_filter,
+};
+use balancer::{Selected, TrackedReader};
+pub use forward::{handle_forward_request, open_tunnel, splice};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::{error, info, warn, Instrument};
+use upstream::{UpstreamError, UpstreamOptions, UpstreamResponse, IDEMPOTENT_METHODS};
+pub use websocket::{close_websockets, handle_websocket};
+
+/// 逐跳头只对单个连接有效，代理不能转发（RFC 9110 第 7.6.1 节）
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// 转发路径时需要重新编码的字符，保留 '/'
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+const VIA: &str = concat!("1.1 multithreading_http_server/", env!("CARGO_PKG_VERSION"));
+
+/// 按最长前缀匹配代理路由
+pub fn find_route<'a>(path: &str, config: &'a Config) -> Option<&'a ProxyRoute> {
+    config
+        .proxy
+        .iter()
+        .filter(|route| prefix_matches(&route.prefix, path))
+        .max_by_key(|route| route.prefix.len())
+}
+
+/// 启动上游池和健康检查
+pub fn start(config: &Config) {
+    balancer::start(config);
+}
+
+/// 以 JSON 返回所有上游池的状态
+pub fn status_response() -> HttpResponse {
+    HttpResponse::ok().body("application/json", balancer::status().to_string())
+}
+
+pub async fn handle_proxy_request(route: &ProxyRoute, req: &HttpRequest) -> HttpResponse {
+    let pool = balancer::pool_for(route);
+    if let Some(mirror) = &route.mirror {
+        if pool.should_mirror(mirror.percent) {
+            tokio::spawn(
+                mirror_request(route.clone(), mirror.upstream.clone(), req.clone())
+                    .in_current_span(),
+            );
+        }
+    }
+
+    let retry = &route.retry;
+    let retryable = retry.attempts > 0 && IDEMPOTENT_METHODS.contains(&req.method.as_str());
+    if retryable {
+        pool.deposit_retry_budget();
+    }
+
+    let client = client_ip(req).await;
+    let mut attempt = 0;
+    loop {
+        let Some(server) = pool.select(req, client) else {
+            error!("No available upstream for {}", route.prefix);
+            return HttpResponse::service_unavailable();
+        };
+        let address = server.address().to_string();
+        info!("Proxying {} {} to {}", req.method, req.path, address);
+        let result = forward(route, &address, req).await;
+        match &result {
+            Ok(response) => server.report_response(response.code),
+            Err(_) => server.report_failure(),
+        }
+
+        let should_retry = match &result {
+            Ok(response) => retry.retry_on_status.contains(&response.code),
+            Err(UpstreamError::Connect(_) | UpstreamError::ConnectTimeout) => true,
+            Err(_) => false,
+        };
+        if retryable && should_retry && attempt < retry.attempts {
+            if pool.withdraw_retry_budget() {
+                let backoff = retry
+                    .backoff_ms
+                    .saturating_mul(1 << attempt.min(16))
+                    .min(retry.max_backoff_ms);
+                attempt += 1;
+                warn!(
+                    "Retrying {} {} after {}ms (attempt {}/{})",
+                    req.method, req.path, backoff, attempt, retry.attempts
+                );
+                drop(result);
+                drop(server);
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+                continue;
+            }
+            warn!("Retry budget of {} exhausted", route.prefix);
+        }
+
+        return match result {
+            Ok(response) => build_response(response, Some(server)),
+            Err(e) => error_response(&address, e),
+        };
+    }
+}
+
+/// 上游请求失败时返回给客户端的响应，超时为 504，其余为 502
+fn error_response(address: &str, e: UpstreamError) -> HttpResponse {
+    match e {
+        UpstreamError::ConnectTimeout => {
+            error!("Connecting to upstream {} timed out", address);
+            HttpResponse::gateway_timeout()
+        }
+        UpstreamError::Connect(e) => {
+            error!("Cannot connect to upstream {}: {}", address, e);
+            HttpResponse::bad_gateway()
+        }
+        UpstreamError::Io(e) => {
+            error!("Upstream {} failed: {}", address, e);
+            HttpResponse::bad_gateway()
+        }
+        UpstreamError::InvalidResponse => {
+            error!("Invalid response from upstream {}", address);
+            HttpResponse::bad_gateway()
+        }
+        UpstreamError::Timeout => {
+            error!("Upstream {} timed out", address);
+            HttpResponse::gateway_timeout()
+        }
+    }
+}
+
+/// 哈希策略使用的客户端地址，来自可信代理的请求取转发头中的地址
+async fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let config = config::read_config().await.ok()?;
+    ip_filter::client_ip(req, &config.ip_access)
+}
+
+/// 把请求发给一个上游，等待响应头
+async fn forward(
+    route: &ProxyRoute,
+    address: &str,
+    req: &HttpRequest,
+) -> Result<UpstreamResponse, UpstreamError> {
+    let request = build_upstream_request(route, address, req, None);
+    let options = UpstreamOptions {
+        connect_timeout: Duration::from_secs(route.connect_timeout_secs),
+        max_idle: route.max_idle,
+    };
+    let send = upstream::send(address, &request, &req.method, &options);
+    tokio::time::timeout(Duration::from_secs(route.timeout_secs), send)
+        .await
+        .unwrap_or(Err(UpstreamError::Timeout))
+}
+
+/// 把请求复制给影子上游，读完并丢弃响应
+async fn mirror_request(route: ProxyRoute, address: String, req: HttpRequest) {
+    match forward(&route, &address, &req).await {
+        Ok(response) => {
+            info!("Mirror {} responded {}", address, response.code);
+            if let Some(mut body) = response.body {
+                let _ = tokio::io::copy(&mut body.reader, &mut tokio::io::sink()).await;
+            }
+        }
+        Err(e) => warn!("Mirror request to {} failed: {:?}", address, e),
+    }
+}
+
+/// 认证子请求：带着原请求的请求头向 `path` 发送没有正文的 GET，
+/// 原来的方法和 URI 放在 X-Original-Method、X-Original-URI 中。
+/// 只返回状态码和响应头，连接失败或超时时返回 None
+pub async fn subrequest(
+    address: &str,
+    path: &str,
+    req: &HttpRequest,
+    timeout: Duration,
+) -> Option<(u16, Vec<(String, String)>)> {
+    let head = subrequest_head(address, path, req);
+
+    let options = UpstreamOptions {
+        connect_timeout: timeout,
+        max_idle: 8,
+    };
+    let send = upstream::send(address, head.as_bytes(), "GET", &options);
+    match tokio::time::timeout(timeout, send).await {
+        Ok(Ok(response)) => {
+            // 读完并丢弃响应体，连接才能放回连接池
+            if let Some(mut body) = response.body {
+                tokio::spawn(async move {
+                    let _ = tokio::io::copy(&mut body.reader, &mut tokio::io::sink()).await;
+                });
+            }
+            Some((response.code, response.headers))
+        }
+        Ok(Err(e)) => {
+            error!("Auth subrequest to {} failed: {:?}", address, e);
+            None
+        }
+        Err(_) => {
+            error!("Auth subrequest to {} timed out", address);
+            None
+        }
+    }
+}
+
+/// 子请求的请求行和请求头，没有正文
+fn subrequest_head(address: &str, path: &str, req: &HttpRequest) -> String {
+    let mut uri = utf8_percent_encode(&req.path, PATH_ENCODE_SET).to_string();
+    if !req.query.is_empty() {
+        uri.push('?');
+        uri.push_str(&req.query);
+    }
+    let mut head = format!("GET {} HTTP/1.1\r\n", path);
+    let tokens = connection_tokens(req.header("Connection"));
+    // 客户端发来的 X-Original-* 不能冒充原请求的方法和 URI
+    let managed = [
+        "Host",
+        "Content-Length",
+        "Content-Type",
+        "X-Original-Method",
+        "X-Original-URI",
+    ];
+    for (key, val) in &req.headers {
+        let managed = managed.iter().any(|h| h.eq_ignore_ascii_case(key));
+        if managed || is_hop_by_hop(key, &tokens) {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", key, val));
+    }
+    let host = if address.starts_with("unix:") {
+        "localhost"
+    } else {
+        address
+    };
+    head.push_str(&format!("Host: {}\r\n", host));
+    head.push_str(&format!("X-Original-Method: {}\r\n", req.method));
+    head.push_str(&format!("X-Original-URI: {}\r\n", uri));
+    if let Some(addr) = req.remote_addr {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", addr.ip()));
+    }
+    head.push_str("Connection: keep-alive\r\n\r\n");
+    head
+}
+
+fn is_hop_by_hop(name: &str, connection_tokens: &[String]) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+        || connection_tokens
+            .iter()
+            .any(|token| token.eq_ignore_ascii_case(name))
+}
+
+/// Connection 头中列出的字段同样是逐跳的
+fn connection_tokens(value: Option<&str>) -> Vec<String> {
+    value
+        .map(|v| v.split(',').map(|t| t.trim().to_string()).collect())
+        .unwrap_or_default()
+}
+
+fn upstream_target(route: &ProxyRoute, req: &HttpRequest) -> String {
+    let mut path = req.path.as_str();
+    if route.strip_prefix {
+        path = path
+            .strip_prefix(route.prefix.trim_end_matches('/'))
+            .unwrap_or(path);
+    }
+    let mut target = utf8_percent_encode(path, PATH_ENCODE_SET).to_string();
+    if !target.starts_with('/') {
+        target.insert(0, '/');
+    }
+    if !req.query.is_empty() {
+        target.push('?');
+        target.push_str(&req.query);
+    }
+    target
+}
+
+/// 构造发给上游的请求，`upgrade` 为要升级到的协议，此时保留 Upgrade 头而不复用连接
+fn build_upstream_request(
+    route: &ProxyRoute,
+    address: &str,
+    req: &HttpRequest,
+    upgrade: Option<&str>,
+) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\n",
+        req.method,
+        upstream_target(route, req)
+    );
+    let tokens = connection_tokens(req.header("Connection"));
+    let original_host = req.header("Host");
+
+    for (key, val) in &req.headers {
+        let managed = [
+            "Host",
+            "Content-Length",
+            "X-Forwarded-For",
+            "X-Forwarded-Proto",
+            "X-Forwarded-Host",
+            "Via",
+        ]
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(key));
+        if managed || is_hop_by_hop(key, &tokens) {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", key, val));
+    }
+
+    let host = match original_host {
+        Some(host) if route.preserve_host => host.to_string(),
+        // Unix 域套接字没有主机名
+        _ if address.starts_with("unix:") => "localhost".to_string(),
+        _ => address.to_string(),
+    };
+    head.push_str(&format!("Host: {}\r\n", host));
+
+    let client_ip = req.remote_addr.map(|addr| addr.ip().to_string());
+    let forwarded_for = match (req.header("X-Forwarded-For"), client_ip) {
+        (Some(prev), Some(ip)) => Some(format!("{}, {}", prev, ip)),
+        (Some(prev), None) => Some(prev.to_string()),
+        (None, ip) => ip,
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    let proto = if req.tls { "https" } else { "http" };
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\n", proto));
+    if let Some(host) = original_host {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    head.push_str(&format!("Via: {}\r\n", append_via(req.header("Via"))));
+    if !req.body.is_empty() || req.header("Content-Length").is_some() {
+        head.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+    }
+    match upgrade {
+        Some(protocol) => head.push_str(&format!(
+            "Upgrade: {}\r\nConnection: Upgrade\r\n\r\n",
+            protocol
+        )),
+        None => head.push_str("Connection: keep-alive\r\n\r\n"),
+    }
+
+    let mut request = head.into_bytes();
+    request.extend_from_slice(&req.body);
+    request
+}
+
+fn append_via(previous: Option<&str>) -> String {
+    match previous {
+        Some(prev) => format!("{}, {}", prev, VIA),
+        None => VIA.to_string(),
+    }
+}
+
+/// 把上游响应转换为发给客户端的响应，`server` 为负载均衡选中的上游，响应体读完前一直计入其并发数
+fn build_response(upstream: UpstreamResponse, server: Option<Selected>) -> HttpResponse {
+    let mut response = HttpResponse::from_status(upstream.code, &upstream.reason);
+    let find = |name: &str| {
+        upstream
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    };
+    let tokens = connection_tokens(find("Connection"));
+    let via = append_via(find("Via"));
+    let content_length = find("Content-Length").map(str::to_string);
+
+    // 同名的头合并为一个，以逗号分隔
+    let mut merged: Vec<(String, String)> = Vec::new();
+    for (key, val) in &upstream.headers {
+        let skip = ["Content-Length", "Via"]
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(key));
+        if skip || is_hop_by_hop(key, &tokens) {
+            continue;
+        }
+        match merged.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(val);
+            }
+            None => merged.push((key.clone(), val.clone())),
+        }
+    }
+    for (key, val) in &merged {
+        response = response.header(key, val);
+    }
+    response = response.header("Via", &via);
+
+    match upstream.body {
+        Some(body) => {
+            let reader: BoxedReader = match server {
+                Some(server) => Box::new(TrackedReader::new(body.reader, server)),
+                None => Box::new(body.reader),
+            };
+            match body.content_length {
+                Some(len) => response
+                    .header("Content-Length", &len.to_string())
+                    .stream(BodyStream::Sized(reader)),
+                None => response.stream(BodyStream::Chunked(reader)),
+            }
+        }
+        // HEAD 响应保留上游给出的长度
+        None => match content_length {
+            Some(len) => response.header("Content-Length", &len),
+            None => response,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn upstream_head(req: &HttpRequest) -> String {
+        let route: ProxyRoute =
+            serde_json::from_value(json!({ "prefix": "/api", "upstream": "127.0.0.1:9000" }))
+                .unwrap();
+        String::from_utf8(build_upstream_request(&route, "127.0.0.1:9000", req, None)).unwrap()
+    }
+
+    fn request() -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.method = "GET".to_string();
+        req.path = "/api/items".to_string();
+        req.remote_addr = Some("198.51.100.7:40000".parse().unwrap());
+        req.headers
+            .insert("Host".to_string(), "www.example.com".to_string());
+        req.headers
+            .insert("X-Forwarded-Proto".to_string(), "https".to_string());
+        req
+    }
+
+    #[test]
+    fn forwarded_proto_follows_the_client_connection() {
+        let mut req = request();
+        let head = upstream_head(&req);
+        assert!(head.contains("X-Forwarded-Proto: http\r\n"));
+        assert!(!head.contains("X-Forwarded-Proto: https"));
+
+        req.tls = true;
+        assert!(upstream_head(&req).contains("X-Forwarded-Proto: https\r\n"));
+    }
+
+    #[test]
+    fn forwarded_headers_are_rewritten() {
+        let mut req = request();
+        req.headers
+            .insert("X-Forwarded-For".to_string(), "203.0.113.1".to_string());
+        let head = upstream_head(&req);
+        assert!(head.contains("X-Forwarded-For: 203.0.113.1, 198.51.100.7\r\n"));
+        assert!(head.contains("X-Forwarded-Host: www.example.com\r\n"));
+        assert!(head.contains("Host: 127.0.0.1:9000\r\n"));
+    }
+
+    #[test]
+    fn subrequest_replaces_client_original_headers() {
+        let mut req = request();
+        req.method = "POST".to_string();
+        req.query = "id=1".to_string();
+        req.headers
+            .insert("x-original-uri".to_string(), "/public".to_string());
+        req.headers
+            .insert("X-ORIGINAL-METHOD".to_string(), "GET".to_string());
+        let head = subrequest_head("127.0.0.1:9001", "/auth", &req);
+        assert!(head.starts_with("GET /auth HTTP/1.1\r\n"));
+        assert!(head.contains("X-Original-Method: POST\r\n"));
+        assert!(head.contains("X-Original-URI: /api/items?id=1\r\n"));
+        assert_eq!(head.to_ascii_lowercase().matches("x-original-").count(), 2);
+    }
+}
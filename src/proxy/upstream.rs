@@ -0,0 +1,443 @@
+//! 上游 HTTP/1.1 客户端，keep-alive 连接按地址放入连接池复用
+
+use crate::backend::BackendStream;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+};
+use tracing::{warn, Instrument};
+
+/// 上游响应头的最大长度
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// 重试时可以安全重发的方法
+pub const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+pub type Connection = BufReader<BackendStream>;
+
+// 按上游地址保存的空闲连接
+static POOL: LazyLock<Mutex<HashMap<String, Vec<Connection>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub struct UpstreamResponse {
+    pub code: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// 响应体，None 表示没有响应体（HEAD、204、304 等）
+    pub body: Option<UpstreamBody>,
+}
+
+pub struct UpstreamBody {
+    /// 已知的响应体长度，None 表示 chunked 或读到连接关闭为止
+    pub content_length: Option<u64>,
+    /// 已去掉传输编码的响应体
+    pub reader: DuplexStream,
+}
+
+/// 升级请求的结果
+pub enum Upgrade {
+    /// 上游返回 101，之后连接上是升级后的协议
+    Switched {
+        reason: String,
+        headers: Vec<(String, String)>,
+        conn: Connection,
+    },
+    Response(UpstreamResponse),
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Connect(io::Error),
+    ConnectTimeout,
+    Io(io::Error),
+    InvalidResponse,
+    /// 等待响应头超时
+    Timeout,
+}
+
+/// 上游连接的设置
+pub struct UpstreamOptions {
+    pub connect_timeout: Duration,
+    pub max_idle: usize,
+}
+
+/// 响应体的分帧方式
+#[derive(Clone, Copy, PartialEq)]
+enum Framing {
+    None,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// 发送一个完整的请求（请求头和请求体），`method` 是请求的方法
+pub async fn send(
+    address: &str,
+    request: &[u8],
+    method: &str,
+    options: &UpstreamOptions,
+) -> Result<UpstreamResponse, UpstreamError> {
+    let (conn, reused) = match take_idle(address) {
+        Some(conn) => (conn, true),
+        None => (connect(address, options).await?, false),
+    };
+
+    let (conn, code, reason, headers) = match exchange(conn, request).await {
+        Ok(result) => result,
+        // 空闲连接可能已经被上游关闭，此时换新连接重试一次。
+        // 无法确定上游是否已经处理了请求，所以只重发幂等的请求
+        Err((e, stale)) if reused && stale && IDEMPOTENT_METHODS.contains(&method) => {
+            warn!(
+                "Pooled upstream connection to {} was closed: {}",
+                address, e
+            );
+            let conn = connect(address, options).await?;
+            exchange(conn, request)
+                .await
+                .map_err(|(e, _)| UpstreamError::Io(e))?
+        }
+        Err((e, _)) => return Err(UpstreamError::Io(e)),
+    };
+
+    let head_request = method == "HEAD";
+    into_response(address, conn, code, reason, headers, head_request, options)
+}
+
+/// 发送带 Upgrade 头的请求，上游同意升级时返回 101 的响应头和连接本身，
+/// 否则按普通响应返回。升级用的连接不从连接池取，也不放回连接池
+pub async fn upgrade(
+    address: &str,
+    request: &[u8],
+    options: &UpstreamOptions,
+) -> Result<Upgrade, UpstreamError> {
+    let conn = connect(address, options).await?;
+    let (conn, code, reason, headers) = exchange(conn, request)
+        .await
+        .map_err(|(e, _)| UpstreamError::Io(e))?;
+    if code == 101 {
+        return Ok(Upgrade::Switched {
+            reason,
+            headers,
+            conn,
+        });
+    }
+    into_response(address, conn, code, reason, headers, false, options).map(Upgrade::Response)
+}
+
+/// 读完响应头之后，按分帧方式启动响应体的转发
+fn into_response(
+    address: &str,
+    conn: Connection,
+    code: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    head_request: bool,
+    options: &UpstreamOptions,
+) -> Result<UpstreamResponse, UpstreamError> {
+    let find = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    };
+    let framing = if head_request || code < 200 || code == 204 || code == 304 {
+        Framing::None
+    } else if find("Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked"))
+    {
+        Framing::Chunked
+    } else if let Some(len) = find("Content-Length") {
+        Framing::Length(
+            len.trim()
+                .parse()
+                .map_err(|_| UpstreamError::InvalidResponse)?,
+        )
+    } else {
+        Framing::UntilClose
+    };
+    let keep_alive = !find("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+
+    if framing == Framing::None {
+        if keep_alive {
+            put_idle(address, conn, options.max_idle);
+        }
+        return Ok(UpstreamResponse {
+            code,
+            reason,
+            headers,
+            body: None,
+        });
+    }
+
+    let content_length = match framing {
+        Framing::Length(len) => Some(len),
+        _ => None,
+    };
+    let (output, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(
+        pump_body(
+            address.to_string(),
+            conn,
+            output,
+            framing,
+            keep_alive,
+            options.max_idle,
+        )
+        .in_current_span(),
+    );
+    Ok(UpstreamResponse {
+        code,
+        reason,
+        headers,
+        body: Some(UpstreamBody {
+            content_length,
+            reader,
+        }),
+    })
+}
+
+/// 主动健康检查：新建连接发送 GET，响应 2xx/3xx 视为健康
+pub async fn probe(address: &str, path: &str, timeout: Duration) -> bool {
+    let host = if address.starts_with("unix:") {
+        "localhost"
+    } else {
+        address
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    let check = async {
+        let mut conn = BufReader::new(BackendStream::connect(address).await?);
+        write_request(conn.get_mut(), request.as_bytes()).await?;
+        let mut line = String::new();
+        conn.read_line(&mut line).await?;
+        let (code, _) = parse_status_line(&line).ok_or_else(invalid_data)?;
+        Ok::<_, io::Error>(code)
+    };
+    matches!(
+        tokio::time::timeout(timeout, check).await,
+        Ok(Ok(code)) if (200..400).contains(&code)
+    )
+}
+
+async fn connect(address: &str, options: &UpstreamOptions) -> Result<Connection, UpstreamError> {
+    match tokio::time::timeout(options.connect_timeout, BackendStream::connect(address)).await {
+        Ok(Ok(stream)) => Ok(BufReader::new(stream)),
+        Ok(Err(e)) => Err(UpstreamError::Connect(e)),
+        Err(_) => Err(UpstreamError::ConnectTimeout),
+    }
+}
+
+fn take_idle(address: &str) -> Option<Connection> {
+    POOL.lock().unwrap().get_mut(address)?.pop()
+}
+
+fn put_idle(address: &str, conn: Connection, max_idle: usize) {
+    let mut pool = POOL.lock().unwrap();
+    let idle = pool.entry(address.to_string()).or_default();
+    if idle.len() < max_idle {
+        idle.push(conn);
+    }
+}
+
+/// 发送请求并读取响应头，出错时第二个值表示上游是否还没有开始响应
+#[allow(clippy::type_complexity)]
+async fn exchange(
+    mut conn: Connection,
+    request: &[u8],
+) -> Result<(Connection, u16, String, Vec<(String, String)>), (io::Error, bool)> {
+    if let Err(e) = write_request(conn.get_mut(), request).await {
+        return Err((e, true));
+    }
+    loop {
+        let mut line = String::new();
+        match conn.read_line(&mut line).await {
+            Ok(0) => {
+                return Err((io::Error::from(io::ErrorKind::UnexpectedEof), true));
+            }
+            Ok(_) => {}
+            Err(e) => return Err((e, false)),
+        }
+        let (code, reason) = parse_status_line(&line).ok_or((invalid_data(), false))?;
+        let headers = read_headers(&mut conn).await.map_err(|e| (e, false))?;
+        // 跳过 100 Continue 等中间响应
+        if (100..200).contains(&code) && code != 101 {
+            continue;
+        }
+        return Ok((conn, code, reason, headers));
+    }
+}
+
+async fn write_request<W>(writer: &mut W, request: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(request).await?;
+    writer.flush().await
+}
+
+fn parse_status_line(line: &str) -> Option<(u16, String)> {
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let version = parts.next()?;
+    if !version.starts_with("HTTP/1.") {
+        return None;
+    }
+    let code = parts.next()?.parse().ok()?;
+    let reason = parts.next().unwrap_or("").to_string();
+    Some((code, reason))
+}
+
+async fn read_headers<R>(reader: &mut R) -> io::Result<Vec<(String, String)>>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut headers = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).await?;
+        total += n;
+        if n == 0 || total > MAX_HEAD_SIZE {
+            return Err(invalid_data());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let (key, val) = line.split_once(':').ok_or_else(invalid_data)?;
+        headers.push((key.trim().to_string(), val.trim().to_string()));
+    }
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid upstream response")
+}
+
+/// 把响应体去掉传输编码后写入 output，完整读完后连接放回连接池
+async fn pump_body(
+    address: String,
+    mut conn: Connection,
+    mut output: DuplexStream,
+    framing: Framing,
+    keep_alive: bool,
+    max_idle: usize,
+) {
+    let result = match framing {
+        Framing::Length(len) => copy_exact(&mut conn, &mut output, len).await,
+        Framing::Chunked => copy_chunked(&mut conn, &mut output).await,
+        Framing::UntilClose | Framing::None => {
+            tokio::io::copy(&mut conn, &mut output).await.map(|_| ())
+        }
+    };
+    match result {
+        Ok(()) if keep_alive && framing != Framing::UntilClose => {
+            put_idle(&address, conn, max_idle)
+        }
+        Ok(()) => {}
+        Err(e) => warn!("Failed to relay upstream body from {}: {}", address, e),
+    }
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, len: u64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(&mut reader.take(len), writer).await?;
+    if copied < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+async fn copy_chunked<W>(conn: &mut Connection, writer: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        // 忽略 chunk 扩展
+        let size = line.trim().split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid_data())?;
+        if size == 0 {
+            // 读掉可能存在的 trailer
+            read_headers(conn).await?;
+            return Ok(());
+        }
+        copy_exact(conn, writer, size).await?;
+        line.clear();
+        conn.read_line(&mut line).await?;
+        if !line.trim().is_empty() {
+            return Err(invalid_data());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// 每条连接只回答一个请求就关闭，连接池里留下的都是已经失效的连接
+    async fn one_shot_upstream() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+            }
+        });
+        (address, accepted)
+    }
+
+    async fn send_to(address: &str, method: &str) -> Result<UpstreamResponse, UpstreamError> {
+        let request = format!(
+            "{} / HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n",
+            method
+        );
+        let options = UpstreamOptions {
+            connect_timeout: Duration::from_secs(1),
+            max_idle: 1,
+        };
+        let response = send(address, request.as_bytes(), method, &options).await;
+        // 等上游关闭连接，让池中的连接失效
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn stale_connection_is_retried_only_for_idempotent_methods() {
+        let (address, accepted) = one_shot_upstream().await;
+        assert_eq!(send_to(&address, "GET").await.unwrap().code, 204);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // 失效的连接上发出的 POST 可能已经被处理，不能重发
+        assert!(send_to(&address, "POST").await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        assert_eq!(send_to(&address, "GET").await.unwrap().code, 204);
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        // GET 遇到失效的连接时换新连接重发
+        assert_eq!(send_to(&address, "GET").await.unwrap().code, 204);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+}
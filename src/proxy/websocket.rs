@@ -0,0 +1,343 @@
+//! WebSocket 反向代理：把升级请求转发给上游，收到 101 后在两端之间双向转发字节
+//!
+//! 转发时跟踪帧边界，空闲超时或服务器关闭时等当前帧转发完，再向两端发送关闭帧。
+
+use super::balancer;
+use super::upstream::{self, Connection, Upgrade, UpstreamError, UpstreamOptions};
+use super::{append_via, build_response, build_upstream_request, client_ip, error_response};
+use crate::{
+    config::ProxyRoute,
+    http::{HttpRequest, HttpResponse},
+    shutdown,
+    websocket::{CLOSE_GOING_AWAY, CLOSE_NORMAL},
+};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{watch, Notify};
+use tracing::{error, info, warn};
+
+/// 要求关闭后，等待两个方向转发完当前帧的最长时间
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 打开的隧道数，服务器关闭时等待它归零
+static OPEN_TUNNELS: AtomicUsize = AtomicUsize::new(0);
+static TUNNEL_CLOSED: Notify = Notify::const_new();
+
+/// 处理发往代理路由的 WebSocket 升级请求，返回时隧道已经关闭
+pub async fn handle_websocket<R, W>(
+    route: &ProxyRoute,
+    req: &HttpRequest,
+    client_reader: &mut R,
+    client_writer: &mut W,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let pool = balancer::pool_for(route);
+    let Some(server) = pool.select(req, client_ip(req).await) else {
+        error!("No available upstream for {}", route.prefix);
+        write_response(HttpResponse::service_unavailable(), client_writer).await;
+        return;
+    };
+    let address = server.address().to_string();
+    info!("Proxying WebSocket {} to {}", req.path, address);
+
+    let protocol = req.header("Upgrade").unwrap_or("websocket");
+    let request = build_upstream_request(route, &address, req, Some(protocol));
+    let options = UpstreamOptions {
+        connect_timeout: Duration::from_secs(route.connect_timeout_secs),
+        max_idle: route.max_idle,
+    };
+    let upgrade = upstream::upgrade(&address, &request, &options);
+    let result = tokio::time::timeout(Duration::from_secs(route.timeout_secs), upgrade)
+        .await
+        .unwrap_or(Err(UpstreamError::Timeout));
+    let (reason, headers, conn) = match result {
+        Ok(Upgrade::Switched {
+            reason,
+            headers,
+            conn,
+        }) => {
+            server.report_response(101);
+            (reason, headers, conn)
+        }
+        // 上游拒绝升级，把它的响应原样返回
+        Ok(Upgrade::Response(response)) => {
+            server.report_response(response.code);
+            warn!("Upstream {} refused WebSocket upgrade", address);
+            write_response(build_response(response, Some(server)), client_writer).await;
+            return;
+        }
+        Err(e) => {
+            server.report_failure();
+            write_response(error_response(&address, e), client_writer).await;
+            return;
+        }
+    };
+
+    let mut head = format!("HTTP/1.1 101 {}\r\n", reason);
+    let mut via = None;
+    for (key, val) in &headers {
+        if key.eq_ignore_ascii_case("Via") {
+            via = Some(val.as_str());
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", key, val));
+    }
+    head.push_str(&format!("Via: {}\r\n\r\n", append_via(via)));
+    if let Err(e) = client_writer.write_all(head.as_bytes()).await {
+        warn!("Failed to relay WebSocket handshake: {}", e);
+        return;
+    }
+
+    server.websocket_opened();
+    let open = OPEN_TUNNELS.fetch_add(1, Ordering::Relaxed) + 1;
+    info!("WebSocket tunnel to {} opened ({} open)", address, open);
+    let idle_timeout = Duration::from_secs(route.websocket_idle_timeout_secs);
+    tunnel(client_reader, client_writer, conn, idle_timeout).await;
+    server.websocket_closed();
+    let open = OPEN_TUNNELS.fetch_sub(1, Ordering::Relaxed) - 1;
+    info!("WebSocket tunnel to {} closed ({} open)", address, open);
+    TUNNEL_CLOSED.notify_waiters();
+}
+
+/// 服务器关闭时调用，等待所有隧道关闭，最多等待 `limit`
+pub async fn close_websockets(limit: Duration) {
+    let wait = async {
+        loop {
+            // 先注册再检查计数，避免错过通知
+            let closed = TUNNEL_CLOSED.notified();
+            let open = OPEN_TUNNELS.load(Ordering::Relaxed);
+            if open == 0 {
+                return;
+            }
+            info!("Waiting for {} WebSocket tunnels to close", open);
+            closed.await;
+        }
+    };
+    if tokio::time::timeout(limit, wait).await.is_err() {
+        warn!(
+            "{} WebSocket tunnels still open at shutdown",
+            OPEN_TUNNELS.load(Ordering::Relaxed)
+        );
+    }
+}
+
+async fn write_response<W>(response: HttpResponse, writer: &mut W)
+where
+    W: AsyncWrite + Unpin,
+{
+    if let Err(e) = response.write_to(writer).await {
+        warn!("Failed to write response: {}", e);
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// 双向转发直到两个方向都结束，空闲超时或服务器关闭时发送关闭帧
+async fn tunnel<R, W>(
+    client_reader: &mut R,
+    client_writer: &mut W,
+    upstream: Connection,
+    idle_timeout: Duration,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut upstream_reader, mut upstream_writer) = tokio::io::split(upstream);
+    let activity = Activity::new();
+    // 要求两个方向结束，值为发送给两端的关闭码
+    let (stop_tx, stop_rx) = watch::channel(None::<u16>);
+
+    let relays = async {
+        tokio::join!(
+            // 代理对上游来说是客户端，发给上游的关闭帧要加掩码
+            relay(
+                client_reader,
+                &mut upstream_writer,
+                true,
+                &activity,
+                stop_rx.clone()
+            ),
+            relay(
+                &mut upstream_reader,
+                client_writer,
+                false,
+                &activity,
+                stop_rx
+            ),
+        )
+    };
+    let watchdog = async {
+        let mut shutting_down = shutdown::subscribe();
+        loop {
+            let idle_for = activity.idle_for();
+            if idle_for >= idle_timeout {
+                info!("WebSocket tunnel idle for {}s", idle_for.as_secs());
+                stop_tx.send_replace(Some(CLOSE_NORMAL));
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(idle_timeout - idle_for) => {}
+                _ = shutting_down.wait_for(|down| *down) => {
+                    info!("Closing WebSocket tunnel for shutdown");
+                    stop_tx.send_replace(Some(CLOSE_GOING_AWAY));
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(DRAIN_TIMEOUT).await;
+    };
+
+    tokio::select! {
+        (sent, received) = relays => match (sent, received) {
+            (Ok(sent), Ok(received)) => info!(
+                "WebSocket tunnel finished, {} bytes sent, {} bytes received",
+                sent, received
+            ),
+            (Err(e), _) | (_, Err(e)) => warn!("WebSocket tunnel failed: {}", e),
+        },
+        _ = watchdog => warn!("WebSocket tunnel did not finish its frames in time"),
+    }
+}
+
+/// 单向转发，读到 EOF 时关闭对端的写方向；收到停止要求后转发完当前帧，再发送关闭帧
+async fn relay<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    masked: bool,
+    activity: &Activity,
+    mut stop: watch::Receiver<Option<u16>>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut frames = FrameTracker::default();
+    let mut total = 0;
+    loop {
+        let code = *stop.borrow();
+        let limit = match code {
+            Some(code) if frames.at_boundary() => {
+                writer.write_all(&close_frame(code, masked)).await?;
+                break;
+            }
+            // 不读过当前帧的结尾
+            Some(_) => frames.bytes_to_boundary().min(buf.len()),
+            None => buf.len(),
+        };
+        let n = tokio::select! {
+            n = reader.read(&mut buf[..limit]) => n?,
+            _ = stop.changed(), if code.is_none() => continue,
+        };
+        if n == 0 {
+            break;
+        }
+        frames.advance(&buf[..n]);
+        writer.write_all(&buf[..n]).await?;
+        activity.touch();
+        total += n as u64;
+    }
+    let _ = writer.shutdown().await;
+    Ok(total)
+}
+
+/// 关闭帧，负载只有关闭码
+fn close_frame(code: u16, masked: bool) -> Vec<u8> {
+    let [high, low] = code.to_be_bytes();
+    if masked {
+        // 全零的掩码不改变负载
+        vec![0x88, 0x82, 0, 0, 0, 0, high, low]
+    } else {
+        vec![0x88, 0x02, high, low]
+    }
+}
+
+/// 最后一次转发数据的时间
+struct Activity {
+    started: Instant,
+    last_millis: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last_millis: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_millis.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_millis.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+/// 跟踪字节流中 WebSocket 帧的边界
+#[derive(Default)]
+struct FrameTracker {
+    /// 已读到的当前帧头
+    header: Vec<u8>,
+    /// 当前帧剩余的负载字节数
+    remaining: u64,
+}
+
+impl FrameTracker {
+    fn advance(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len() as u64);
+                self.remaining -= n;
+                data = &data[n as usize..];
+                continue;
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            if self.header.len() == header_len(&self.header) {
+                self.remaining = payload_len(&self.header);
+                self.header.clear();
+            }
+        }
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.remaining == 0 && self.header.is_empty()
+    }
+
+    /// 到当前帧结尾（或帧头中已知部分结尾）还需要的字节数
+    fn bytes_to_boundary(&self) -> usize {
+        if self.remaining > 0 {
+            return self.remaining.min(usize::MAX as u64) as usize;
+        }
+        header_len(&self.header) - self.header.len()
+    }
+}
+
+/// 帧头的长度，只读到第一个字节时按最短的 2 字节计算
+fn header_len(header: &[u8]) -> usize {
+    let Some(second) = header.get(1) else {
+        return 2;
+    };
+    let extended = match second & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if second & 0x80 != 0 { 4 } else { 0 };
+    2 + extended + mask
+}
+
+fn payload_len(header: &[u8]) -> u64 {
+    match header[1] & 0x7f {
+        126 => u16::from_be_bytes([header[2], header[3]]) as u64,
+        127 => u64::from_be_bytes(header[2..10].try_into().unwrap()),
+        len => len as u64,
+    }
+}
@@ -0,0 +1,434 @@
+//! nginx 风格的重写规则：按顺序匹配路径、方法、主机、请求头和查询字符串，
+//! 然后在内部改写路径、返回重定向或直接返回状态码
+
+use crate::{
+    config::{self, RewriteFlag, RewriteRule},
+    http::{normalize_path, HttpRequest, HttpResponse},
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+use tracing::{info, warn};
+
+/// `last` 最多重新开始的次数，超过时认为规则循环
+const MAX_RESTARTS: usize = 10;
+
+/// Location 中必须编码的字符，其余保持原样
+const LOCATION: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
+
+// 编译过的正则，无效的正则记为 None，只报告一次
+static REGEXES: LazyLock<RwLock<HashMap<String, Option<Regex>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+pub enum Outcome {
+    /// 没有规则改变请求
+    Unchanged,
+    /// 路径被改写，用新的请求继续处理
+    Rewritten(HttpRequest),
+    /// 直接返回的响应（重定向或固定状态码）
+    Respond(HttpResponse),
+}
+
+/// 对请求执行规则，命中的规则记录到日志
+pub fn apply(req: &HttpRequest, rules: &[RewriteRule]) -> Outcome {
+    evaluate(req, rules, &mut |line| info!("Rewrite {}", line))
+}
+
+/// 执行规则，`trace` 收到每条命中规则的说明
+fn evaluate(req: &HttpRequest, rules: &[RewriteRule], trace: &mut dyn FnMut(String)) -> Outcome {
+    let mut current = req.clone();
+    let mut changed = false;
+    let mut restarts = 0;
+    'restart: loop {
+        for (index, rule) in rules.iter().enumerate() {
+            let number = index + 1;
+            let captures = match &rule.path {
+                Some(pattern) => match regex(pattern).and_then(|re| re.captures(&current.path)) {
+                    Some(captures) => Some(captures),
+                    None => continue,
+                },
+                None => None,
+            };
+            if !conditions_match(rule, &current) {
+                continue;
+            }
+
+            if let Some(target) = &rule.redirect {
+                let (path, query) = split_target(&expand(target, &captures), &current.query);
+                let mut location = utf8_percent_encode(&path, LOCATION).to_string();
+                if !query.is_empty() {
+                    location.push('?');
+                    location.push_str(&query);
+                }
+                let status = match rule.status {
+                    Some(code @ (301 | 302 | 307 | 308)) => code,
+                    Some(code) => {
+                        warn!(
+                            "Rule {}: {} is not a redirect status, using 302",
+                            number, code
+                        );
+                        302
+                    }
+                    None => 302,
+                };
+                trace(format!(
+                    "rule {}: redirect {} to {}",
+                    number, status, location
+                ));
+                return Outcome::Respond(
+                    HttpResponse::from_status(status, "").header("Location", &location),
+                );
+            }
+
+            if let Some(target) = &rule.rewrite {
+                let (path, query) = split_target(&expand(target, &captures), &current.query);
+                trace(format!(
+                    "rule {}: rewrite {} to {}{}{}",
+                    number,
+                    current.path,
+                    path,
+                    if query.is_empty() { "" } else { "?" },
+                    query
+                ));
+                // 捕获组拼出的路径同样不能带 "//"、"." 和 ".."
+                current.path = normalize_path(&path);
+                current.query = query;
+                changed = true;
+                match rule.flag {
+                    Some(RewriteFlag::Last) => {
+                        restarts += 1;
+                        if restarts > MAX_RESTARTS {
+                            trace(format!("rule {}: rewrite cycle, giving up", number));
+                            return Outcome::Respond(HttpResponse::internal_server_error());
+                        }
+                        continue 'restart;
+                    }
+                    Some(RewriteFlag::Break) => break 'restart,
+                    None => continue,
+                }
+            }
+
+            if let Some(status) = rule.status {
+                trace(format!("rule {}: return {}", number, status));
+                return Outcome::Respond(HttpResponse::from_status(status, ""));
+            }
+            trace(format!("rule {}: matched without an action", number));
+        }
+        break;
+    }
+    if changed {
+        Outcome::Rewritten(current)
+    } else {
+        Outcome::Unchanged
+    }
+}
+
+/// 路径以外的条件
+fn conditions_match(rule: &RewriteRule, req: &HttpRequest) -> bool {
+    if !rule.methods.is_empty()
+        && !rule
+            .methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(&req.method))
+    {
+        return false;
+    }
+    if let Some(pattern) = &rule.host {
+        let host = config::host_name(req.header("Host").unwrap_or(""));
+        if !regex(pattern).is_some_and(|re| re.is_match(&host)) {
+            return false;
+        }
+    }
+    let headers_match = rule.headers.iter().all(|(name, pattern)| {
+        req.header(name)
+            .is_some_and(|val| regex(pattern).is_some_and(|re| re.is_match(val)))
+    });
+    if !headers_match {
+        return false;
+    }
+    match &rule.query {
+        Some(pattern) => regex(pattern).is_some_and(|re| re.is_match(&req.query)),
+        None => true,
+    }
+}
+
+fn regex(pattern: &str) -> Option<Regex> {
+    if let Some(re) = REGEXES.read().unwrap().get(pattern) {
+        return re.clone();
+    }
+    let re = match Regex::new(pattern) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            warn!("Invalid rewrite pattern {:?}: {}", pattern, e);
+            None
+        }
+    };
+    REGEXES
+        .write()
+        .unwrap()
+        .insert(pattern.to_string(), re.clone());
+    re
+}
+
+/// 用路径正则的捕获组替换 $1、${name}
+fn expand(template: &str, captures: &Option<Captures>) -> String {
+    match captures {
+        Some(captures) => {
+            let mut out = String::new();
+            captures.expand(template, &mut out);
+            out
+        }
+        None => template.to_string(),
+    }
+}
+
+/// 拆分目标中的路径和查询字符串。目标带查询参数时原来的参数接在后面，
+/// 以 "?" 结尾时丢弃原来的参数，不带 "?" 时保留原来的参数
+fn split_target(target: &str, original_query: &str) -> (String, String) {
+    match target.split_once('?') {
+        Some((path, "")) => (path.to_string(), String::new()),
+        Some((path, query)) if !original_query.is_empty() => {
+            (path.to_string(), format!("{}&{}", query, original_query))
+        }
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (target.to_string(), original_query.to_string()),
+    }
+}
+
+/// 命令行的 dry-run：`rewrite-test [-X 方法] [-H "名称: 值"]... URL`，
+/// 显示 URL 命中的规则和最终结果，返回进程退出码
+pub async fn dry_run(args: &[String]) -> i32 {
+    let usage = "usage: rewrite-test [-X METHOD] [-H \"Name: value\"]... URL";
+    let mut req = HttpRequest::new();
+    req.method = "GET".to_string();
+    req.version = "HTTP/1.1".to_string();
+    let mut url = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-X" => match args.next() {
+                Some(method) => req.method = method.to_ascii_uppercase(),
+                None => {
+                    eprintln!("{}", usage);
+                    return 2;
+                }
+            },
+            "-H" => match args.next().and_then(|h| h.split_once(':')) {
+                Some((name, val)) => {
+                    req.headers
+                        .insert(name.trim().to_string(), val.trim().to_string());
+                }
+                None => {
+                    eprintln!("{}", usage);
+                    return 2;
+                }
+            },
+            _ => url = Some(arg.as_str()),
+        }
+    }
+    let Some(url) = url else {
+        eprintln!("{}", usage);
+        return 2;
+    };
+
+    // 完整的 URL 中的主机作为 Host 头
+    let target = match url.split_once("://") {
+        Some((_, rest)) => {
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            req.headers
+                .insert("Host".to_string(), rest[..end].to_string());
+            &rest[end..]
+        }
+        None => url,
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = if path.is_empty() { "/" } else { path };
+    req.path = normalize_path(&percent_decode_str(path).decode_utf8_lossy());
+    req.query = query.to_string();
+
+    let config = match config::for_request(&req).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Cannot read config: {:?}", e);
+            return 1;
+        }
+    };
+    println!(
+        "{} {}{}{} (Host: {})",
+        req.method,
+        req.path,
+        if req.query.is_empty() { "" } else { "?" },
+        req.query,
+        req.header("Host").unwrap_or("-")
+    );
+    let mut hits = 0;
+    let outcome = evaluate(&req, &config.rewrites, &mut |line| {
+        hits += 1;
+        println!("  {}", line);
+    });
+    if hits == 0 {
+        println!("  no rule matched");
+    }
+    match outcome {
+        Outcome::Unchanged => println!("result: served as {}", req.path),
+        Outcome::Rewritten(new) => println!(
+            "result: served as {}{}{}",
+            new.path,
+            if new.query.is_empty() { "" } else { "?" },
+            new.query
+        ),
+        Outcome::Respond(response) => {
+            let location = response
+                .headers
+                .get("Location")
+                .map_or(String::new(), |l| format!(", Location: {}", l));
+            println!("result: {} {}{}", response.code, response.reason, location);
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn rules(rules: Value) -> Vec<RewriteRule> {
+        serde_json::from_value(rules).unwrap()
+    }
+
+    fn request(method: &str, target: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.method = method.to_string();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        req.path = path.to_string();
+        req.query = query.to_string();
+        req.headers
+            .insert("Host".to_string(), "www.example.com:8080".to_string());
+        req
+    }
+
+    /// 结果的简短描述："unchanged"、"rewrite /路径?查询" 或 "状态码 Location"
+    fn run(rules: &[RewriteRule], req: &HttpRequest) -> String {
+        match evaluate(req, rules, &mut |_| {}) {
+            Outcome::Unchanged => "unchanged".to_string(),
+            Outcome::Rewritten(new) if new.query.is_empty() => format!("rewrite {}", new.path),
+            Outcome::Rewritten(new) => format!("rewrite {}?{}", new.path, new.query),
+            Outcome::Respond(response) => match response.headers.get("Location") {
+                Some(location) => format!("{} {}", response.code, location),
+                None => response.code,
+            },
+        }
+    }
+
+    #[test]
+    fn rewrites_with_named_and_numbered_captures() {
+        let rules = rules(json!([
+            { "path": "^/blog/(?P<slug>[^/]+)$", "rewrite": "/blog.html?slug=${slug}" },
+            { "path": "^/u/(\\d+)$", "rewrite": "/user.cgi?id=$1" }
+        ]));
+        assert_eq!(
+            run(&rules, &request("GET", "/blog/hello")),
+            "rewrite /blog.html?slug=hello"
+        );
+        assert_eq!(
+            run(&rules, &request("GET", "/u/42?tab=posts")),
+            "rewrite /user.cgi?id=42&tab=posts"
+        );
+        assert_eq!(run(&rules, &request("GET", "/u/x")), "unchanged");
+    }
+
+    #[test]
+    fn trailing_question_mark_drops_the_query() {
+        let rules = rules(json!([{ "path": "^/old$", "rewrite": "/new?" }]));
+        assert_eq!(run(&rules, &request("GET", "/old?a=1")), "rewrite /new");
+        let rules = self::rules(json!([{ "path": "^/old$", "rewrite": "/new" }]));
+        assert_eq!(run(&rules, &request("GET", "/old?a=1")), "rewrite /new?a=1");
+    }
+
+    #[test]
+    fn last_restarts_and_break_stops() {
+        let rules = rules(json!([
+            { "path": "^/b$", "rewrite": "/c" },
+            { "path": "^/a$", "rewrite": "/b", "flag": "last" },
+            { "path": "^/c$", "rewrite": "/d", "flag": "break" },
+            { "path": "^/d$", "status": 410 }
+        ]));
+        assert_eq!(run(&rules, &request("GET", "/a")), "rewrite /d");
+        assert_eq!(run(&rules, &request("GET", "/d")), "410");
+    }
+
+    #[test]
+    fn last_cycle_gives_500() {
+        let rules = rules(json!([
+            { "path": "^/a$", "rewrite": "/b", "flag": "last" },
+            { "path": "^/b$", "rewrite": "/a", "flag": "last" }
+        ]));
+        assert_eq!(run(&rules, &request("GET", "/a")), "500");
+    }
+
+    #[test]
+    fn redirects_keep_the_query_and_default_to_302() {
+        let rules = rules(json!([
+            { "path": "^/docs/(.*)$", "redirect": "https://docs.example.com/$1", "status": 301 },
+            { "path": "^/go$", "redirect": "/there" },
+            { "path": "^/bad$", "redirect": "/there", "status": 200 }
+        ]));
+        assert_eq!(
+            run(&rules, &request("GET", "/docs/a b?x=1")),
+            "301 https://docs.example.com/a%20b?x=1"
+        );
+        assert_eq!(run(&rules, &request("GET", "/go")), "302 /there");
+        assert_eq!(run(&rules, &request("GET", "/bad")), "302 /there");
+    }
+
+    #[test]
+    fn conditions_must_all_match() {
+        let rules = rules(json!([{
+            "path": "^/api/",
+            "methods": ["post"],
+            "host": "^www\\.example\\.com$",
+            "headers": { "X-Debug": "^1$" },
+            "query": "(^|&)v=2",
+            "status": 403
+        }]));
+        let mut req = request("POST", "/api/x?v=2");
+        req.headers.insert("X-Debug".to_string(), "1".to_string());
+        assert_eq!(run(&rules, &req), "403");
+
+        let mut other = req.clone();
+        other.method = "GET".to_string();
+        assert_eq!(run(&rules, &other), "unchanged");
+        let mut other = req.clone();
+        other.query = "v=1".to_string();
+        assert_eq!(run(&rules, &other), "unchanged");
+        let mut other = req.clone();
+        other.headers.remove("X-Debug");
+        assert_eq!(run(&rules, &other), "unchanged");
+        let mut other = req.clone();
+        other
+            .headers
+            .insert("Host".to_string(), "example.com".to_string());
+        assert_eq!(run(&rules, &other), "unchanged");
+    }
+
+    #[test]
+    fn rewritten_paths_are_normalized() {
+        let rules = rules(json!([
+            { "path": "^/files/(.*)$", "rewrite": "/public/$1//./index.html" },
+            { "path": "^/up$", "rewrite": "/public/../private" }
+        ]));
+        assert_eq!(
+            run(&rules, &request("GET", "/files/a")),
+            "rewrite /public/a/index.html"
+        );
+        assert_eq!(run(&rules, &request("GET", "/up")), "rewrite /private");
+    }
+
+    #[test]
+    fn invalid_patterns_never_match() {
+        let rules = rules(json!([{ "path": "(", "status": 403 }]));
+        assert_eq!(run(&rules, &request("GET", "/")), "unchanged");
+    }
+}
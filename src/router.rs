@@ -1,129 +1,436 @@
 use crate::{
-    config,
-    http::{HttpRequest, HttpResponse},
+    auth, backend, cache,
+    cgi::{self, CgiResponse},
+    config::{self, prefix_matches, Config, MountConfig},
+    error_page,
+    http::{normalize_path, BodyStream, HttpRequest, HttpResponse},
+    ip_filter, proxy, rewrite, sse,
 };
 use percent_encoding::NON_ALPHANUMERIC;
-use percent_encoding::percent_encode;
+use percent_encoding::{percent_decode_str, percent_encode};
 use std::cmp::Ordering;
-use std::env;
 use std::path::{Path, PathBuf};
 use tokio::fs;
-use tokio::process::Command;
 use tracing::{error, info, warn};
 
+/// CGI 本地重定向的最大次数
+const MAX_LOCAL_REDIRECTS: u8 = 5;
+
+/// 服务器能识别的全部请求方法，其余方法一律返回 501
+const KNOWN_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// 请求路径最终指向的资源类型，每种资源支持的方法不同
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resource {
+    Directory,
+    StaticFile,
+    CgiScript,
+    /// 交给外部应用服务器（FastCGI 等）处理的脚本
+    Backend,
+}
+
+impl Resource {
+    const ALL: [Resource; 4] = [
+        Resource::Directory,
+        Resource::StaticFile,
+        Resource::CgiScript,
+        Resource::Backend,
+    ];
+
+    fn allowed_methods(&self) -> &'static [&'static str] {
+        match self {
+            Resource::Directory | Resource::StaticFile => &["GET", "HEAD", "OPTIONS"],
+            Resource::CgiScript | Resource::Backend => &["GET", "HEAD", "POST", "OPTIONS"],
+        }
+    }
+}
+
+/// 路由之前的访问检查：Host 头、重写规则、IP 规则和认证，都基于重写后的最终路径。
+/// 通过时返回之后用于路由的请求（认证可能写入用户名和转发的请求头），
+/// 否则返回给客户端的响应
+pub async fn admit(req: &HttpRequest) -> Result<HttpRequest, HttpResponse> {
+    match check_access(req).await {
+        Ok(admitted) => Ok(admitted),
+        Err(response) => Err(finish(req, response).await),
+    }
+}
+
+async fn check_access(req: &HttpRequest) -> Result<HttpRequest, HttpResponse> {
+    let forward_proxy = match config::read_config().await {
+        Ok(config) => config.forward_proxy.is_some(),
+        Err(_) => return Err(HttpResponse::internal_server_error()),
+    };
+    let origin;
+    let req = match &req.authority {
+        Some(authority) if !forward_proxy && req.method != "CONNECT" => {
+            origin = origin_form(req, authority);
+            &origin
+        }
+        _ => req,
+    };
+    // HTTP/1.1 要求带 Host 头（RFC 9112 第 3.2 节），虚拟主机也依赖它
+    if req.version == "HTTP/1.1" && req.header("Host").is_none() {
+        warn!("HTTP/1.1 request without Host header");
+        return Err(HttpResponse::bad_request());
+    }
+    // 重写规则可能改写路径，正向代理的请求不经过重写
+    let mut admitted = match config::for_request(req).await {
+        Ok(config) if req.authority.is_none() => match rewrite::apply(req, &config.rewrites) {
+            rewrite::Outcome::Unchanged => req.clone(),
+            rewrite::Outcome::Rewritten(new) => new,
+            rewrite::Outcome::Respond(response) => return Err(response),
+        },
+        Ok(_) => req.clone(),
+        Err(_) => return Err(HttpResponse::internal_server_error()),
+    };
+    if let Some(denied) = ip_filter::check(&admitted).await {
+        return Err(denied);
+    }
+    auth::authenticate(&mut admitted).await?;
+    Ok(admitted)
+}
+
+/// 没有开启正向代理时，绝对形式的请求目标按源站形式处理：
+/// 主机取请求目标中的，忽略 Host 头（RFC 9112 第 3.2.2 节）
+fn origin_form(req: &HttpRequest, authority: &str) -> HttpRequest {
+    let mut origin = req.clone();
+    origin.authority = None;
+    origin
+        .headers
+        .retain(|key, _| !key.eq_ignore_ascii_case("Host"));
+    origin
+        .headers
+        .insert("Host".to_string(), authority.to_string());
+    origin
+}
+
+/// 处理通过访问检查的请求
 pub async fn router_request(req: &HttpRequest) -> HttpResponse {
+    let cache_config = config::read_config()
+        .await
+        .ok()
+        .and_then(|c| c.cache.clone());
+    let response = match (sse::find_source(req), &cache_config) {
+        // 事件流一直保持打开，不经过缓存
+        (Some(source), _) => sse::handle(source, req).await,
+        (None, Some(cache_config)) if req.method == "PURGE" => {
+            cache::purge(req, cache_config).await
+        }
+        (None, Some(cache_config)) if cache::applies(req) => {
+            cache::handle(req, cache_config, |req| async move { route(&req, 0).await }).await
+        }
+        _ => route(req, 0).await,
+    };
+    finish(req, response).await
+}
+
+async fn finish(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    // 没有正文的错误响应换成错误页
+    let mut response = error_page::decorate(req, response).await;
+    // HEAD 与 GET 处理相同，只是不返回正文（保留 Content-Length）
+    // 原样转发的流（nph 脚本）由脚本自己负责，无法剥离正文
+    if req.method == "HEAD" {
+        response.body.clear();
+        if let Some(BodyStream::Chunked(_) | BodyStream::Sized(_)) = response.stream {
+            response.stream = None;
+        }
+    }
+    response
+}
+
+/// `redirects` 记录 CGI 本地重定向的次数，防止脚本之间循环重定向
+async fn route(req: &HttpRequest, redirects: u8) -> HttpResponse {
     // 1. 验证请求方法
-    if !is_valid_method(req) {
-        warn!("Invalid method: {}", req.method);
+    if !is_known_method(req) {
+        warn!("Unknown method: {}", req.method);
         return HttpResponse::not_implemented();
     }
 
-    // 2. 获取配置并构建安全路径
-    let (public_path, full_path) = match prepare_path(req).await {
-        Ok((p, fp)) => (p, fp),
-        Err(response) => {
+    // OPTIONS * 询问的是整个服务器的能力
+    if req.method == "OPTIONS" && req.path == "*" {
+        return options_response(&server_allowed_methods());
+    }
+
+    // 匹配到虚拟主机时使用它的文档根目录和挂载点
+    let config = match config::for_request(req).await {
+        Ok(c) => c,
+        Err(_) => return HttpResponse::internal_server_error(),
+    };
+
+    // 正向代理：请求行中带有目标主机
+    if let (Some(_), Some(forward)) = (&req.authority, &config.forward_proxy) {
+        return proxy::handle_forward_request(forward, req).await;
+    }
+
+    // 挂载到上游服务的路径前缀直接转发，不经过静态文件处理
+    if config.upstream_status_path.as_deref() == Some(req.path.as_str()) {
+        return proxy::status_response();
+    }
+    if let Some(route) = proxy::find_route(&req.path, &config) {
+        return proxy::handle_proxy_request(route, req).await;
+    }
+
+    // 2. 按挂载点把 URL 映射为文件路径
+    let mount = config.mount_for(&req.path);
+    let root = Path::new(&mount.root);
+    let (full_path, path_info) = match prepare_path(req, &mount, &config) {
+        Some(paths) => paths,
+        None => {
             error!("Failed to prepare path for request: {}", req.path);
-            return response;
+            return HttpResponse::not_found();
         }
     };
 
     // 3. 验证路径安全性
-    if !is_path_safe(&public_path, &full_path) {
+    if !is_path_safe(root, &full_path) {
         warn!("Unsafe path access attempt: {}", req.path);
         return HttpResponse::not_found();
     }
 
-    // 4. 根据路径类型处理请求
-    handle_request_by_path_type(&full_path, req).await
+    // 4. 根据资源类型检查方法是否被允许
+    let mut full_path = full_path;
+    let mut resource = match classify_resource(&full_path, &mount, &config) {
+        Some(r) => r,
+        None => return HttpResponse::bad_request(),
+    };
+    // 目录优先使用索引文件，可用的方法由索引文件决定
+    let directory = resource == Resource::Directory;
+    if directory {
+        if let Some(index) = find_index_file(&full_path, &config) {
+            if !is_path_safe(root, &index) {
+                warn!("Unsafe index file: {}", index.display());
+                return HttpResponse::not_found();
+            }
+            resource = match classify_resource(&index, &mount, &config) {
+                Some(r) => r,
+                None => return HttpResponse::not_found(),
+            };
+            full_path = index;
+        }
+    }
+    // 只有脚本可以带额外的路径信息
+    if !path_info.is_empty() && !matches!(resource, Resource::CgiScript | Resource::Backend) {
+        return HttpResponse::not_found();
+    }
+    let allowed = resource.allowed_methods();
+    if req.method == "OPTIONS" {
+        return options_response(allowed);
+    }
+    if !allowed.contains(&req.method.as_str()) {
+        warn!("Method {} not allowed for {}", req.method, req.path);
+        return HttpResponse::method_not_allowed().allow(allowed);
+    }
+    // 目录缺少结尾的 '/' 时重定向，放在方法检查之后，不支持的方法得到 405 而不是 301
+    if directory && !req.path.ends_with('/') {
+        return directory_redirect(req);
+    }
+
+    // 5. 根据资源类型处理请求
+    match resource {
+        Resource::Directory if !autoindex_enabled(&req.path, &mount, &config) => {
+            warn!("Directory listing disabled for {}", req.path);
+            HttpResponse::forbidden()
+        }
+        Resource::Directory => {
+            let response = handle_directory_request(&full_path, &req.path).await;
+            with_cache_control(response, &mount)
+        }
+        Resource::StaticFile => {
+            with_cache_control(handle_regular_file_request(&full_path).await, &mount)
+        }
+        Resource::CgiScript => {
+            let result = cgi::run_cgi(&full_path, root, req, &path_info).await;
+            follow_cgi_response(result, req, redirects).await
+        }
+        Resource::Backend => {
+            let result = match backend::find_backend(&full_path, root, &config) {
+                Some(b) => backend::handle_backend_request(b, &full_path, req, &path_info).await,
+                None => CgiResponse::Response(HttpResponse::internal_server_error()),
+            };
+            follow_cgi_response(result, req, redirects).await
+        }
+    }
 }
 
 // 辅助函数
-async fn prepare_path(req: &HttpRequest) -> Result<(PathBuf, PathBuf), HttpResponse> {
-    let static_dir = config::read_config()
-        .await
-        .map_err(|_| HttpResponse::internal_server_error())?
-        .static_dir;
-    let public_path = Path::new(&static_dir).to_path_buf();
+/// 返回 (规范化后的文件路径, CGI 的 PATH_INFO)
+fn prepare_path(
+    req: &HttpRequest,
+    mount: &MountConfig,
+    config: &Config,
+) -> Option<(PathBuf, String)> {
+    let root = Path::new(&mount.root);
+    // 去掉挂载点的 URL 前缀，剩下的部分相对于挂载的目录
+    let relative = req
+        .path
+        .strip_prefix(mount.url.trim_end_matches('/'))
+        .unwrap_or(&req.path)
+        .trim_start_matches('/');
+    if let Ok(p) = root.join(relative).canonicalize() {
+        return Some((p, String::new()));
+    }
+    if !mount.cgi {
+        return None;
+    }
 
-    let mut full_path = PathBuf::from(req.path.trim_start_matches("/"));
-    if let Ok(p) = full_path.canonicalize() {
-        full_path = p;
-    } else {
-        return Err(HttpResponse::not_found());
+    // 路径不存在时，尝试把它拆成 CGI 脚本 + PATH_INFO，例如 /py.cgi/extra
+    split_path_info(root, relative, config)
+}
+
+fn split_path_info(root: &Path, relative: &str, config: &Config) -> Option<(PathBuf, String)> {
+    for (idx, _) in relative.match_indices('/') {
+        let (script, rest) = relative.split_at(idx);
+        let script = match root.join(script).canonicalize() {
+            Ok(p) if p.is_file() => p,
+            _ => continue,
+        };
+        if is_script(&script, root, config) {
+            return Some((script, rest.to_string()));
+        }
     }
+    None
+}
 
-    Ok((public_path, full_path))
+fn is_script(path: &Path, root: &Path, config: &Config) -> bool {
+    backend::find_backend(path, root, config).is_some()
+        || cgi::find_handler(path, root, config).is_some()
 }
 
-fn is_valid_method(req: &HttpRequest) -> bool {
-    req.method == "GET"
+fn is_known_method(req: &HttpRequest) -> bool {
+    KNOWN_METHODS.contains(&req.method.as_str())
 }
 
-fn is_path_safe(public_path: &Path, full_path: &Path) -> bool {
-    // full_path.starts_with(env::current_dir().unwrap().join(public_path))
-    match env::current_dir() {
-        Ok(current_dir) => full_path.starts_with(current_dir.join(public_path)),
-        Err(_) => {
-            eprintln!("Get current_dir error");
-            false
+/// 所有资源类型支持的方法的并集，用于回答 OPTIONS *
+fn server_allowed_methods() -> Vec<&'static str> {
+    let mut methods: Vec<&'static str> = Vec::new();
+    for resource in Resource::ALL {
+        for method in resource.allowed_methods() {
+            if !methods.contains(method) {
+                methods.push(method);
+            }
         }
     }
+    methods
+}
+
+/// OPTIONS 的应答没有正文，明确给出长度为 0
+fn options_response(methods: &[&str]) -> HttpResponse {
+    HttpResponse::ok()
+        .allow(methods)
+        .header("Content-Length", "0")
 }
 
-async fn handle_request_by_path_type(path: &Path, req: &HttpRequest) -> HttpResponse {
+fn classify_resource(path: &Path, mount: &MountConfig, config: &Config) -> Option<Resource> {
+    let root = Path::new(&mount.root);
     if path.is_dir() {
-        handle_directory_request(path).await
+        Some(Resource::Directory)
     } else if path.is_file() {
-        handle_file_request(path, req).await
+        if !mount.cgi {
+            Some(Resource::StaticFile)
+        } else if backend::find_backend(path, root, config).is_some() {
+            Some(Resource::Backend)
+        } else if cgi::find_handler(path, root, config).is_some() {
+            Some(Resource::CgiScript)
+        } else {
+            Some(Resource::StaticFile)
+        }
     } else {
-        HttpResponse::bad_request()
+        None
+    }
+}
+
+/// 目录中第一个存在的索引文件
+fn find_index_file(dir: &Path, config: &Config) -> Option<PathBuf> {
+    config
+        .index_files
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+        .and_then(|path| path.canonicalize().ok())
+}
+
+/// 是否列出目录：最长前缀匹配的目录设置优先，其次是挂载点的设置，最后是全局设置
+fn autoindex_enabled(url_path: &str, mount: &MountConfig, config: &Config) -> bool {
+    config
+        .directories
+        .iter()
+        .filter(|dir| prefix_matches(&dir.path, url_path))
+        .max_by_key(|dir| dir.path.len())
+        .map(|dir| dir.autoindex)
+        .or(mount.autoindex)
+        .unwrap_or(config.autoindex)
+}
+
+/// 挂载点设置了 Cache-Control 时加到成功的静态响应上
+fn with_cache_control(response: HttpResponse, mount: &MountConfig) -> HttpResponse {
+    match &mount.cache_control {
+        Some(value) if response.code == "200" => response.header("Cache-Control", value),
+        _ => response,
     }
 }
 
-async fn handle_directory_request(path: &Path) -> HttpResponse {
-    list_directory(path)
+/// 目录的 URL 缺少结尾的 '/' 时重定向过去，否则目录列表中的相对链接会指向上一级
+fn directory_redirect(req: &HttpRequest) -> HttpResponse {
+    let mut location = encode_url_path(&req.path);
+    location.push('/');
+    if !req.query.is_empty() {
+        location.push('?');
+        location.push_str(&req.query);
+    }
+    HttpResponse::from_status(301, "").header("Location", &location)
+}
+
+/// 逐段编码 URL 路径，保留 '/'
+fn encode_url_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| percent_encode(segment.as_bytes(), NON_ALPHANUMERIC).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// 规范化后的路径必须仍在挂载的目录之内（防止 ../ 和符号链接逃逸）
+fn is_path_safe(root: &Path, full_path: &Path) -> bool {
+    full_path.starts_with(root)
+}
+
+async fn handle_directory_request(path: &Path, url_path: &str) -> HttpResponse {
+    list_directory(path, url_path)
         .await
         .map(|content| HttpResponse::ok().body("html", content))
         .unwrap_or_else(|_| HttpResponse::internal_server_error())
 }
 
-pub async fn list_directory(path: &Path) -> tokio::io::Result<String> {
+/// 列出目录内容，链接按 URL 生成，`url_path` 以 '/' 结尾
+pub async fn list_directory(path: &Path, url_path: &str) -> tokio::io::Result<String> {
     let mut entries = fs::read_dir(path).await?;
-    let current_dir = env::current_dir().map_err(|e| {
-        eprintln!("Failed to get current directory: {}", e);
-        tokio::io::Error::new(tokio::io::ErrorKind::Other, "Failed to get current directory")
-    })?;
-    
-    let current_path = path.strip_prefix(current_dir).map_err(|e| {
-        eprintln!("Failed to get relative path: {}", e);
-        tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, "Failed to get relative path")
-    })?.display().to_string();
+    let base = encode_url_path(url_path);
 
     // 构建简单 HTML
     let mut html = String::new();
     html.push_str("<!DOCTYPE html>\n");
     html.push_str("<html>\n");
     html.push_str("<meta charset=\"UTF-8\">");
-    html.push_str("<head><title>Directory listing for /");
-    html.push_str(&current_path);
+    html.push_str("<head><title>Directory listing for ");
+    html.push_str(url_path);
     html.push_str("</title></head>\n");
     html.push_str("<body>\n");
-    html.push_str("<h1>Directory listing for /");
-    html.push_str(&current_path);
+    html.push_str("<h1>Directory listing for ");
+    html.push_str(url_path);
     html.push_str("</h1>\n");
     html.push_str("<hr>\n");
     html.push_str("<pre>\n");
 
     // 添加返回上一级链接
-    if current_path != "" {
-        let parent_path = Path::new(&current_path)
-            .parent()
-            .map(|p| p.to_str().unwrap_or(""))
-            .unwrap_or("");
-        let encoded_parent = percent_encode(parent_path.as_bytes(), NON_ALPHANUMERIC);
+    if url_path != "/" {
+        let parent = match url_path.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, _)) => format!("{}/", parent),
+            None => "/".to_string(),
+        };
         html.push_str(&format!(
-            "<a href=\"/{}\">[Parent Directory]</a>\n",
-            encoded_parent
+            "<a href=\"{}\">[Parent Directory]</a>\n",
+            encode_url_path(&parent)
         ));
     }
 
@@ -134,66 +441,49 @@ pub async fn list_directory(path: &Path) -> tokio::io::Result<String> {
     while let Some(entry) = entries.next_entry().await? {
         let file_name = entry.file_name().to_string_lossy().into_owned();
         let file_type = entry.file_type().await?;
-        let file_path = format!("{}/{}", current_path, file_name);
 
         if file_type.is_dir() {
-            dirs.push((file_name, file_path));
+            dirs.push(file_name);
         } else if file_type.is_file() {
-            files.push((file_name, file_path));
+            files.push(file_name);
         }
     }
 
     // 对目录按名称排序
-    dirs.sort_by(|a, b| a.0.to_lowercase().cmp(&b.0.to_lowercase()));
+    dirs.sort_by_key(|a| a.to_lowercase());
 
     // 对文件先按后缀排序，再按名称排序
     files.sort_by(|a, b| {
-        let ext_a = Path::new(&a.0)
+        let ext_a = Path::new(a)
             .extension()
             .and_then(|e| e.to_str())
             .unwrap_or("");
-        let ext_b = Path::new(&b.0)
+        let ext_b = Path::new(b)
             .extension()
             .and_then(|e| e.to_str())
             .unwrap_or("");
 
         match ext_a.to_lowercase().cmp(&ext_b.to_lowercase()) {
-            Ordering::Equal => a.0.to_lowercase().cmp(&b.0.to_lowercase()),
+            Ordering::Equal => a.to_lowercase().cmp(&b.to_lowercase()),
             other => other,
         }
     });
 
     // 先输出目录
-    for (file_name, file_path) in dirs {
-        // let encoded_path = percent_encode(file_path.as_bytes(), NON_ALPHANUMERIC);
-        let encoded_path: String = Path::new(&file_path)
-            .components()
-            .map(|comp| {
-                let part = comp.as_os_str().to_string_lossy();
-                percent_encode(part.as_bytes(), NON_ALPHANUMERIC).to_string()
-            })
-            .collect::<Vec<_>>()
-            .join("/");
+    for file_name in dirs {
+        let encoded_name = percent_encode(file_name.as_bytes(), NON_ALPHANUMERIC);
         html.push_str(&format!(
-            "<a href=\"/{}\">[DIR] {}/</a>\n",
-            encoded_path, file_name
+            "<a href=\"{}{}/\">[DIR] {}/</a>\n",
+            base, encoded_name, file_name
         ));
     }
 
     // 再输出文件
-    for (file_name, file_path) in files {
-        // let encoded_path = percent_encode(file_path.as_bytes(), NON_ALPHANUMERIC);
-        let encoded_path: String = Path::new(&file_path)
-            .components()
-            .map(|comp| {
-                let part = comp.as_os_str().to_string_lossy();
-                percent_encode(part.as_bytes(), NON_ALPHANUMERIC).to_string()
-            })
-            .collect::<Vec<_>>()
-            .join("/");
+    for file_name in files {
+        let encoded_name = percent_encode(file_name.as_bytes(), NON_ALPHANUMERIC);
         html.push_str(&format!(
-            "<a href=\"/{}\">{}</a>\n",
-            encoded_path, file_name
+            "<a href=\"{}{}\">{}</a>\n",
+            base, encoded_name, file_name
         ));
     }
 
@@ -205,27 +495,39 @@ pub async fn list_directory(path: &Path) -> tokio::io::Result<String> {
     Ok(html)
 }
 
-async fn handle_file_request(path: &Path, req: &HttpRequest) -> HttpResponse {
-    if req.path.ends_with(".cgi") {
-        handle_cgi_request(path).await
-    } else {
-        handle_regular_file_request(path).await
+/// 处理 CGI 格式的响应，本地重定向时重新路由
+async fn follow_cgi_response(
+    result: CgiResponse,
+    req: &HttpRequest,
+    redirects: u8,
+) -> HttpResponse {
+    let location = match result {
+        CgiResponse::Response(response) => return response,
+        CgiResponse::LocalRedirect(location) => location,
+    };
+
+    if redirects >= MAX_LOCAL_REDIRECTS {
+        error!("Too many CGI local redirects, last location: {}", location);
+        return HttpResponse::internal_server_error();
     }
-}
+    info!("CGI local redirect to {}", location);
 
-async fn handle_cgi_request(path: &Path) -> HttpResponse {
-    info!("Executing CGI script: {}", path.display());
-    Command::new(path)
-        .output()
-        .await
-        .map(|output| {
-            info!("CGI script executed successfully");
-            HttpResponse::ok().body("text/html", output.stdout)
-        })
-        .unwrap_or_else(|e| {
-            error!("Failed to execute CGI script: {}", e);
-            HttpResponse::internal_server_error()
-        })
+    // 本地重定向按 GET 请求重新路由，原请求体不再转发
+    let (raw_path, query) = location.split_once('?').unwrap_or((&location, ""));
+    let path = match percent_decode_str(raw_path).decode_utf8() {
+        Ok(p) => p.into_owned(),
+        Err(_) => return HttpResponse::bad_gateway(),
+    };
+    let mut redirected = req.clone();
+    redirected.method = "GET".to_string();
+    redirected.path = normalize_path(&path);
+    redirected.query = query.to_string();
+    redirected.body.clear();
+    // 新的路径同样要经过重写和访问检查
+    match Box::pin(check_access(&redirected)).await {
+        Ok(admitted) => Box::pin(route(&admitted, redirects + 1)).await,
+        Err(response) => response,
+    }
 }
 
 async fn handle_regular_file_request(path: &Path) -> HttpResponse {
@@ -233,7 +535,11 @@ async fn handle_regular_file_request(path: &Path) -> HttpResponse {
         .first_or_octet_stream()
         .to_string();
 
-    info!("Serving file: {} (mime type: {})", path.display(), mime_type);
+    info!(
+        "Serving file: {} (mime type: {})",
+        path.display(),
+        mime_type
+    );
     match tokio::fs::read(path).await {
         Ok(data) => {
             if mime_type.starts_with("text/") {
@@ -253,3 +559,139 @@ async fn handle_regular_file_request(path: &Path) -> HttpResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 使用 testdata/config.json，文档根目录为 testdata/www
+
+    fn request(method: &str, path: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.method = method.to_string();
+        req.path = path.to_string();
+        req.version = "HTTP/1.1".to_string();
+        req
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response.headers.get(name).map(String::as_str)
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_methods_of_a_resource() {
+        let response = route(&request("OPTIONS", "/a.txt"), 0).await;
+        assert_eq!(response.code, "200");
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(header(&response, "Content-Length"), Some("0"));
+
+        let response = route(&request("OPTIONS", "/x.cgi"), 0).await;
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, POST, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn options_star_lists_every_method() {
+        let response = route(&request("OPTIONS", "*"), 0).await;
+        assert_eq!(response.code, "200");
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS, POST"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_gets_405_with_allow() {
+        for method in ["POST", "PUT", "DELETE"] {
+            let response = route(&request(method, "/a.txt"), 0).await;
+            assert_eq!(response.code, "405");
+            assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+        }
+    }
+
+    #[tokio::test]
+    async fn directory_methods_are_checked_before_the_redirect() {
+        for method in ["POST", "PUT", "DELETE"] {
+            let response = route(&request(method, "/docs"), 0).await;
+            assert_eq!(response.code, "405");
+            assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+        }
+        let response = route(&request("OPTIONS", "/docs"), 0).await;
+        assert_eq!(response.code, "200");
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let response = route(&request("GET", "/docs"), 0).await;
+        assert_eq!(response.code, "301");
+        assert_eq!(header(&response, "Location"), Some("/docs/"));
+        assert_eq!(route(&request("GET", "/docs/"), 0).await.code, "200");
+    }
+
+    #[tokio::test]
+    async fn directory_with_a_script_index_takes_its_methods() {
+        let response = route(&request("OPTIONS", "/app"), 0).await;
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        assert_eq!(route(&request("POST", "/app"), 0).await.code, "301");
+    }
+
+    #[tokio::test]
+    async fn unknown_method_gets_501() {
+        let response = route(&request("BREW", "/a.txt"), 0).await;
+        assert_eq!(response.code, "501");
+    }
+
+    async fn parse(raw: &str) -> HttpRequest {
+        let mut reader = tokio::io::BufReader::new(raw.as_bytes());
+        let mut req = HttpRequest::try_from_reader(&mut reader).await.unwrap();
+        req.remote_addr = Some("127.0.0.1:40000".parse().unwrap());
+        req
+    }
+
+    async fn denied(raw: &str) -> String {
+        match admit(&parse(raw).await).await {
+            Ok(admitted) => panic!("{} was admitted", admitted.path),
+            Err(response) => response.code,
+        }
+    }
+
+    #[tokio::test]
+    async fn absolute_form_is_served_as_origin_form() {
+        let req = parse("GET http://x/a.txt HTTP/1.1\r\nHost: other\r\n\r\n").await;
+        let admitted = admit(&req).await.unwrap();
+        assert_eq!(admitted.authority, None);
+        assert_eq!(admitted.header("Host"), Some("x"));
+        let response = route(&admitted, 0).await;
+        assert_eq!(response.code, "200");
+        assert_eq!(response.body, b"hello\n");
+    }
+
+    #[tokio::test]
+    async fn absolute_form_is_checked_against_ip_rules() {
+        assert_eq!(
+            denied("GET /admin/s.txt HTTP/1.1\r\nHost: x\r\n\r\n").await,
+            "403"
+        );
+        assert_eq!(
+            denied("GET http://x//admin/s.txt HTTP/1.1\r\nHost: x\r\n\r\n").await,
+            "403"
+        );
+        assert_eq!(
+            denied("GET http://x/a/../admin/s.txt HTTP/1.1\r\n\r\n").await,
+            "403"
+        );
+    }
+
+    #[tokio::test]
+    async fn absolute_form_is_checked_against_auth() {
+        assert_eq!(
+            denied("GET //private/p.txt HTTP/1.1\r\nHost: x\r\n\r\n").await,
+            "401"
+        );
+        assert_eq!(
+            denied("GET http://x//private/p.txt HTTP/1.1\r\nHost: x\r\n\r\n").await,
+            "401"
+        );
+
+        // alice:secret
+        let raw =
+            "GET http://x//private/p.txt HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n";
+        let admitted = admit(&parse(raw).await).await.unwrap();
+        assert_eq!(admitted.remote_user.as_deref(), Some("alice"));
+        assert_eq!(route(&admitted, 0).await.body, b"private\n");
+    }
+}
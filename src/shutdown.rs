@@ -1,8 +1,12 @@
-use std::sync::Arc;
-use tokio::sync::Notify;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::{watch, Notify};
+
+// 开始关闭时置为 true，WebSocket 隧道等长连接据此主动结束
+static SHUTTING_DOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
 
 #[derive(Debug)]
 pub enum ShutdownError {
+    #[allow(dead_code)]
     SignalBindFail,
 }
 
@@ -10,11 +14,17 @@ pub fn start_shutdown_listener() -> Result<Arc<Notify>, ShutdownError> {
     let notify = Arc::new(Notify::new());
     let notify_clone = notify.clone();
     tokio::spawn(async move {
-        if let Err(_) = tokio::signal::ctrl_c().await {
+        if tokio::signal::ctrl_c().await.is_err() {
             return;
         }
         println!("Ctrl+C received");
+        SHUTTING_DOWN.send_replace(true);
         notify_clone.notify_waiters();
     });
     Ok(notify)
 }
+
+/// 订阅关闭信号，值变为 true 表示服务器正在关闭
+pub fn subscribe() -> watch::Receiver<bool> {
+    SHUTTING_DOWN.subscribe()
+}
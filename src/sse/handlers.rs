@@ -0,0 +1,35 @@
+//! 内置的事件源，也作为实现 EventSource 的示例
+
+use super::{Event, EventSender, EventSource, HandlerFuture};
+use crate::http::HttpRequest;
+use std::time::{Duration, SystemTime};
+
+/// 每秒发送一个 "tick" 事件，id 递增，重连时从 Last-Event-ID 的下一个继续
+pub struct Ticker;
+
+impl EventSource for Ticker {
+    fn on_subscribe(
+        &self,
+        events: EventSender,
+        last_event_id: Option<String>,
+        _req: HttpRequest,
+    ) -> HandlerFuture {
+        Box::pin(async move {
+            let mut next = last_event_id
+                .and_then(|id| id.parse::<u64>().ok())
+                .map_or(0, |id| id + 1);
+            loop {
+                let now = httpdate::fmt_http_date(SystemTime::now());
+                let event = Event::new(&now).id(&next.to_string()).event("tick");
+                if events.send(&event).await.is_err() {
+                    break;
+                }
+                next += 1;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                    _ = events.closed() => break,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,235 @@
+//! Server-Sent Events（text/event-stream）
+//!
+//! 按路径注册的 EventSource 在订阅时被调用，通过 EventSender 推送事件。
+//! 响应以 chunked 流的形式一直保持打开，空闲时定期发送注释行防止中间设备断开连接。
+
+mod handlers;
+
+use crate::{
+    config::Config,
+    http::{BodyStream, HttpRequest, HttpResponse},
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tracing::{info, Instrument};
+
+pub use handlers::Ticker;
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// SSE 路由的事件源，每个订阅调用一次，返回的 future 结束时关闭响应
+pub trait EventSource: Send + Sync {
+    /// `last_event_id` 为客户端重连时带上的 Last-Event-ID，事件源应从它之后继续发送
+    fn on_subscribe(
+        &self,
+        events: EventSender,
+        last_event_id: Option<String>,
+        req: HttpRequest,
+    ) -> HandlerFuture;
+}
+
+// 按路径注册的事件源
+static SOURCES: LazyLock<RwLock<HashMap<String, Arc<dyn EventSource>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// 一个事件，data 中的换行会拆成多行 data 字段
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<u64>,
+}
+
+/// 向一个订阅者推送事件，订阅者断开后发送返回错误
+#[derive(Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+/// 注册一个 SSE 路由，相同路径的旧事件源会被替换
+pub fn register(path: &str, source: impl EventSource + 'static) {
+    SOURCES
+        .write()
+        .unwrap()
+        .insert(path.to_string(), Arc::new(source));
+}
+
+/// 注册配置中启用的内置路由
+pub fn start(config: &Config) {
+    if let Some(path) = &config.sse.ticker_path {
+        register(path, Ticker);
+        info!("SSE ticker at {}", path);
+    }
+}
+
+pub fn find_source(req: &HttpRequest) -> Option<Arc<dyn EventSource>> {
+    SOURCES.read().unwrap().get(&req.path).cloned()
+}
+
+/// 打开事件流，事件源在后台推送，响应体一直转发到事件源结束或客户端断开
+pub async fn handle(source: Arc<dyn EventSource>, req: &HttpRequest) -> HttpResponse {
+    if req.method == "OPTIONS" {
+        return HttpResponse::ok().allow(&["GET", "HEAD", "OPTIONS"]);
+    }
+    if req.method != "GET" && req.method != "HEAD" {
+        return HttpResponse::method_not_allowed().allow(&["GET", "HEAD", "OPTIONS"]);
+    }
+    let (keep_alive, retry_ms) = match crate::config::read_config().await {
+        Ok(config) => (config.sse.keep_alive_secs, config.sse.retry_ms),
+        Err(_) => (15, None),
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    let (output, body) = tokio::io::duplex(16 * 1024);
+    // 先告诉客户端断开后多久重连
+    let first = retry_ms.map(|ms| format!("retry: {}\n\n", ms).into_bytes());
+    tokio::spawn(pump(rx, output, first, Duration::from_secs(keep_alive.max(1))).in_current_span());
+
+    let last_event_id = req.header("Last-Event-ID").map(|id| id.trim().to_string());
+    info!(
+        "SSE subscription to {} (Last-Event-ID {:?})",
+        req.path, last_event_id
+    );
+    let events = EventSender { tx };
+    tokio::spawn(
+        source
+            .on_subscribe(events, last_event_id, req.clone())
+            .in_current_span(),
+    );
+
+    HttpResponse::ok()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        // 让前面的反向代理不要缓冲事件流
+        .header("X-Accel-Buffering", "no")
+        .stream(BodyStream::Chunked(Box::new(body)))
+}
+
+/// 把事件写入响应体，空闲 `keep_alive` 后发送一行注释
+async fn pump(
+    mut events: mpsc::Receiver<Vec<u8>>,
+    mut output: DuplexStream,
+    first: Option<Vec<u8>>,
+    keep_alive: Duration,
+) {
+    if let Some(first) = first {
+        if output.write_all(&first).await.is_err() {
+            return;
+        }
+    }
+    let mut idle = tokio::time::interval_at(tokio::time::Instant::now() + keep_alive, keep_alive);
+    loop {
+        let chunk = tokio::select! {
+            chunk = events.recv() => match chunk {
+                Some(chunk) => chunk,
+                // 事件源结束，响应随之结束
+                None => break,
+            },
+            _ = idle.tick() => b": keep-alive\n\n".to_vec(),
+        };
+        // 客户端断开后响应体的读端被丢弃，写入失败
+        if output.write_all(&chunk).await.is_err() {
+            info!("SSE subscriber disconnected");
+            break;
+        }
+        idle.reset();
+    }
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Event {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// 编码为 text/event-stream 格式，以空行结束
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        // id 和 event 只占一行，去掉其中的换行
+        let single_line = |s: &str| s.replace(['\r', '\n'], "");
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry));
+        }
+        // 客户端把 \r\n、\r 和 \n 都当作换行，单独的 \r 也要拆开
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            out.push_str(&format!("data: {}\n", line));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+impl EventSender {
+    pub async fn send(&self, event: &Event) -> io::Result<()> {
+        self.tx
+            .send(event.encode().into_bytes())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// 等待订阅者断开
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_only_event() {
+        assert_eq!(Event::new("hello").encode(), "data: hello\n\n");
+        assert_eq!(Event::new("").encode(), "data: \n\n");
+    }
+
+    #[test]
+    fn multi_line_data_becomes_several_fields() {
+        assert_eq!(
+            Event::new("a\nb\r\nc\rd").encode(),
+            "data: a\ndata: b\ndata: c\ndata: d\n\n"
+        );
+        assert_eq!(Event::new("end\n").encode(), "data: end\ndata: \n\n");
+    }
+
+    #[test]
+    fn id_event_and_retry_come_before_data() {
+        let mut event = Event::new("x").id("7").event("tick");
+        event.retry = Some(3000);
+        assert_eq!(
+            event.encode(),
+            "id: 7\nevent: tick\nretry: 3000\ndata: x\n\n"
+        );
+    }
+
+    #[test]
+    fn id_and_event_cannot_inject_lines() {
+        let event = Event::new("x").id("1\ndata: forged").event("a\r\nb");
+        assert_eq!(event.encode(), "id: 1data: forged\nevent: ab\ndata: x\n\n");
+    }
+}
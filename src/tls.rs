@@ -0,0 +1,94 @@
+//! HTTPS 监听端口使用的 TLS 配置，按 SNI 为每个虚拟主机选择证书
+
+use crate::config::{Config, TlsConfig};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    self,
+    crypto::ring::sign::any_supported_type,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, warn};
+
+/// 按 SNI 找到虚拟主机的证书，没有匹配时使用顶层的证书
+struct SniResolver {
+    config: Config,
+    // 按证书路径加载的证书和私钥
+    keys: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// 加载配置中的所有证书，没有可用证书时返回 None
+pub fn build_acceptor(config: &Config) -> Option<TlsAcceptor> {
+    let all = config
+        .tls
+        .iter()
+        .chain(config.virtual_hosts.iter().filter_map(|v| v.tls.as_ref()));
+    let mut keys = HashMap::new();
+    for tls in all {
+        if keys.contains_key(&tls.cert) {
+            continue;
+        }
+        match load_key(tls) {
+            Ok(key) => {
+                info!("Loaded certificate {}", tls.cert);
+                keys.insert(tls.cert.clone(), Arc::new(key));
+            }
+            Err(e) => error!("Cannot load certificate {}: {}", tls.cert, e),
+        }
+    }
+    if keys.is_empty() {
+        return None;
+    }
+
+    let resolver = SniResolver {
+        config: config.clone(),
+        keys,
+    };
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    // 每个连接只处理一个 HTTP/1.1 请求
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Some(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_key(tls: &TlsConfig) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| e.to_string())?;
+    if certs.is_empty() {
+        return Err("no certificate in file".to_string());
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| format!("{}: {}", tls.key, e))?;
+    let key = any_supported_type(&key).map_err(|e| e.to_string())?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        // 没有 SNI 时与没有 Host 头一样使用默认虚拟主机
+        let name = client_hello.server_name().unwrap_or("");
+        let tls = self
+            .config
+            .virtual_host(name)
+            .and_then(|vhost| vhost.tls.as_ref())
+            .or(self.config.tls.as_ref())?;
+        let key = self.keys.get(&tls.cert).cloned();
+        if key.is_none() {
+            warn!("No usable certificate for server name {:?}", name);
+        }
+        key
+    }
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniResolver")
+            .field("certificates", &self.keys.keys())
+            .finish()
+    }
+}
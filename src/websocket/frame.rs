@@ -0,0 +1,205 @@
+//! WebSocket 帧的编解码（RFC 6455 第 5 节）
+
+use super::WebSocketError;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+/// 控制帧的最大负载长度
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+}
+
+/// 读取客户端发来的一帧，客户端的帧必须带掩码
+pub async fn read_frame<R>(reader: &mut R, max_payload: usize) -> Result<Frame, WebSocketError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    // 没有协商任何扩展，RSV 位必须为 0
+    if header[0] & 0x70 != 0 {
+        return Err(WebSocketError::Protocol);
+    }
+    let opcode = header[0] & 0x0f;
+    if !matches!(
+        opcode,
+        OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+    ) {
+        return Err(WebSocketError::Protocol);
+    }
+    if header[1] & 0x80 == 0 {
+        return Err(WebSocketError::Protocol);
+    }
+
+    let len = match header[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    let control = opcode & 0x8 != 0;
+    if control && (!fin || len > MAX_CONTROL_PAYLOAD) {
+        return Err(WebSocketError::Protocol);
+    }
+    if len > max_payload as u64 {
+        return Err(WebSocketError::TooLarge);
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// 编码服务器发出的一帧，服务器的帧不加掩码
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 10);
+    buf.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => buf.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            buf.push(126);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(127);
+            buf.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(payload);
+    buf
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// 客户端发出的一帧，使用固定的掩码
+    pub fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut buf = vec![first];
+        match payload.len() {
+            len if len < 126 => buf.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.push(0x80 | 126);
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                buf.push(0x80 | 127);
+                buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        buf
+    }
+
+    async fn read(bytes: &[u8], max_payload: usize) -> Result<Frame, WebSocketError> {
+        read_frame(&mut &bytes[..], max_payload).await
+    }
+
+    #[tokio::test]
+    async fn unmasks_client_frames() {
+        // RFC 6455 第 5.7 节的例子：带掩码的 "Hello"
+        let bytes = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = read(&bytes, 1024).await.unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[tokio::test]
+    async fn rejects_unmasked_frames_and_reserved_bits() {
+        let unmasked = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(matches!(
+            read(&unmasked, 1024).await,
+            Err(WebSocketError::Protocol)
+        ));
+        let rsv = masked(0x80 | 0x40 | OP_TEXT, b"x");
+        assert!(matches!(
+            read(&rsv, 1024).await,
+            Err(WebSocketError::Protocol)
+        ));
+        let unknown_opcode = masked(0x80 | 0x3, b"x");
+        assert!(matches!(
+            read(&unknown_opcode, 1024).await,
+            Err(WebSocketError::Protocol)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_extended_lengths() {
+        let medium = vec![b'm'; 300];
+        let frame = read(&masked(0x80 | OP_BINARY, &medium), 1 << 20)
+            .await
+            .unwrap();
+        assert_eq!(frame.payload, medium);
+
+        let large = vec![b'l'; 70_000];
+        let bytes = masked(0x80 | OP_BINARY, &large);
+        assert_eq!(bytes[1], 0x80 | 127);
+        let frame = read(&bytes, 1 << 20).await.unwrap();
+        assert_eq!(frame.payload, large);
+    }
+
+    #[tokio::test]
+    async fn enforces_the_payload_limit() {
+        let bytes = masked(0x80 | OP_BINARY, &[0; 200]);
+        assert!(matches!(
+            read(&bytes, 199).await,
+            Err(WebSocketError::TooLarge)
+        ));
+        assert!(read(&bytes, 200).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn control_frames_must_be_short_and_final() {
+        let long_ping = masked(0x80 | OP_PING, &[0; 126]);
+        assert!(matches!(
+            read(&long_ping, 1024).await,
+            Err(WebSocketError::Protocol)
+        ));
+        let fragmented_ping = masked(OP_PING, b"x");
+        assert!(matches!(
+            read(&fragmented_ping, 1024).await,
+            Err(WebSocketError::Protocol)
+        ));
+    }
+
+    #[test]
+    fn encodes_unmasked_server_frames() {
+        assert_eq!(encode_frame(OP_TEXT, b"Hi"), [0x81, 0x02, b'H', b'i']);
+        let medium = encode_frame(OP_BINARY, &[0; 300]);
+        assert_eq!(&medium[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(medium.len(), 304);
+        let large = encode_frame(OP_BINARY, &[0; 70_000]);
+        assert_eq!(&large[..2], &[0x82, 127]);
+        assert_eq!(&large[2..10], &70_000u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,93 @@
+//! 内置的 WebSocket 处理器，也作为实现 WebSocketHandler 的示例
+
+use super::{HandlerFuture, Message, WebSocket, WebSocketHandler};
+use crate::http::HttpRequest;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+/// 每个房间缓存的未发送消息数，慢的客户端超过后会丢消息
+const ROOM_CAPACITY: usize = 64;
+
+/// 房间里广播的消息：(发送者, 文本)
+type RoomSender = broadcast::Sender<(u64, String)>;
+
+/// 把收到的消息原样发回
+pub struct Echo;
+
+impl WebSocketHandler for Echo {
+    fn on_connect(&self, mut socket: WebSocket, _req: HttpRequest) -> HandlerFuture {
+        Box::pin(async move {
+            while let Some(message) = socket.recv().await {
+                if socket.send(&message).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+/// 广播房间：同一房间内一个客户端发送的文本消息会转发给其他所有客户端，
+/// 房间名取自查询字符串 `room=`，默认为 "lobby"
+#[derive(Default)]
+pub struct BroadcastRoom {
+    rooms: Arc<Mutex<HashMap<String, RoomSender>>>,
+}
+
+impl WebSocketHandler for BroadcastRoom {
+    fn on_connect(&self, socket: WebSocket, req: HttpRequest) -> HandlerFuture {
+        let rooms = self.rooms.clone();
+        Box::pin(async move {
+            let name = req
+                .query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("room="))
+                .filter(|name| !name.is_empty())
+                .unwrap_or("lobby")
+                .to_string();
+            // 请求 ID 唯一，用来区分房间里的成员
+            let member = req.id;
+            let room = rooms
+                .lock()
+                .unwrap()
+                .entry(name.clone())
+                .or_insert_with(|| broadcast::channel(ROOM_CAPACITY).0)
+                .clone();
+            let mut incoming = room.subscribe();
+            info!("Member {} joined room {}", member, name);
+
+            let (sender, mut receiver) = socket.split();
+            let forward = tokio::spawn(async move {
+                loop {
+                    match incoming.recv().await {
+                        Ok((from, text)) if from != member => {
+                            if sender.send(&Message::Text(text)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Member {} missed {} messages", member, skipped);
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+            while let Some(message) = receiver.recv().await {
+                if let Message::Text(text) = message {
+                    let _ = room.send((member, text));
+                }
+            }
+            forward.abort();
+            let _ = forward.await;
+
+            // 最后一个成员离开时删除房间
+            let mut rooms = rooms.lock().unwrap();
+            if room.receiver_count() == 0 {
+                rooms.remove(&name);
+            }
+            info!("Member {} left room {}", member, name);
+        })
+    }
+}
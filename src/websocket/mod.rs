@@ -0,0 +1,435 @@
+//! WebSocket 服务端（RFC 6455）
+//!
+//! 握手完成后连接交给按路径注册的 WebSocketHandler，处理器返回时关闭连接。
+//! ping 自动回复 pong，分片消息在交给处理器前拼接完整，协议错误时按规范发送关闭帧。
+
+mod frame;
+mod handlers;
+
+use crate::{
+    config::Config,
+    http::{BoxedReader, HttpRequest, HttpResponse},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use frame::{OP_BINARY, OP_CLOSE, OP_CONTINUATION, OP_PING, OP_PONG, OP_TEXT};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{info, warn};
+
+pub use handlers::{BroadcastRoom, Echo};
+
+/// 计算 Sec-WebSocket-Accept 时拼接在 key 后面的固定 GUID
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// WebSocket 路由的处理器，连接建立后调用一次
+pub trait WebSocketHandler: Send + Sync {
+    fn on_connect(&self, socket: WebSocket, req: HttpRequest) -> HandlerFuture;
+}
+
+// 按路径注册的处理器
+static HANDLERS: LazyLock<RwLock<HashMap<String, Arc<dyn WebSocketHandler>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    Protocol,
+    InvalidUtf8,
+    TooLarge,
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> Self {
+        WebSocketError::Io(e)
+    }
+}
+
+/// 一条完整的数据消息
+#[derive(Debug, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+pub struct WebSocket {
+    sender: Sender,
+    receiver: Receiver,
+}
+
+/// 发送端，可以克隆后在多个任务中使用
+#[derive(Clone)]
+pub struct Sender {
+    writer: Arc<tokio::sync::Mutex<BoxedWriter>>,
+    closed: Arc<AtomicBool>,
+}
+
+/// 接收端，读取时自动处理控制帧
+pub struct Receiver {
+    reader: BoxedReader,
+    sender: Sender,
+    max_message: usize,
+    closed: bool,
+}
+
+/// 注册一个 WebSocket 路由，相同路径的旧处理器会被替换
+pub fn register(path: &str, handler: impl WebSocketHandler + 'static) {
+    HANDLERS
+        .write()
+        .unwrap()
+        .insert(path.to_string(), Arc::new(handler));
+}
+
+/// 注册配置中启用的内置路由
+pub fn start(config: &Config) {
+    if let Some(path) = &config.websocket.echo_path {
+        register(path, Echo);
+        info!("WebSocket echo endpoint at {}", path);
+    }
+    if let Some(path) = &config.websocket.room_path {
+        register(path, BroadcastRoom::default());
+        info!("WebSocket broadcast room at {}", path);
+    }
+}
+
+/// 请求要求升级为 WebSocket 且路径上注册了处理器时返回该处理器
+pub fn find_handler(req: &HttpRequest) -> Option<Arc<dyn WebSocketHandler>> {
+    if !is_upgrade_request(req) {
+        return None;
+    }
+    HANDLERS.read().unwrap().get(&req.path).cloned()
+}
+
+/// 请求头中带有 Upgrade: websocket
+pub fn is_upgrade_request(req: &HttpRequest) -> bool {
+    req.header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// 完成握手并把连接交给处理器，握手失败时返回错误响应
+pub async fn serve<R, W>(
+    handler: Arc<dyn WebSocketHandler>,
+    req: HttpRequest,
+    reader: BufReader<R>,
+    mut writer: W,
+    max_message: usize,
+) where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let accept = match check_handshake(&req) {
+        Ok(accept) => accept,
+        Err(response) => {
+            warn!("Rejected WebSocket handshake for {}", req.path);
+            if let Err(e) = response.write_to(&mut writer).await {
+                warn!("Failed to write response: {}", e);
+            }
+            let _ = writer.shutdown().await;
+            return;
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    if let Err(e) = writer.write_all(head.as_bytes()).await {
+        warn!("Failed to complete WebSocket handshake: {}", e);
+        return;
+    }
+    info!("WebSocket connection opened on {}", req.path);
+
+    let socket = WebSocket::new(Box::new(reader), Box::new(writer), max_message);
+    let sender = socket.sender.clone();
+    handler.on_connect(socket, req).await;
+    let _ = sender.close(CLOSE_NORMAL, "").await;
+    let _ = sender.writer.lock().await.shutdown().await;
+    info!("WebSocket connection closed");
+}
+
+/// 检查握手请求，返回 Sec-WebSocket-Accept 的值
+fn check_handshake(req: &HttpRequest) -> Result<String, Box<HttpResponse>> {
+    let connection_upgrade = req.header("Connection").is_some_and(|v| {
+        v.split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    if req.method != "GET" || req.version != "HTTP/1.1" || !connection_upgrade {
+        return Err(Box::new(HttpResponse::bad_request()));
+    }
+    if req.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Box::new(
+            HttpResponse::from_status(426, "Upgrade Required")
+                .header("Sec-WebSocket-Version", "13"),
+        ));
+    }
+    let key = req.header("Sec-WebSocket-Key").unwrap_or("").trim();
+    // key 是 16 字节随机数的 base64 编码
+    if STANDARD.decode(key).map(|k| k.len()) != Ok(16) {
+        return Err(Box::new(HttpResponse::bad_request()));
+    }
+    Ok(accept_key(key))
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+impl WebSocket {
+    fn new(reader: BoxedReader, writer: BoxedWriter, max_message: usize) -> Self {
+        let sender = Sender {
+            writer: Arc::new(tokio::sync::Mutex::new(writer)),
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+        WebSocket {
+            receiver: Receiver {
+                reader,
+                sender: sender.clone(),
+                max_message,
+                closed: false,
+            },
+            sender,
+        }
+    }
+
+    /// 读取下一条消息，连接关闭后返回 None
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+
+    // 接收端不是 Sync，这里取 &mut self 保证返回的 future 是 Send
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.sender.send(message).await
+    }
+
+    /// 拆分为发送端和接收端，可以同时收发
+    pub fn split(self) -> (Sender, Receiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+impl Sender {
+    pub async fn send(&self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.send_frame(OP_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.send_frame(OP_BINARY, data).await,
+        }
+    }
+
+    /// 发送关闭帧，只发送一次，之后不能再发送消息
+    pub async fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_frame(OP_CLOSE, &payload).await
+    }
+
+    async fn send_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        self.write_frame(opcode, payload).await
+    }
+
+    async fn write_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer
+            .write_all(&frame::encode_frame(opcode, payload))
+            .await?;
+        writer.flush().await
+    }
+}
+
+impl Receiver {
+    /// 读取下一条消息，连接关闭或出错后返回 None
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.closed {
+            return None;
+        }
+        match self.read_message().await {
+            Ok(message) => message,
+            Err(e) => {
+                self.closed = true;
+                let code = match e {
+                    WebSocketError::Io(e) => {
+                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                            warn!("WebSocket connection error: {}", e);
+                        }
+                        return None;
+                    }
+                    WebSocketError::Protocol => CLOSE_PROTOCOL_ERROR,
+                    WebSocketError::InvalidUtf8 => CLOSE_INVALID_DATA,
+                    WebSocketError::TooLarge => CLOSE_TOO_BIG,
+                };
+                warn!("Closing WebSocket connection: {:?}", e);
+                let _ = self.sender.close(code, "").await;
+                None
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Option<Message>, WebSocketError> {
+        // 正在拼接的分片消息
+        let mut fragments: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = frame::read_frame(&mut self.reader, self.max_message).await?;
+            if frame.is_control() {
+                match frame.opcode {
+                    OP_PING => {
+                        // 已经发出关闭帧后不再回复
+                        let _ = self.sender.send_frame(OP_PONG, &frame.payload).await;
+                    }
+                    OP_CLOSE => {
+                        let code = parse_close(&frame.payload)?;
+                        self.closed = true;
+                        let _ = self.sender.close(code, "").await;
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            let (opcode, mut data) = match (frame.opcode, fragments.take()) {
+                (OP_CONTINUATION, Some((opcode, mut data))) => {
+                    data.extend_from_slice(&frame.payload);
+                    (opcode, data)
+                }
+                (OP_TEXT | OP_BINARY, None) => (frame.opcode, frame.payload),
+                // 没有开始就继续，或者上一条消息还没结束
+                _ => return Err(WebSocketError::Protocol),
+            };
+            if data.len() > self.max_message {
+                return Err(WebSocketError::TooLarge);
+            }
+            if !frame.fin {
+                fragments = Some((opcode, data));
+                continue;
+            }
+            return match opcode {
+                OP_TEXT => String::from_utf8(std::mem::take(&mut data))
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|_| WebSocketError::InvalidUtf8),
+                _ => Ok(Some(Message::Binary(data))),
+            };
+        }
+    }
+}
+
+/// 解析关闭帧，返回回复时使用的关闭码
+fn parse_close(payload: &[u8]) -> Result<u16, WebSocketError> {
+    match payload {
+        [] => Ok(CLOSE_NORMAL),
+        [_] => Err(WebSocketError::Protocol),
+        [high, low, reason @ ..] => {
+            let code = u16::from_be_bytes([*high, *low]);
+            // 1004-1006 和 1015 保留，不能出现在关闭帧中
+            let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+            if !valid {
+                return Err(WebSocketError::Protocol);
+            }
+            std::str::from_utf8(reason).map_err(|_| WebSocketError::InvalidUtf8)?;
+            Ok(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frame::tests::masked;
+    use std::io::Cursor;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    /// 客户端发来 input 的连接，返回值的第二项读取服务器写出的字节
+    fn connect(input: Vec<u8>, max_message: usize) -> (WebSocket, DuplexStream) {
+        let (writer, output) = tokio::io::duplex(1 << 20);
+        let socket = WebSocket::new(Box::new(Cursor::new(input)), Box::new(writer), max_message);
+        (socket, output)
+    }
+
+    async fn written(socket: WebSocket, mut output: DuplexStream) -> Vec<u8> {
+        drop(socket);
+        let mut bytes = Vec::new();
+        output.read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        // RFC 6455 第 1.3 节
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn joins_fragments_and_answers_pings_in_between() {
+        let mut input = masked(OP_TEXT, b"Hel");
+        input.extend(masked(0x80 | OP_PING, b"p"));
+        input.extend(masked(OP_CONTINUATION, b"lo"));
+        input.extend(masked(0x80 | OP_CONTINUATION, b"!"));
+        let (mut socket, output) = connect(input, 1024);
+        match socket.recv().await {
+            Some(Message::Text(text)) => assert_eq!(text, "Hello!"),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(socket.recv().await.is_none());
+        assert_eq!(written(socket, output).await, [0x8a, 0x01, b'p']);
+    }
+
+    #[tokio::test]
+    async fn message_limit_covers_all_fragments() {
+        let mut input = masked(OP_BINARY, b"abc");
+        input.extend(masked(0x80 | OP_CONTINUATION, b"de"));
+        let (mut socket, output) = connect(input, 4);
+        assert!(socket.recv().await.is_none());
+        // 1009 Message Too Big
+        assert_eq!(written(socket, output).await, [0x88, 0x02, 0x03, 0xf1]);
+    }
+
+    #[tokio::test]
+    async fn protocol_errors_close_with_a_code() {
+        // 没有开始的消息就收到后续分片：1002
+        let (mut socket, output) = connect(masked(0x80 | OP_CONTINUATION, b"x"), 1024);
+        assert!(socket.recv().await.is_none());
+        assert_eq!(written(socket, output).await, [0x88, 0x02, 0x03, 0xea]);
+
+        // 文本消息不是 UTF-8：1007
+        let (mut socket, output) = connect(masked(0x80 | OP_TEXT, &[0xff, 0xfe]), 1024);
+        assert!(socket.recv().await.is_none());
+        assert_eq!(written(socket, output).await, [0x88, 0x02, 0x03, 0xef]);
+    }
+
+    #[tokio::test]
+    async fn close_frame_is_echoed() {
+        let (mut socket, output) = connect(masked(0x80 | OP_CLOSE, &[0x03, 0xe8]), 1024);
+        assert!(socket.recv().await.is_none());
+        assert!(socket
+            .send(&Message::Text("late".to_string()))
+            .await
+            .is_err());
+        assert_eq!(written(socket, output).await, [0x88, 0x02, 0x03, 0xe8]);
+    }
+}